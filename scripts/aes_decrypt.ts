@@ -1,7 +1,9 @@
 #!/usr/bin/env -S deno run -A
-// Usage: deno run -A ./scripts/aes_decrypt.ts <password> <salt-hex> <nonce-hex> <aead-string> <ciphertext-hex>
+// Usage: deno run -A ./scripts/aes_decrypt.ts <password> <salt-hex> <nonce-hex> <aead-string> <ciphertext-hex> [chunked]
 
 const PBKDF2_ITERATIONS = 256;
+// Chunked objects are segments of 64 KiB of plaintext, each followed by its 16 bytes tag
+const STORED_SEGMENT_LEN = 64 * 1024 + 16;
 
 // Function to convert a hex string to a Uint8Array
 function hexToBytes(hex: string): Uint8Array {
@@ -42,7 +44,7 @@ async function decrypt(
   nonce: Uint8Array,
   ciphertext: Uint8Array,
   aead: string
-): Promise<string> {
+): Promise<Uint8Array> {
   const decrypted = await crypto.subtle.decrypt(
     {
       name: "AES-GCM",
@@ -53,18 +55,39 @@ async function decrypt(
     ciphertext
   );
 
-  return new TextDecoder().decode(decrypted);
+  return new Uint8Array(decrypted);
+}
+
+// The nonce of each segment is the first 7 bytes of the file nonce, the segment index and a last segment flag
+async function decryptChunked(
+  aesKey: CryptoKey,
+  nonce: Uint8Array,
+  ciphertext: Uint8Array,
+  aead: string
+): Promise<Uint8Array> {
+  const parts: Uint8Array[] = [];
+  for (let start = 0, index = 0; start === 0 || start < ciphertext.length; start += STORED_SEGMENT_LEN, index++) {
+    const segmentNonce = new Uint8Array(12);
+    segmentNonce.set(nonce.subarray(0, 7));
+    new DataView(segmentNonce.buffer).setUint32(7, index);
+    segmentNonce[11] = start + STORED_SEGMENT_LEN >= ciphertext.length ? 1 : 0;
+    parts.push(await decrypt(aesKey, segmentNonce, ciphertext.subarray(start, start + STORED_SEGMENT_LEN), aead));
+  }
+
+  const plaintext = new Uint8Array(parts.reduce((len, part) => len + part.length, 0));
+  parts.reduce((offset, part) => (plaintext.set(part, offset), offset + part.length), 0);
+  return plaintext;
 }
 
 // Main function
 async function main() {
   const args = Deno.args;
-  if (args.length !== 5) {
-    console.error("Usage: deno run script.ts <password> <salt-hex> <nonce-hex> <aead-string> <ciphertext-hex>");
+  if (args.length !== 5 && !(args.length === 6 && args[5] === "chunked")) {
+    console.error("Usage: deno run script.ts <password> <salt-hex> <nonce-hex> <aead-string> <ciphertext-hex> [chunked]");
     Deno.exit(1);
   }
 
-  const [password, saltHex, nonceHex, aead, ciphertextHex] = args;
+  const [password, saltHex, nonceHex, aead, ciphertextHex, format] = args;
 
   // Convert hex strings to byte arrays
   const salt = hexToBytes(saltHex);
@@ -75,9 +98,11 @@ async function main() {
   const aesKey = await deriveKey(password, salt);
 
   // Decrypt the ciphertext
-  const plaintext = await decrypt(aesKey, nonce, ciphertext, aead);
+  const plaintext = format === "chunked"
+    ? await decryptChunked(aesKey, nonce, ciphertext, aead)
+    : await decrypt(aesKey, nonce, ciphertext, aead);
 
-  console.log("Plaintext:", plaintext);
+  console.log("Plaintext:", new TextDecoder().decode(plaintext));
 }
 
 // Run the main function
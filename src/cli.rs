@@ -1,6 +1,7 @@
 use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use crate::utils::{current_timestamp, NANOS_PER_SECOND};
 
 /// Utility to mount a shadow filesystem, supports encryption and multiple storage backends: S3, Sqlar and FileSystem
 #[derive(Parser)]
@@ -50,6 +51,125 @@ pub enum Commands {
     Stats,
     /// Verify integrity of the filesystem data
     Verify,
+    /// Remove orphan files from the index and unreferenced objects from every backend
+    Gc {
+        /// Only report what would be removed
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// Keep unreachable files and objects changed in the last period of time: 30m, 1h, 7d
+        #[arg(long, value_name = "AGE", value_parser = parse_age, default_value = "1h")]
+        older_than: i64,
+    },
+    /// Print a report of duplicated file contents and the space saved by deduplication
+    DedupReport {
+        /// Number of largest duplicate sets to include in the summary
+        #[arg(short, long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Print the disk usage of each directory
+    Du {
+        /// Directory to start from
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// Maximum depth of directories to print, relative to the starting directory
+        #[arg(short, long)]
+        depth: Option<usize>,
+
+        /// Sort order: path, size, stored or files
+        #[arg(short, long, value_enum, default_value_t = DuSortOrder::Path)]
+        sort: DuSortOrder,
+
+        /// Print the results as JSON instead of a table
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Search files in the metadata index
+    Find(Box<FindArgs>),
+    /// Run a read-only SQL query against the metadata database
+    Sql {
+        /// SQL query, the views file_paths and duplicate_contents are available
+        query: String,
+
+        /// Output format: table, csv or json
+        #[arg(short, long, value_enum, default_value_t = SqlOutputFormat::Table)]
+        format: SqlOutputFormat,
+    },
+    /// Copy a file inside the filesystem, sharing the stored object when the backends allow it
+    Cp {
+        /// Path of the file to copy
+        source: String,
+
+        /// Path of the new file, or an existing directory to copy the file into
+        destination: String,
+    },
+}
+
+#[derive(Args)]
+pub struct FindArgs {
+    /// File name glob pattern, like '*.jpg'
+    #[arg(long, value_name = "GLOB")]
+    pub name: Option<String>,
+
+    /// File name regular expression, like '^IMG_[0-9]+\.jpg$'
+    #[arg(long, value_name = "REGEX")]
+    pub regex: Option<String>,
+
+    /// Kind of file: file, directory, fifo, socket, char-device or block-device
+    #[arg(long, value_enum)]
+    pub kind: Option<FindKind>,
+
+    /// Minimum file size, accepts units: 10K, 5M, 1G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_size: Option<i64>,
+
+    /// Maximum file size, accepts units: 10K, 5M, 1G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<i64>,
+
+    /// Modified after a unix timestamp or a relative time ago: 30m, 12h, 7d, 4w
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub mtime_after: Option<i64>,
+
+    /// Modified before a unix timestamp or a relative time ago: 30m, 12h, 7d, 4w
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub mtime_before: Option<i64>,
+
+    /// Changed after a unix timestamp or a relative time ago: 30m, 12h, 7d, 4w
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub ctime_after: Option<i64>,
+
+    /// Changed before a unix timestamp or a relative time ago: 30m, 12h, 7d, 4w
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub ctime_before: Option<i64>,
+
+    /// Accessed after a unix timestamp or a relative time ago: 30m, 12h, 7d, 4w
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub atime_after: Option<i64>,
+
+    /// Accessed before a unix timestamp or a relative time ago: 30m, 12h, 7d, 4w
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub atime_before: Option<i64>,
+
+    /// Owner user id
+    #[arg(long)]
+    pub uid: Option<i64>,
+
+    /// Owner group id
+    #[arg(long)]
+    pub gid: Option<i64>,
+
+    /// Prefix of the content SHA512 hash
+    #[arg(long, value_name = "PREFIX")]
+    pub sha512: Option<String>,
+
+    /// Only files whose content is shared with at least one other file
+    #[arg(long, default_value_t = false)]
+    pub duplicates: bool,
+
+    /// Print the results as JSON instead of a list of paths
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
 }
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
@@ -65,6 +185,31 @@ pub enum FileExportFormat {
     Zip,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SqlOutputFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DuSortOrder {
+    Path,
+    Size,
+    Stored,
+    Files,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum FindKind {
+    File,
+    Directory,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
 impl Display for IndexExportFormat {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -82,4 +227,82 @@ impl Display for FileExportFormat {
             FileExportFormat::Zip => write!(f, "zip"),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Parse a size with an optional binary unit suffix: 512, 10K, 5M, 1G, 2T
+fn parse_size(value: &str) -> Result<i64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, ""),
+    };
+
+    let multiplier: i64 = match unit.to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Invalid size unit: '{}'", unit)),
+    };
+
+    let number = number.parse::<i64>().map_err(|e| format!("Invalid size '{}': {}", value, e))?;
+    number.checked_mul(multiplier).ok_or_else(|| format!("Invalid size '{}': number too large", value))
+}
+
+/// Parse a unix timestamp, or a relative time ago with a unit suffix: 30s, 30m, 12h, 7d, 4w
+fn parse_timestamp(value: &str) -> Result<i64, String> {
+    let value = value.trim();
+
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Ok(timestamp);
+    }
+
+    let (number, unit) = value.split_at(value.len() - value.chars().last().map(char::len_utf8).unwrap_or(0));
+    let seconds: i64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Invalid time '{}', expected a unix timestamp or a relative time like 7d", value)),
+    };
+
+    let number = number.parse::<i64>().map_err(|e| format!("Invalid time '{}': {}", value, e))?;
+    Ok(current_timestamp() / NANOS_PER_SECOND - number * seconds)
+}
+
+/// Parse a period of time ago, the unit suffix is required so it's not taken for a unix timestamp: 30m, 1h, 7d
+fn parse_age(value: &str) -> Result<i64, String> {
+    if value.trim().parse::<i64>().is_ok() {
+        return Err(format!("Invalid age '{}', expected a number with a unit like 30m, 1h or 7d", value.trim()));
+    }
+    parse_timestamp(value)
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("512"), Ok(512));
+    assert_eq!(parse_size("10K"), Ok(10 * 1024));
+    assert_eq!(parse_size("5MiB"), Ok(5 * 1024 * 1024));
+    assert_eq!(parse_size("1g"), Ok(1024 * 1024 * 1024));
+    assert!(parse_size("1X").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("9000000000T").is_err());
+    assert_eq!(parse_size("8388607T"), Ok(8388607 << 40));
+}
+
+#[test]
+fn test_parse_timestamp() {
+    assert_eq!(parse_timestamp("1700000000"), Ok(1700000000));
+    assert!((current_timestamp() / NANOS_PER_SECOND - 7 * 24 * 60 * 60 - parse_timestamp("7d").unwrap()).abs() <= 1);
+    assert!(parse_timestamp("7y").is_err());
+    assert!(parse_timestamp("").is_err());
+}
+#[test]
+fn test_parse_age() {
+    assert!((current_timestamp() / NANOS_PER_SECOND - 60 * 60 - parse_age("1h").unwrap()).abs() <= 1);
+    assert!((current_timestamp() / NANOS_PER_SECOND - parse_age("0s").unwrap()).abs() <= 1);
+    assert!(parse_age("3600").is_err());
+    assert!(parse_age("1y").is_err());
+}
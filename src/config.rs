@@ -2,7 +2,7 @@ use std::fmt::Display;
 use std::{env, fs};
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::Arc;
 use anyhow::{anyhow, Error};
 use log::error;
 use serde::{Deserialize, Serialize};
@@ -11,12 +11,20 @@ use crate::metadata_db::MetadataDB;
 use crate::obj_storage::ObjInfo;
 use crate::utils::ask_for_confirmation;
 
+// S3 rejects parts smaller than 5 MiB, except the last one
+const S3_MIN_PART_SIZE_MB: u64 = 5;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct YamlConfig {
     database_file: Option<String>,
     mount_point: Option<String>,
     update_access_time: Option<bool>,
     store_file_change_history: Option<bool>,
+    worker_threads: Option<usize>,
+    cache_directory: Option<String>,
+    cache_memory_limit_mb: Option<u64>,
+    object_cache_size_mb: Option<u64>,
+    object_cache_policy: Option<String>,
     primary: Option<YamlStorageConfig>,
     replicas: Option<Vec<YamlStorageConfig>>,
     // Default value for each backend
@@ -28,9 +36,23 @@ struct YamlConfig {
     s3_base_path: Option<String>,
     s3_access_key: Option<String>,
     s3_secret_key: Option<String>,
+    s3_session_token: Option<String>,
+    s3_profile: Option<String>,
+    s3_force_path_style: Option<bool>,
+    s3_ca_bundle: Option<String>,
+    s3_server_side_encryption: Option<String>,
+    s3_sse_customer_key: Option<String>,
+    s3_storage_class: Option<String>,
+    s3_part_size_mb: Option<u64>,
+    s3_upload_concurrency: Option<usize>,
+    retry_max_attempts: Option<u32>,
+    retry_base_delay_ms: Option<u64>,
+    retry_max_delay_ms: Option<u64>,
+    operation_timeout_secs: Option<u64>,
     encryption_key: Option<String>,
     compression_level: Option<u32>,
     use_hash_as_filename: Option<bool>,
+    chaos: Option<ChaosConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -43,9 +65,23 @@ pub struct YamlStorageConfig {
     s3_base_path: Option<String>,
     s3_access_key: Option<String>,
     s3_secret_key: Option<String>,
+    s3_session_token: Option<String>,
+    s3_profile: Option<String>,
+    s3_force_path_style: Option<bool>,
+    s3_ca_bundle: Option<String>,
+    s3_server_side_encryption: Option<String>,
+    s3_sse_customer_key: Option<String>,
+    s3_storage_class: Option<String>,
+    s3_part_size_mb: Option<u64>,
+    s3_upload_concurrency: Option<usize>,
+    retry_max_attempts: Option<u32>,
+    retry_base_delay_ms: Option<u64>,
+    retry_max_delay_ms: Option<u64>,
+    operation_timeout_secs: Option<u64>,
     encryption_key: Option<String>,
     compression_level: Option<u32>,
     use_hash_as_filename: Option<bool>,
+    chaos: Option<ChaosConfig>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -56,14 +92,59 @@ pub enum StorageOption {
     RocksDb,
 }
 
+/// Server-side encryption of the objects stored in S3
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum S3Encryption {
+    None,
+    // Keys managed by S3 (SSE-S3)
+    S3Managed,
+    // Key from the config, sent with every request (SSE-C)
+    CustomerKey,
+}
+
+/// Faults injected by ChaosObjectStorage, to test how the filesystem behaves when a backend fails.
+/// Rates are the probability of each operation being affected, from 0 to 1.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ChaosConfig {
+    // Seed of the random faults, so a failure can be reproduced. A random one is logged if not set.
+    pub seed: Option<u64>,
+    // Random delay added to every operation, up to this value
+    pub latency_ms: u64,
+    // Operations failing with a transient error, returned without being retried
+    pub error_rate: f64,
+    // Writes that store only the start of the content and still succeed
+    pub partial_write_rate: f64,
+    // Reads returning the content with a bit flipped
+    pub bit_flip_rate: f64,
+    // Reads failing as if the object didn't exist
+    pub missing_rate: f64,
+}
+
+/// Objects removed first when the object cache is full
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CachePolicy {
+    // Least recently read
+    Lru,
+    // Oldest stored
+    Fifo,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub database_file: String,
     pub mount_point: String,
-    pub primary: Rc<StorageConfig>,
-    pub replicas: Vec<Rc<StorageConfig>>,
+    pub primary: Arc<StorageConfig>,
+    pub replicas: Vec<Arc<StorageConfig>>,
     pub update_access_time: bool,
     pub store_file_change_history: bool,
+    pub worker_threads: usize,
+    pub cache_directory: String,
+    // Bytes of open file contents kept in memory, 0 for no limit
+    pub cache_memory_limit: u64,
+    // Bytes of objects of remote backends kept in the cache directory, 0 to disable it
+    pub object_cache_size: u64,
+    pub object_cache_policy: CachePolicy,
 }
 
 #[derive(Debug, Clone)]
@@ -76,13 +157,34 @@ pub struct StorageConfig {
     pub s3_base_path: String,
     pub s3_access_key: String,
     pub s3_secret_key: String,
+    // Without access and secret keys, the credentials come from the environment, `~/.aws` or web identity
+    pub s3_session_token: String,
+    pub s3_profile: String,
+    // Buckets in the path instead of the host name, for MinIO or Ceph
+    pub s3_force_path_style: bool,
+    // PEM certificates trusted besides the ones of the system
+    pub s3_ca_bundle: String,
+    pub s3_server_side_encryption: S3Encryption,
+    pub s3_sse_customer_key: String,
+    pub s3_storage_class: String,
+    // Objects larger than a part are uploaded in parts, several of them at the same time
+    pub s3_part_size: u64,
+    pub s3_upload_concurrency: usize,
+    // Operations failing with transient errors are tried up to max attempts, waiting an exponential
+    // delay between them. The timeout limits each attempt, 0 for no limit.
+    pub retry_max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub operation_timeout_secs: u64,
     pub encryption_key: String,
     pub compression_level: u32,
     pub use_hash_as_filename: bool,
+    // Only for testing, the backend fails on purpose
+    pub chaos: Option<ChaosConfig>,
 }
 
 /// Read and parse the main config file
-pub fn read_config(config_path: &PathBuf) -> Result<Rc<Config>, Error> {
+pub fn read_config(config_path: &PathBuf) -> Result<Arc<Config>, Error> {
     if fs::metadata(&config_path).is_err() {
         let program_name = env::args().next()
             .as_ref()
@@ -104,7 +206,7 @@ pub fn read_config(config_path: &PathBuf) -> Result<Rc<Config>, Error> {
     // Fields in the global config are the defaults for primary and replicas
     let primary_clone = config.primary.clone();
     let primary = primary_clone.as_ref();
-    let primary = Rc::new(StorageConfig {
+    let primary = Arc::new(StorageConfig {
         storage_backend: StorageOption::from_string(
             &primary.and_then(|p| p.storage_backend.clone())
                 .or(config.storage_backend.clone()))?,
@@ -129,6 +231,45 @@ pub fn read_config(config_path: &PathBuf) -> Result<Rc<Config>, Error> {
         s3_secret_key: primary.and_then(|p| p.s3_secret_key.clone())
             .or(config.s3_secret_key.clone())
             .unwrap_or("".to_string()),
+        s3_session_token: primary.and_then(|p| p.s3_session_token.clone())
+            .or(config.s3_session_token.clone())
+            .unwrap_or("".to_string()),
+        s3_profile: primary.and_then(|p| p.s3_profile.clone())
+            .or(config.s3_profile.clone())
+            .unwrap_or("".to_string()),
+        s3_force_path_style: primary.and_then(|p| p.s3_force_path_style)
+            .or(config.s3_force_path_style)
+            .unwrap_or(false),
+        s3_ca_bundle: primary.and_then(|p| p.s3_ca_bundle.clone())
+            .or(config.s3_ca_bundle.clone())
+            .unwrap_or("".to_string()),
+        s3_server_side_encryption: S3Encryption::from_string(
+            &primary.and_then(|p| p.s3_server_side_encryption.clone())
+                .or(config.s3_server_side_encryption.clone()))?,
+        s3_sse_customer_key: primary.and_then(|p| p.s3_sse_customer_key.clone())
+            .or(config.s3_sse_customer_key.clone())
+            .unwrap_or("".to_string()),
+        s3_storage_class: primary.and_then(|p| p.s3_storage_class.clone())
+            .or(config.s3_storage_class.clone())
+            .unwrap_or("".to_string()),
+        s3_part_size: primary.and_then(|p| p.s3_part_size_mb)
+            .or(config.s3_part_size_mb)
+            .unwrap_or(16).max(S3_MIN_PART_SIZE_MB) * 1024 * 1024,
+        s3_upload_concurrency: primary.and_then(|p| p.s3_upload_concurrency)
+            .or(config.s3_upload_concurrency)
+            .unwrap_or(4).max(1),
+        retry_max_attempts: primary.and_then(|p| p.retry_max_attempts)
+            .or(config.retry_max_attempts)
+            .unwrap_or(3).max(1),
+        retry_base_delay_ms: primary.and_then(|p| p.retry_base_delay_ms)
+            .or(config.retry_base_delay_ms)
+            .unwrap_or(100),
+        retry_max_delay_ms: primary.and_then(|p| p.retry_max_delay_ms)
+            .or(config.retry_max_delay_ms)
+            .unwrap_or(5000),
+        operation_timeout_secs: primary.and_then(|p| p.operation_timeout_secs)
+            .or(config.operation_timeout_secs)
+            .unwrap_or(60),
         encryption_key: primary.and_then(|p| p.encryption_key.clone())
             .or(config.encryption_key.clone())
             .unwrap_or("".to_string()),
@@ -138,6 +279,8 @@ pub fn read_config(config_path: &PathBuf) -> Result<Rc<Config>, Error> {
         use_hash_as_filename: primary.and_then(|p| p.use_hash_as_filename.clone())
             .or(config.use_hash_as_filename.clone())
             .unwrap_or(false),
+        chaos: primary.and_then(|p| p.chaos.clone())
+            .or(config.chaos.clone()),
     });
 
     let mut cfg = Config {
@@ -147,11 +290,16 @@ pub fn read_config(config_path: &PathBuf) -> Result<Rc<Config>, Error> {
         replicas: vec![],
         update_access_time: config.update_access_time.unwrap_or(false),
         store_file_change_history: config.store_file_change_history.unwrap_or(true),
+        worker_threads: config.worker_threads.unwrap_or(16).max(1),
+        cache_directory: config.cache_directory.clone().unwrap_or("./cache".to_string()),
+        cache_memory_limit: config.cache_memory_limit_mb.unwrap_or(512) * 1024 * 1024,
+        object_cache_size: config.object_cache_size_mb.unwrap_or(1024) * 1024 * 1024,
+        object_cache_policy: CachePolicy::from_string(&config.object_cache_policy)?,
     };
 
     let replicas = config.replicas.clone().unwrap_or_default();
     for replica in &replicas {
-        cfg.replicas.push(Rc::new(StorageConfig {
+        cfg.replicas.push(Arc::new(StorageConfig {
             storage_backend: StorageOption::from_string(
                 &replica.storage_backend.clone()
                     .or(config.storage_backend.clone()))?,
@@ -176,6 +324,45 @@ pub fn read_config(config_path: &PathBuf) -> Result<Rc<Config>, Error> {
             s3_secret_key: replica.s3_secret_key.clone()
                 .or(config.s3_secret_key.clone())
                 .unwrap_or("".to_string()),
+            s3_session_token: replica.s3_session_token.clone()
+                .or(config.s3_session_token.clone())
+                .unwrap_or("".to_string()),
+            s3_profile: replica.s3_profile.clone()
+                .or(config.s3_profile.clone())
+                .unwrap_or("".to_string()),
+            s3_force_path_style: replica.s3_force_path_style
+                .or(config.s3_force_path_style)
+                .unwrap_or(false),
+            s3_ca_bundle: replica.s3_ca_bundle.clone()
+                .or(config.s3_ca_bundle.clone())
+                .unwrap_or("".to_string()),
+            s3_server_side_encryption: S3Encryption::from_string(
+                &replica.s3_server_side_encryption.clone()
+                    .or(config.s3_server_side_encryption.clone()))?,
+            s3_sse_customer_key: replica.s3_sse_customer_key.clone()
+                .or(config.s3_sse_customer_key.clone())
+                .unwrap_or("".to_string()),
+            s3_storage_class: replica.s3_storage_class.clone()
+                .or(config.s3_storage_class.clone())
+                .unwrap_or("".to_string()),
+            s3_part_size: replica.s3_part_size_mb
+                .or(config.s3_part_size_mb)
+                .unwrap_or(16).max(S3_MIN_PART_SIZE_MB) * 1024 * 1024,
+            s3_upload_concurrency: replica.s3_upload_concurrency
+                .or(config.s3_upload_concurrency)
+                .unwrap_or(4).max(1),
+            retry_max_attempts: replica.retry_max_attempts
+                .or(config.retry_max_attempts)
+                .unwrap_or(3).max(1),
+            retry_base_delay_ms: replica.retry_base_delay_ms
+                .or(config.retry_base_delay_ms)
+                .unwrap_or(100),
+            retry_max_delay_ms: replica.retry_max_delay_ms
+                .or(config.retry_max_delay_ms)
+                .unwrap_or(5000),
+            operation_timeout_secs: replica.operation_timeout_secs
+                .or(config.operation_timeout_secs)
+                .unwrap_or(60),
             encryption_key: replica.encryption_key.clone()
                 .or(config.encryption_key.clone())
                 .unwrap_or("".to_string()),
@@ -185,6 +372,8 @@ pub fn read_config(config_path: &PathBuf) -> Result<Rc<Config>, Error> {
             use_hash_as_filename: replica.use_hash_as_filename.clone()
                 .or(config.use_hash_as_filename.clone())
                 .unwrap_or(false),
+            chaos: replica.chaos.clone()
+                .or(config.chaos.clone()),
         }));
     }
 
@@ -194,10 +383,10 @@ pub fn read_config(config_path: &PathBuf) -> Result<Rc<Config>, Error> {
         validate_storage(i)?;
     }
 
-    Ok(Rc::new(cfg))
+    Ok(Arc::new(cfg))
 }
 
-pub fn check_config_changes(prefix: &str, config: Rc<StorageConfig>, sql: Rc<MetadataDB>) -> Result<(), AnyError> {
+pub fn check_config_changes(prefix: &str, config: Arc<StorageConfig>, sql: Arc<MetadataDB>) -> Result<(), AnyError> {
     // Changing storage_option will make all the files not available
     let setting_storage_option = format!("{}:storage_option", prefix);
     let storage_option = config.storage_backend.to_string();
@@ -249,9 +438,19 @@ pub fn check_config_changes(prefix: &str, config: Rc<StorageConfig>, sql: Rc<Met
     let setting_s3_region = format!("{}:s3_region", prefix);
     let setting_s3_endpoint_url = format!("{}:s3_endpoint_url", prefix);
 
+    // Objects stored with SSE-C can only be read with the same key
+    let setting_s3_sse_customer_key_hash = format!("{}:s3_sse_customer_key_hash", prefix);
+    let s3_sse_customer_key = hex::encode(&hmac_sha512::Hash::hash(&config.s3_sse_customer_key)[0..32]);
+
     if config.storage_backend == StorageOption::S3 {
         let mut changed = false;
 
+        if let Some(key_hash) = sql.get_setting(&setting_s3_sse_customer_key_hash)? {
+            if key_hash != s3_sse_customer_key {
+                changed = true;
+            }
+        }
+
         if let Some(bucket) = sql.get_setting(&setting_s3_bucket)? {
             if bucket != config.s3_bucket {
                 changed = true;
@@ -281,6 +480,7 @@ pub fn check_config_changes(prefix: &str, config: Rc<StorageConfig>, sql: Rc<Met
     sql.set_setting(&setting_s3_bucket, &config.s3_bucket)?;
     sql.set_setting(&setting_s3_region, &config.s3_region)?;
     sql.set_setting(&setting_s3_endpoint_url, &config.s3_endpoint_url)?;
+    sql.set_setting(&setting_s3_sse_customer_key_hash, &s3_sse_customer_key)?;
 
     // Changing blob_storage will make all the files not available
     let blob_storage = format!("{}:blob_storage", prefix);
@@ -305,17 +505,26 @@ fn validate_storage(cfg: &StorageConfig) -> Result<(), Error> {
     let mut errors = vec![];
 
     if cfg.storage_backend == StorageOption::S3 {
-        if cfg.s3_access_key.is_empty() {
-            errors.push("S3 access key is required".to_string());
+        // Without keys, the credentials are looked up in the environment
+        if cfg.s3_access_key.is_empty() != cfg.s3_secret_key.is_empty() {
+            errors.push("S3 access key and secret key must be provided together".to_string());
         }
-        if cfg.s3_secret_key.is_empty() {
-            errors.push("S3 secret key is required".to_string());
+        if !cfg.s3_session_token.is_empty() && cfg.s3_access_key.is_empty() {
+            errors.push("S3 session token requires an access key and a secret key".to_string());
         }
         if cfg.s3_bucket.is_empty() {
             errors.push("S3 bucket is required".to_string());
         }
-        if cfg.s3_region.is_empty() && cfg.s3_endpoint_url.is_empty() {
-            errors.push("S3 region or endpoint_url must be provided".to_string());
+        if !cfg.s3_ca_bundle.is_empty() && fs::metadata(&cfg.s3_ca_bundle).is_err() {
+            errors.push(format!("S3 CA bundle not found at {:?}", cfg.s3_ca_bundle));
+        }
+        if cfg.s3_server_side_encryption == S3Encryption::CustomerKey && cfg.s3_sse_customer_key.len() != 32 {
+            errors.push("S3 SSE-C customer key must be 32 characters long".to_string());
+        }
+        // Objects in archive classes must be restored before they can be read
+        let storage_classes = ["", "STANDARD", "STANDARD_IA", "ONEZONE_IA", "INTELLIGENT_TIERING", "GLACIER_IR", "REDUCED_REDUNDANCY"];
+        if !storage_classes.contains(&cfg.s3_storage_class.as_str()) {
+            errors.push(format!("S3 storage class {:?} is not supported, use one of {}", cfg.s3_storage_class, storage_classes[1..].join(", ")));
         }
     }
 
@@ -325,6 +534,13 @@ fn validate_storage(cfg: &StorageConfig) -> Result<(), Error> {
         }
     }
 
+    if let Some(chaos) = &cfg.chaos {
+        let rates = [chaos.error_rate, chaos.partial_write_rate, chaos.bit_flip_rate, chaos.missing_rate];
+        if rates.iter().any(|rate| !(0.0..=1.0).contains(rate)) {
+            errors.push("Chaos rates must be between 0 and 1".to_string());
+        }
+    }
+
     if !errors.is_empty() {
         return Err(anyhow!("Config errors detected:\n - {}", errors.join("\n - ")));
     }
@@ -351,6 +567,27 @@ impl StorageOption {
     }
 }
 
+impl S3Encryption {
+    pub fn from_string(encryption: &Option<String>) -> Result<S3Encryption, Error> {
+        match encryption.as_deref().unwrap_or("").to_ascii_lowercase().as_str() {
+            "" | "none" => Ok(S3Encryption::None),
+            "sse-s3" => Ok(S3Encryption::S3Managed),
+            "sse-c" => Ok(S3Encryption::CustomerKey),
+            _ => Err(anyhow!("Invalid S3 server-side encryption")),
+        }
+    }
+}
+
+impl CachePolicy {
+    pub fn from_string(policy: &Option<String>) -> Result<CachePolicy, Error> {
+        match policy.as_deref().unwrap_or("lru").to_ascii_lowercase().as_str() {
+            "lru" => Ok(CachePolicy::Lru),
+            "fifo" => Ok(CachePolicy::Fifo),
+            _ => Err(anyhow!("Invalid object cache policy")),
+        }
+    }
+}
+
 impl Display for StorageOption {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -399,4 +636,10 @@ impl StorageConfig {
             info.full_path.trim_start_matches('/').to_string()
         }
     }
+
+    /// Objects are addressed only by their contents, so files with the same contents can share them.
+    /// Encrypted objects use a random nonce per file, they are never shared.
+    pub fn is_content_addressed(&self) -> bool {
+        self.use_hash_as_filename && self.encryption_key.is_empty()
+    }
 }
\ No newline at end of file
@@ -1,9 +1,10 @@
 use std::cell::RefCell;
 use std::path::PathBuf;
 use std::rc::Rc;
-use crate::metadata_db::{FileRow, FILE_KIND_DIRECTORY, FILE_KIND_REGULAR};
+use crate::metadata_db::{FileRow, FILE_KIND_BLOCK_DEVICE, FILE_KIND_CHAR_DEVICE, FILE_KIND_DIRECTORY, FILE_KIND_FIFO, FILE_KIND_REGULAR, FILE_KIND_SOCKET};
 use serde::{Deserialize, Serialize};
 use crate::AnyError;
+use crate::utils::split_timestamp;
 
 pub type FsTreeRef = Rc<RefCell<FsTree>>;
 
@@ -15,12 +16,26 @@ pub struct FsTree {
     pub uid: i64,
     pub gid: i64,
     pub perms: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub rdev: i64,
     pub size: i64,
+    pub stored_size: i64,
     pub sha512: String,
     pub encryption_key: String,
+    /// Timestamps in seconds, the `_nsec` fields hold the nanoseconds within the second
     pub accessed_at: i64,
+    #[serde(default)]
+    pub accessed_at_nsec: i64,
     pub created_at: i64,
+    #[serde(default)]
+    pub created_at_nsec: i64,
     pub updated_at: i64,
+    #[serde(default)]
+    pub updated_at_nsec: i64,
+    #[serde(default)]
+    pub changed_at: i64,
+    #[serde(default)]
+    pub changed_at_nsec: i64,
     pub children: Vec<FsTreeRef>,
 }
 
@@ -28,27 +43,47 @@ pub struct FsTree {
 pub enum FsTreeKind {
     File,
     Directory,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
 }
 
 impl<'a> From<FileRow> for FsTree {
     fn from(value: FileRow) -> FsTree {
+        let (accessed_at, accessed_at_nsec) = split_timestamp(value.accessed_at);
+        let (created_at, created_at_nsec) = split_timestamp(value.created_at);
+        let (updated_at, updated_at_nsec) = split_timestamp(value.updated_at);
+        let (changed_at, changed_at_nsec) = split_timestamp(value.changed_at);
+
         FsTree {
             id: value.id,
             kind: match value.kind {
                 FILE_KIND_REGULAR => FsTreeKind::File,
                 FILE_KIND_DIRECTORY => FsTreeKind::Directory,
+                FILE_KIND_FIFO => FsTreeKind::Fifo,
+                FILE_KIND_SOCKET => FsTreeKind::Socket,
+                FILE_KIND_CHAR_DEVICE => FsTreeKind::CharDevice,
+                FILE_KIND_BLOCK_DEVICE => FsTreeKind::BlockDevice,
                 _ => panic!("Invalid kind"),
             },
             name: value.name,
             uid: value.uid,
             gid: value.gid,
             perms: value.perms,
+            rdev: value.rdev,
             size: value.size,
+            stored_size: value.stored_size,
             sha512: value.sha512,
             encryption_key: value.encryption_key,
-            accessed_at: value.accessed_at,
-            created_at: value.created_at,
-            updated_at: value.updated_at,
+            accessed_at,
+            accessed_at_nsec,
+            created_at,
+            created_at_nsec,
+            updated_at,
+            updated_at_nsec,
+            changed_at,
+            changed_at_nsec,
             children: vec![],
         }
     }
@@ -79,4 +114,8 @@ impl FsTree {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+fn is_zero(value: &i64) -> bool {
+    *value == 0
+}
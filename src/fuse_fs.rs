@@ -1,22 +1,40 @@
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use cntr_fuse::{fuse_forget_one, FileAttr, FileType, Filesystem, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen, ReplyRead, ReplyStatfs, ReplyWrite, Request, UtimeSpec};
-use libc::{c_int, ENOENT, ENOSYS, O_APPEND, O_CREAT, O_DSYNC, O_EXCL, O_NOATIME, O_NOCTTY, O_NONBLOCK, O_PATH, O_RDONLY, O_RDWR, O_SYNC, O_TMPFILE, O_TRUNC, O_WRONLY};
+use libc::{c_int, EACCES, EBADF, EEXIST, EINVAL, EOPNOTSUPP, ENOENT, ENOSYS, ENOTTY, EPERM, F_OK, R_OK, W_OK, X_OK, O_APPEND, O_CREAT, O_DSYNC, O_EXCL, O_NOATIME, O_NOCTTY, O_NONBLOCK, O_PATH, O_RDONLY, O_RDWR, O_SYNC, O_TMPFILE, O_TRUNC, O_WRONLY, RENAME_EXCHANGE, RENAME_NOREPLACE};
+use anyhow::anyhow;
 use log::{error, trace, warn};
 
-use crate::metadata_db::{FileRow, FILE_KIND_DIRECTORY};
-use crate::sql_fs::SqlFileSystem;
+use crate::lock_manager::{FileLock, LockKind, LockManager, LockReply, LOCK_TYPE_UNLOCKED};
+use crate::metadata_db::{FileRow, FILE_KIND_BLOCK_DEVICE, FILE_KIND_CHAR_DEVICE, FILE_KIND_DIRECTORY, FILE_KIND_FIFO, FILE_KIND_SOCKET};
+use crate::permissions::{chmod_mode, open_access_mask, Credentials};
+use crate::sql_fs::{SqlFileSystem, SqlFileSystemError};
 use crate::utils::{current_timestamp, system_time_from_timestamp, timestamp_from_system_time};
+use crate::worker_pool::WorkerPool;
 
 const BLOCK_SIZE: u32 = 65536; // 64kb
 const FINE_LOGGING: bool = false;
+const TTL: Duration = Duration::from_secs(1);
 
+/// Requests are read by the FUSE session loop and answered from a pool of worker threads, so a slow request, like
+/// fetching or storing an object, does not hold back the rest. Locks are handled in the session loop itself.
 pub struct FuseFileSystem {
-    pub fs: SqlFileSystem,
-    pub open_files: HashMap<u64, u64>,
+    pub fs: Arc<SqlFileSystem>,
+    pub open_files: Arc<Mutex<HashMap<u64, OpenFile>>>,
     pub fh_counter: u64,
+    pub locks: LockManager<ReplyEmpty>,
+    pub workers: WorkerPool,
+}
+
+/// State of a file handle, the contents are shared by every handle of the same file: writes are applied in the order
+/// they arrive, and a flush or release of any handle stores all of them
+#[derive(Debug, Clone, Copy)]
+pub struct OpenFile {
+    pub ino: u64,
+    pub flags: OpenFlags,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -59,40 +77,193 @@ impl OpenFlags {
             other: flags & !(O_WRONLY | O_APPEND | O_CREAT | O_EXCL | O_TRUNC | O_NOCTTY | O_NONBLOCK | O_SYNC | O_DSYNC | O_NOATIME | O_PATH | O_TMPFILE),
         }
     }
+}
 
-    pub fn to_safe_flags(&self) -> i32 {
-        let mut flags = 0;
-        if self.read_only {
-            flags |= O_RDONLY;
-        }
-        if self.write_only {
-            flags |= O_WRONLY;
-        }
-        if self.read_write {
-            flags |= O_RDWR;
+impl FuseFileSystem {
+    pub fn new(fs: SqlFileSystem) -> Self {
+        let workers = WorkerPool::new("fuse-worker", fs.config.worker_threads);
+
+        FuseFileSystem {
+            fs: Arc::new(fs),
+            open_files: Arc::new(Mutex::new(HashMap::new())),
+            fh_counter: 0,
+            locks: LockManager::new(),
+            workers,
         }
-        if self.append {
-            flags |= O_APPEND;
+    }
+
+    /// Answer a request from the worker pool
+    fn spawn(&self, job: impl FnOnce(&SqlFileSystem) + Send + 'static) {
+        let fs = self.fs.clone();
+        self.workers.execute(move || job(&fs));
+    }
+
+    /// Handles are numbered in the session loop, so they are known before the open request is answered
+    fn next_fh(&mut self) -> u64 {
+        self.fh_counter += 1;
+        self.fh_counter
+    }
+
+    /// Answer the lock requests that stopped waiting
+    pub fn send_lock_replies(replies: Vec<LockReply<ReplyEmpty>>) {
+        for (reply, result) in replies {
+            match result {
+                Ok(()) => reply.ok(),
+                Err(code) => reply.error(code),
+            }
         }
-        if self.create {
-            flags |= O_CREAT;
+    }
+}
+
+/// Permission rules of setattr, returns the mode to apply
+#[allow(clippy::too_many_arguments)]
+fn check_setattr(
+    fs: &SqlFileSystem, cred: &Credentials, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>,
+    atime: &UtimeSpec, mtime: &UtimeSpec, fh: Option<u64>, crtime: Option<SystemTime>,
+) -> Result<Option<u32>, SqlFileSystemError> {
+    let id = ino as i64;
+
+    if uid.is_some() || gid.is_some() {
+        fs.check_chown(id, cred, uid, gid)?;
+    }
+
+    // Changing the mode or setting explicit times needs ownership, setting them to now also allows write access
+    let explicit_times = matches!(atime, UtimeSpec::Time(_)) || matches!(mtime, UtimeSpec::Time(_)) || crtime.is_some();
+    let touch = matches!(atime, UtimeSpec::Now) || matches!(mtime, UtimeSpec::Now);
+
+    if mode.is_some() || explicit_times {
+        fs.check_owner(id, cred)?;
+    } else if touch && fs.check_owner(id, cred).is_err() {
+        fs.check_access(id, cred, W_OK)?;
+    }
+
+    if size.is_some() {
+        // Truncating an open file uses the access mode of the handle
+        if fh.is_none() {
+            fs.check_access(id, cred, W_OK)?;
         }
-        flags | self.other
+        // Truncating is a write, the setuid/setgid bits are cleared for other users
+        fs.clear_privileges_on_write(id, cred)?;
+    }
+
+    match mode {
+        Some(mode) => Ok(Some(chmod_mode(&fs.getattr(id)?, cred, mode))),
+        None => Ok(None),
     }
 }
 
-impl FuseFileSystem {
-    pub fn new(fs: SqlFileSystem) -> Self {
-        FuseFileSystem {
-            fs,
-            open_files: HashMap::new(),
-            fh_counter: 0,
+/// Attributes of a file, a file that is open after being unlinked has no links
+fn attr_of(fs: &SqlFileSystem, file: &FileRow) -> FileAttr {
+    let mut attr = FileAttr::from(file);
+
+    if fs.storage.is_open(file.id) && fs.is_unlinked(file.id).unwrap_or(false) {
+        attr.nlink = 0;
+    }
+    attr
+}
+
+/// Renaming needs to remove the entry from the old directory and add it (maybe replacing another) to the new one
+fn check_rename(fs: &SqlFileSystem, cred: &Credentials, parent: u64, old_name: &str, new_parent: u64, new_name: &str, file: &FileRow) -> Result<(), SqlFileSystemError> {
+    fs.check_remove_entry(parent as i64, old_name, cred)?;
+
+    if fs.sql.find_directory_entry(new_parent as i64, new_name)?.is_some() {
+        fs.check_remove_entry(new_parent as i64, new_name, cred)?;
+    } else {
+        fs.check_access(new_parent as i64, cred, W_OK | X_OK)?;
+    }
+
+    // Moving a directory updates its '..' entry
+    if file.kind == FILE_KIND_DIRECTORY && parent != new_parent {
+        fs.check_access(file.id, cred, W_OK)?;
+    }
+
+    Ok(())
+}
+
+/// Open a file for a new handle. FUSE_ATOMIC_O_TRUNC is negotiated, so O_TRUNC comes with the open request instead of
+/// a setattr, and writes patch the contents, the file is truncated here the same way setattr does it.
+fn open_handle(fs: &SqlFileSystem, cred: &Credentials, id: i64, flags: OpenFlags) -> Result<(), SqlFileSystemError> {
+    fs.open(id, flags.read_only)?;
+
+    if flags.truncate && !flags.read_only {
+        let res = fs.clear_privileges_on_write(id, cred)
+            .and_then(|_| fs.setattr(id, None, None, None, Some(0), None, Some(current_timestamp()), None));
+
+        if let Err(e) = res {
+            let _ = fs.release(id);
+            return Err(e);
         }
     }
+    Ok(())
+}
+
+pub fn file_type_of(kind: i64) -> FileType {
+    match kind {
+        FILE_KIND_DIRECTORY => FileType::Directory,
+        FILE_KIND_FIFO => FileType::NamedPipe,
+        FILE_KIND_SOCKET => FileType::Socket,
+        FILE_KIND_CHAR_DEVICE => FileType::CharDevice,
+        FILE_KIND_BLOCK_DEVICE => FileType::BlockDevice,
+        _ => FileType::RegularFile,
+    }
+}
+
+fn credentials(req: &Request) -> Credentials {
+    Credentials::new(req.uid(), req.gid(), req.pid())
+}
+
+/// Move a file to another name, in the same directory or in another one
+fn rename_file(fs: &SqlFileSystem, cred: &Credentials, parent: u64, old_name: &str, new_parent_id: u64, new_name: &str) -> Result<(), SqlFileSystemError> {
+    let file = match fs.lookup(parent as i64, old_name)? {
+        Some(file) => file,
+        None => return Err(SqlFileSystemError { code: ENOENT, error: anyhow!("File not found: {}", old_name) }),
+    };
+
+    check_rename(fs, cred, parent, old_name, new_parent_id, new_name, &file)?;
+
+    // Not allowed to move across directories
+    if parent != new_parent_id {
+        return fs.move_file(parent as i64, old_name, new_parent_id as i64, new_name);
+    }
+
+    fs.rename(parent as i64, old_name, new_name)
+}
+
+/// Swap two files, both entries are replaced and each file moves to the directory of the other
+fn exchange_files(fs: &SqlFileSystem, cred: &Credentials, parent: u64, old_name: &str, newparent: u64, new_name: &str) -> Result<(), SqlFileSystemError> {
+    let file = fs.lookup(parent as i64, old_name)?;
+    let new_file = fs.lookup(newparent as i64, new_name)?;
+    let (file, new_file) = match (file, new_file) {
+        (Some(file), Some(new_file)) => (file, new_file),
+        _ => return Err(SqlFileSystemError { code: ENOENT, error: anyhow!("File not found: {} or {}", old_name, new_name) }),
+    };
+
+    check_rename(fs, cred, parent, old_name, newparent, new_name, &file)?;
+    check_rename(fs, cred, newparent, new_name, parent, old_name, &new_file)?;
+
+    fs.exchange(parent as i64, old_name, newparent as i64, new_name)
+}
+
+/// Rename with the flags of renameat2: RENAME_NOREPLACE fails if the target exists, RENAME_EXCHANGE swaps both files
+fn rename_entry(fs: &SqlFileSystem, cred: &Credentials, parent: u64, old_name: &str, newparent: u64, new_name: &str, flags: u32) -> Result<(), SqlFileSystemError> {
+    // RENAME_WHITEOUT is only used by overlay filesystems
+    if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 || flags == RENAME_NOREPLACE | RENAME_EXCHANGE {
+        return Err(SqlFileSystemError { code: EINVAL, error: anyhow!("Unsupported rename flags: {}", flags) });
+    }
+
+    if flags & RENAME_EXCHANGE != 0 {
+        return exchange_files(fs, cred, parent, old_name, newparent, new_name);
+    }
 
-    pub fn get_ttl(&self) -> Duration {
-        Duration::from_secs(1)
+    if flags & RENAME_NOREPLACE != 0 && fs.lookup(newparent as i64, new_name)?.is_some() {
+        return Err(SqlFileSystemError { code: EEXIST, error: anyhow!("File already exists: {}", new_name) });
     }
+
+    if parent == newparent && old_name == new_name {
+        return Ok(());
+    }
+
+    rename_file(fs, cred, parent, old_name, newparent, new_name)
 }
 
 impl Filesystem for FuseFileSystem {
@@ -103,30 +274,45 @@ impl Filesystem for FuseFileSystem {
 
     fn destroy(&mut self, _req: &Request) {
         trace!("FS destroy");
+
+        if let Err(e) = self.fs.save_cache_stats() {
+            error!("Unable to save cache stats: {:?}", e.error);
+        }
+        if let Err(e) = self.fs.save_retry_stats() {
+            error!("Unable to save retry stats: {:?}", e.error);
+        }
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, os_name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, req: &Request, parent: u64, os_name: &OsStr, reply: ReplyEntry) {
         if FINE_LOGGING {
             trace!("FS lookup(parent: {}, name: {:?})", parent, os_name);
         }
-        let name = os_name.to_string_lossy();
+        let name = os_name.to_string_lossy().to_string();
+        let cred = credentials(req);
 
-        match self.fs.lookup(parent as i64, &name) {
-            Ok(file) => {
-                if let Some(file) = file {
-                    let attr = FileAttr::from(&file);
-                    reply.entry(&self.get_ttl(), &attr, 0);
-                } else {
-                    reply.error(ENOENT);
-                }
+        self.spawn(move |fs| {
+            if let Err(e) = fs.check_access(parent as i64, &cred, X_OK) {
+                reply.error(e.code);
+                return;
             }
-            Err(e) => {
-                if e.code != ENOENT {
-                    warn!("Error looking up file: {:?}", e.error);
+
+            match fs.lookup(parent as i64, &name) {
+                Ok(file) => {
+                    if let Some(file) = file {
+                        let attr = FileAttr::from(&file);
+                        reply.entry(&TTL, &attr, 0);
+                    } else {
+                        reply.error(ENOENT);
+                    }
+                }
+                Err(e) => {
+                    if e.code != ENOENT {
+                        warn!("Error looking up file: {:?}", e.error);
+                    }
+                    reply.error(e.code);
                 }
-                reply.error(e.code);
             }
-        }
+        });
     }
 
     fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
@@ -146,52 +332,65 @@ impl Filesystem for FuseFileSystem {
             trace!("FS getattr(ino: {})", ino);
         }
 
-        match self.fs.getattr(ino as i64) {
-            Ok(file) => {
-                let attr = FileAttr::from(&file);
-                reply.attr(&self.get_ttl(), &attr);
-            }
-            Err(e) => {
-                if e.code != ENOENT {
-                    error!("Error getattr: {:#}", e.error);
+        self.spawn(move |fs| {
+            match fs.getattr(ino as i64) {
+                Ok(file) => {
+                    let attr = attr_of(fs, &file);
+                    reply.attr(&TTL, &attr);
+                }
+                Err(e) => {
+                    if e.code != ENOENT {
+                        error!("Error getattr: {:#}", e.error);
+                    }
+                    reply.error(e.code);
                 }
-                reply.error(e.code);
             }
-        }
+        });
     }
 
-    fn setattr(&mut self, _req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: UtimeSpec, mtime: UtimeSpec, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>, reply: ReplyAttr) {
+    fn setattr(&mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: UtimeSpec, mtime: UtimeSpec, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>, reply: ReplyAttr) {
         trace!("FS setattr(ino: {}, mode: {:?}, uid: {:?}, gid: {:?}, size: {:?}, atime: {:?}, mtime: {:?}, fh: {:?}, crtime: {:?}, chgtime: {:?}, bkuptime: {:?}, flags: {:?})", ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags);
+        let cred = credentials(req);
 
-        let atime = match atime {
-            UtimeSpec::Now => Some(current_timestamp()),
-            UtimeSpec::Omit => None,
-            UtimeSpec::Time(t) => Some(timestamp_from_system_time(t))
-        };
-
-        let mtime = match mtime {
-            UtimeSpec::Now => Some(current_timestamp()),
-            UtimeSpec::Omit => None,
-            UtimeSpec::Time(t) => Some(timestamp_from_system_time(t))
-        };
-
-        match self.fs.setattr(
-            ino as i64, mode, uid, gid, size,
-            atime,
-            mtime,
-            crtime.map(|i| timestamp_from_system_time(i)),
-        ) {
-            Ok(file) => {
-                let attr = FileAttr::from(&file);
-                reply.attr(&self.get_ttl(), &attr);
-            }
-            Err(e) => {
-                if e.code != ENOENT {
-                    error!("Error setattr: {:#}", e.error);
+        self.spawn(move |fs| {
+            let mode = match check_setattr(fs, &cred, ino, mode, uid, gid, size, &atime, &mtime, fh, crtime) {
+                Ok(mode) => mode,
+                Err(e) => {
+                    reply.error(e.code);
+                    return;
+                }
+            };
+
+            let atime = match atime {
+                UtimeSpec::Now => Some(current_timestamp()),
+                UtimeSpec::Omit => None,
+                UtimeSpec::Time(t) => Some(timestamp_from_system_time(t))
+            };
+
+            let mtime = match mtime {
+                UtimeSpec::Now => Some(current_timestamp()),
+                UtimeSpec::Omit => None,
+                UtimeSpec::Time(t) => Some(timestamp_from_system_time(t))
+            };
+
+            match fs.setattr(
+                ino as i64, mode, uid, gid, size,
+                atime,
+                mtime,
+                crtime.map(|i| timestamp_from_system_time(i)),
+            ) {
+                Ok(file) => {
+                    let attr = attr_of(fs, &file);
+                    reply.attr(&TTL, &attr);
+                }
+                Err(e) => {
+                    if e.code != ENOENT {
+                        error!("Error setattr: {:#}", e.error);
+                    }
+                    reply.error(e.code);
                 }
-                reply.error(e.code);
             }
-        }
+        });
     }
 
     fn readlink(&mut self, _req: &Request, _ino: u64, reply: ReplyData) {
@@ -200,66 +399,101 @@ impl Filesystem for FuseFileSystem {
         reply.error(ENOSYS);
     }
 
-    fn mknod(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, _umask: u32, _rdev: u32, reply: ReplyEntry) {
-        trace!("FS mknod(parent: {}, name: {:?}, mode: {}, umask: {}, rdev: {})", parent, name, mode, _umask, _rdev);
-        let name = name.to_string_lossy();
-        match self.fs.mknod(parent as i64, &name, req.uid(), req.gid(), mode) {
-            Ok(file) => {
-                let attr = FileAttr::from(&file);
-                reply.entry(&self.get_ttl(), &attr, 0);
-            }
-            Err(e) => {
-                error!("Error creating file: {:?}", e.error);
+    fn mknod(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, rdev: u32, reply: ReplyEntry) {
+        trace!("FS mknod(parent: {}, name: {:?}, mode: {}, umask: {}, rdev: {})", parent, name, mode, umask, rdev);
+        let name = name.to_string_lossy().to_string();
+        let cred = credentials(req);
+
+        self.spawn(move |fs| {
+            if let Err(e) = fs.check_access(parent as i64, &cred, W_OK | X_OK) {
                 reply.error(e.code);
+                return;
             }
-        }
-    }
 
-    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
-        trace!("FS mkdir(parent: {}, name: {:?}, mode: {}, umask: {})", parent, name, mode, _umask);
-        let name = name.to_string_lossy();
-        match self.fs.mkdir(parent as i64, &name, req.uid(), req.gid(), mode) {
-            Ok(file) => {
-                let attr = FileAttr::from(&file);
-                reply.entry(&self.get_ttl(), &attr, 0);
+            // The umask is not applied by the kernel, it is sent along with the mode
+            match fs.mknod(parent as i64, &name, cred.uid, cred.gid, mode & !umask, rdev) {
+                Ok(file) => {
+                    let attr = FileAttr::from(&file);
+                    reply.entry(&TTL, &attr, 0);
+                }
+                Err(e) => {
+                    error!("Error creating file: {:?}", e.error);
+                    reply.error(e.code);
+                }
             }
-            Err(e) => {
-                error!("Error creating directory: {:?}", e.error);
+        });
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        trace!("FS mkdir(parent: {}, name: {:?}, mode: {}, umask: {})", parent, name, mode, umask);
+        let name = name.to_string_lossy().to_string();
+        let cred = credentials(req);
+
+        self.spawn(move |fs| {
+            if let Err(e) = fs.check_access(parent as i64, &cred, W_OK | X_OK) {
                 reply.error(e.code);
+                return;
             }
-        }
+
+            match fs.mkdir(parent as i64, &name, cred.uid, cred.gid, mode & !umask) {
+                Ok(file) => {
+                    let attr = FileAttr::from(&file);
+                    reply.entry(&TTL, &attr, 0);
+                }
+                Err(e) => {
+                    error!("Error creating directory: {:?}", e.error);
+                    reply.error(e.code);
+                }
+            }
+        });
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         trace!("FS unlink(parent: {}, name: {:?})", parent, name);
-        let name = name.to_string_lossy();
-        match self.fs.unlink(parent as i64, &name) {
-            Ok(_) => {
-                reply.ok();
+        let name = name.to_string_lossy().to_string();
+        let cred = credentials(req);
+
+        self.spawn(move |fs| {
+            if let Err(e) = fs.check_remove_entry(parent as i64, &name, &cred) {
+                reply.error(e.code);
+                return;
             }
-            Err(e) => {
-                if e.code != ENOENT {
-                    error!("Error unlinking file: {:?}", e.error);
+            match fs.unlink(parent as i64, &name) {
+                Ok(_) => {
+                    reply.ok();
+                }
+                Err(e) => {
+                    if e.code != ENOENT {
+                        error!("Error unlinking file: {:?}", e.error);
+                    }
+                    reply.error(e.code);
                 }
-                reply.error(e.code);
             }
-        }
+        });
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         trace!("FS rmdir(parent: {}, name: {:?})", parent, name);
-        let name = name.to_string_lossy();
-        match self.fs.rmdir(parent as i64, &name) {
-            Ok(_) => {
-                reply.ok();
+        let name = name.to_string_lossy().to_string();
+        let cred = credentials(req);
+
+        self.spawn(move |fs| {
+            if let Err(e) = fs.check_remove_entry(parent as i64, &name, &cred) {
+                reply.error(e.code);
+                return;
             }
-            Err(e) => {
-                if e.code != ENOENT {
-                    error!("Error removing directory: {:?}", e.error);
+            match fs.rmdir(parent as i64, &name) {
+                Ok(_) => {
+                    reply.ok();
+                }
+                Err(e) => {
+                    if e.code != ENOENT {
+                        error!("Error removing directory: {:?}", e.error);
+                    }
+                    reply.error(e.code);
                 }
-                reply.error(e.code);
             }
-        }
+        });
     }
 
     fn symlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _link: &Path, reply: ReplyEntry) {
@@ -268,140 +502,158 @@ impl Filesystem for FuseFileSystem {
         reply.error(ENOSYS);
     }
 
-    fn rename(&mut self, _req: &Request, parent: u64, os_name: &OsStr, new_parent_id: u64, new_os_name: &OsStr, reply: ReplyEmpty) {
+    fn rename(&mut self, req: &Request, parent: u64, os_name: &OsStr, new_parent_id: u64, new_os_name: &OsStr, reply: ReplyEmpty) {
         trace!("FS rename(parent: {}, name: {:?}, new_parent: {}, new_name: {:?})", parent, os_name, new_parent_id, new_os_name);
+        self.rename2(req, parent, os_name, new_parent_id, new_os_name, 0, reply);
+    }
 
-        if parent == new_parent_id && os_name == new_os_name {
-            reply.ok();
-            return;
-        }
-
-        let old_name = os_name.to_string_lossy();
-        let new_name = new_os_name.to_string_lossy();
-
-        let file = self.fs.lookup(parent as i64, old_name.as_ref()).unwrap();
-        match file {
-            Some(file) => {
-                if self.open_files.values().any(|f| *f == file.id as u64) {
-                    error!("Error renaming file {} {:?} to {:?}, file in use ({:?})", file.id, old_name, new_name, self.open_files);
-                    reply.error(ENOSYS);
-                    return;
-                }
-            }
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        }
+    fn rename2(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        trace!("FS rename2(parent: {}, name: {:?}, new_parent: {}, new_name: {:?}, flags: {})", parent, name, newparent, newname, flags);
+        let old_name = name.to_string_lossy().to_string();
+        let new_name = newname.to_string_lossy().to_string();
+        let cred = credentials(req);
 
-        // Not allowed to move across directories
-        if parent != new_parent_id {
-            match self.fs.move_file(parent as i64, &old_name, new_parent_id as i64, &new_name) {
+        self.spawn(move |fs| {
+            match rename_entry(fs, &cred, parent, &old_name, newparent, &new_name, flags) {
                 Ok(_) => {
                     reply.ok();
                 }
                 Err(e) => {
-                    error!("Error renaming file: {:?}", e.error);
+                    if !matches!(e.code, ENOENT | EEXIST | EACCES | EPERM) {
+                        error!("Error renaming file: {:?}", e.error);
+                    }
                     reply.error(e.code);
                 }
             }
-            return;
-        }
-
-        match self.fs.rename(parent as i64, &old_name, &new_name) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => {
-                error!("Error renaming file: {:?}", e.error);
-                reply.error(e.code);
-            }
-        }
-    }
-
-    fn rename2(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
-        self.rename(_req, parent, name, newparent, newname, reply);
+        });
     }
 
     fn link(&mut self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr, reply: ReplyEntry) {
         trace!("FS link(ino: {}, newparent: {}, newname: {:?})", _ino, _newparent, _newname);
-        warn!("Link not implemented");
-        reply.error(ENOSYS);
+        // Hard links are not supported: a file has a single name and path, objects may be stored by it. EPERM is the
+        // error of filesystems without hard links, and the kernel never links back unlinked files to FUSE filesystems
+        reply.error(EPERM);
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         trace!("FS open(ino: {}, flags: {})", ino, flags);
-
+        let cred = credentials(req);
         let open_flags = OpenFlags::from(flags as i32);
-        let flags = open_flags.to_safe_flags() as u32;
-
-        match self.fs.open(ino as i64, flags) {
-            Ok(_) => {
-                self.fh_counter += 1;
-                let fh = self.fh_counter;
-                self.open_files.insert(fh, ino);
-                reply.opened(fh, flags);
+        let fh = self.next_fh();
+        let open_files = self.open_files.clone();
+
+        self.spawn(move |fs| {
+            if let Err(e) = fs.check_access(ino as i64, &cred, open_access_mask(flags as i32)) {
+                reply.error(e.code);
+                return;
             }
-            Err(e) => {
-                if e.code != ENOENT {
-                    error!("Error opening file: {:?}", e.error);
+
+            match open_handle(fs, &cred, ino as i64, open_flags) {
+                Ok(_) => {
+                    open_files.lock().unwrap().insert(fh, OpenFile { ino, flags: open_flags });
+                    reply.opened(fh, 0);
+                }
+                Err(e) => {
+                    if e.code != ENOENT {
+                        error!("Error opening file: {:?}", e.error);
+                    }
+                    reply.error(e.code);
                 }
-                reply.error(e.code);
             }
-        }
+        });
     }
 
     fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyRead) {
         trace!("FS read(ino: {}, file_handle: {}, offset: {}, size: {})", ino, fh, offset, size);
-        match self.fs.read(ino as i64, offset, size as usize) {
-            Ok(data) => {
-                reply.data(&data);
-            }
-            Err(e) => {
-                error!("Error reading file: {:?}", e.error);
-                reply.error(e.code);
-            }
+
+        // Write-only handles are read too, the kernel fills its page cache through them when writeback caching is on
+        if self.open_files.lock().unwrap().get(&fh).is_none_or(|handle| handle.ino != ino) {
+            reply.error(EBADF);
+            return;
         }
+
+        self.spawn(move |fs| {
+            match fs.read(ino as i64, offset, size as usize) {
+                Ok(data) => {
+                    reply.data(&data);
+                }
+                Err(e) => {
+                    error!("Error reading file: {:?}", e.error);
+                    reply.error(e.code);
+                }
+            }
+        });
     }
 
-    fn write(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32, reply: ReplyWrite) {
+    fn write(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32, reply: ReplyWrite) {
         trace!("FS write(ino: {}, file_handle: {}, offset: {}, data: {} B, flags: {})", ino, fh, offset, data.len(), flags);
-        match self.fs.write(ino as i64, offset, data) {
-            Ok(size) => {
-                reply.written(size as u32);
-            }
-            Err(e) => {
-                error!("Error writing file: {:?}", e.error);
+
+        if !self.open_files.lock().unwrap().get(&fh).is_some_and(|handle| handle.ino == ino && !handle.flags.read_only) {
+            reply.error(EBADF);
+            return;
+        }
+
+        let cred = credentials(req);
+        let data = data.to_vec();
+
+        self.spawn(move |fs| {
+            if let Err(e) = fs.clear_privileges_on_write(ino as i64, &cred) {
+                error!("Error clearing setuid/setgid bits: {:?}", e.error);
                 reply.error(e.code);
+                return;
             }
-        }
+            // The kernel already moved the writes of O_APPEND handles to the end, and with writeback caching it sends
+            // the dirty pages of any handle, so the offset is always used
+            match fs.write(ino as i64, offset, &data) {
+                Ok(size) => {
+                    reply.written(size as u32);
+                }
+                Err(e) => {
+                    error!("Error writing file: {:?}", e.error);
+                    reply.error(e.code);
+                }
+            }
+        });
     }
 
-    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         trace!("FS flush(ino: {}, file_handle: {})", ino, _fh);
-        match self.fs.flush(ino as i64) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => {
-                error!("Error flushing file: {:?}", e.error);
-                reply.error(e.code);
+
+        // Closing any descriptor of a file releases the POSIX locks of the process on it
+        Self::send_lock_replies(self.locks.release_owner(ino, lock_owner));
+
+        self.spawn(move |fs| {
+            match fs.flush(ino as i64) {
+                Ok(_) => {
+                    reply.ok();
+                }
+                Err(e) => {
+                    error!("Error flushing file: {:?}", e.error);
+                    reply.error(e.code);
+                }
             }
-        }
+        });
     }
 
-    fn release(&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+    fn release(&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
         trace!("FS release(ino: {}, file_handle: {}, flags: {})", ino, fh, _flags);
-        match self.fs.release(ino as i64) {
-            Ok(_) => {
-                self.open_files.remove(&fh);
-                reply.ok();
-            }
-            Err(e) => {
-                error!("Error releasing file: {:?}", e.error);
-                reply.error(e.code);
+
+        // Flock locks belong to the open file, they last until its last handle is released
+        Self::send_lock_replies(self.locks.release_handle(ino, fh, lock_owner));
+        let open_files = self.open_files.clone();
+
+        self.spawn(move |fs| {
+            match fs.release(ino as i64) {
+                Ok(_) => {
+                    open_files.lock().unwrap().remove(&fh);
+                    reply.ok();
+                }
+                Err(e) => {
+                    error!("Error releasing file: {:?}", e.error);
+                    reply.error(e.code);
+                }
             }
-        }
+        });
     }
 
     fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
@@ -411,11 +663,19 @@ impl Filesystem for FuseFileSystem {
         reply.ok();
     }
 
-    fn opendir(&mut self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+    fn opendir(&mut self, req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
         if FINE_LOGGING {
-            trace!("FS opendir(ino: {}, flags: {})", _ino, _flags);
+            trace!("FS opendir(ino: {}, flags: {})", ino, _flags);
         }
-        reply.opened(0, 0);
+        let cred = credentials(req);
+
+        self.spawn(move |fs| {
+            if let Err(e) = fs.check_access(ino as i64, &cred, R_OK) {
+                reply.error(e.code);
+                return;
+            }
+            reply.opened(0, 0);
+        });
     }
 
     fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
@@ -423,24 +683,26 @@ impl Filesystem for FuseFileSystem {
             trace!("FS readdir(ino: {}, file_handle: {}, offset: {})", ino, fh, offset);
         }
 
-        match self.fs.readdir(ino as i64, offset) {
-            Ok(entries) => {
-                let mut index = offset + 1;
-                for e in entries {
-                    let fuse_kind = if e.kind == FILE_KIND_DIRECTORY { FileType::Directory } else { FileType::RegularFile };
-                    let ino = e.entry_file_id as u64;
-                    if reply.add(ino, index, fuse_kind, e.name) {
-                        break;
+        self.spawn(move |fs| {
+            match fs.readdir(ino as i64, offset) {
+                Ok(entries) => {
+                    let mut index = offset + 1;
+                    for e in entries {
+                        let fuse_kind = file_type_of(e.kind);
+                        let ino = e.entry_file_id as u64;
+                        if reply.add(ino, index, fuse_kind, e.name) {
+                            break;
+                        }
+                        index += 1;
                     }
-                    index += 1;
+                    reply.ok();
+                }
+                Err(e) => {
+                    error!("Error reading directory: {:?}", e.error);
+                    reply.error(e.code);
                 }
-                reply.ok();
-            }
-            Err(e) => {
-                error!("Error reading directory: {:?}", e.error);
-                reply.error(e.code);
             }
-        }
+        });
     }
 
     fn readdirplus(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectoryPlus) {
@@ -448,26 +710,40 @@ impl Filesystem for FuseFileSystem {
             trace!("FS readdirplus(ino: {}, file_handle: {}, offset: {})", ino, fh, offset);
         }
 
-        match self.fs.readdir(ino as i64, offset as i64) {
-            Ok(entries) => {
-                let mut index = offset + 1;
-                for e in entries {
-                    let ino = e.entry_file_id as u64;
-                    let file = self.fs.get_file_or_err(e.entry_file_id).unwrap();
-                    let attr = FileAttr::from(&file);
-
-                    if reply.add(ino, index as i64, e.name, &self.get_ttl(), &attr, 0) {
-                        break;
+        self.spawn(move |fs| {
+            match fs.readdir(ino as i64, offset as i64) {
+                Ok(entries) => {
+                    let mut index = offset + 1;
+                    for e in entries {
+                        let ino = e.entry_file_id as u64;
+                        let file = match fs.get_file_or_err(e.entry_file_id) {
+                            Ok(file) => file,
+                            // Removed after the entries were read, the offsets of the next ones are kept
+                            Err(e) if e.code == ENOENT => {
+                                index += 1;
+                                continue;
+                            }
+                            Err(e) => {
+                                error!("Error reading directory: {:?}", e.error);
+                                reply.error(e.code);
+                                return;
+                            }
+                        };
+                        let attr = FileAttr::from(&file);
+
+                        if reply.add(ino, index as i64, e.name, &TTL, &attr, 0) {
+                            break;
+                        }
+                        index += 1;
                     }
-                    index += 1;
+                    reply.ok();
+                }
+                Err(e) => {
+                    error!("Error reading directory: {:?}", e.error);
+                    reply.error(e.code);
                 }
-                reply.ok();
-            }
-            Err(e) => {
-                error!("Error reading directory: {:?}", e.error);
-                reply.error(e.code);
             }
-        }
+        });
     }
 
     fn releasedir(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
@@ -499,68 +775,131 @@ impl Filesystem for FuseFileSystem {
         );
     }
 
-    fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
-        trace!("FS access(ino: {}, mask: {})", _ino, _mask);
-        warn!("Access not implemented");
-        reply.error(ENOSYS);
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        trace!("FS access(ino: {}, mask: {})", ino, mask);
+        let cred = credentials(req);
+
+        self.spawn(move |fs| {
+            let res = if mask as i32 == F_OK {
+                fs.getattr(ino as i64)
+            } else {
+                fs.check_access(ino as i64, &cred, mask as i32)
+            };
+
+            match res {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(e.code),
+            }
+        });
     }
 
-    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, _umask: u32, flags: u32, reply: ReplyCreate) {
-        trace!("FS create(parent: {}, name: {:?}, mode: {}, umask: {}, flags: {})", parent, name, mode, _umask, flags);
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: u32, reply: ReplyCreate) {
+        trace!("FS create(parent: {}, name: {:?}, mode: {}, umask: {}, flags: {})", parent, name, mode, umask, flags);
 
         let open_flags = OpenFlags::from(flags as i32);
-        let flags = open_flags.to_safe_flags() as u32;
 
-        let name = name.to_string_lossy();
-        let file = match self.fs.lookup(parent as i64, &name) {
-            Ok(Some(file)) => {
-                file
+        let name = name.to_string_lossy().to_string();
+        let cred = credentials(req);
+        let fh = self.next_fh();
+        let open_files = self.open_files.clone();
+
+        self.spawn(move |fs| {
+            if let Err(e) = fs.check_access(parent as i64, &cred, X_OK) {
+                reply.error(e.code);
+                return;
             }
-            Ok(None) => {
-                let res = self.fs.mknod(parent as i64, &name, req.uid(), req.gid(), mode);
 
-                match res {
-                    Err(e) => {
-                        error!("Error creating file: {:?}", e.error);
+            let file = match fs.lookup(parent as i64, &name) {
+                Ok(Some(file)) => {
+                    if let Err(e) = fs.check_access(file.id, &cred, open_access_mask(flags as i32)) {
                         reply.error(e.code);
                         return;
                     }
-                    Ok(file) => file
+                    file
+                }
+                Ok(None) => {
+                    if let Err(e) = fs.check_access(parent as i64, &cred, W_OK) {
+                        reply.error(e.code);
+                        return;
+                    }
+
+                    let res = fs.mknod(parent as i64, &name, cred.uid, cred.gid, mode & !umask, 0);
+
+                    match res {
+                        Err(e) => {
+                            error!("Error creating file: {:?}", e.error);
+                            reply.error(e.code);
+                            return;
+                        }
+                        Ok(file) => file
+                    }
+                }
+                Err(e) => {
+                    error!("Error looking up file: {:?}", e.error);
+                    reply.error(e.code);
+                    return;
+                }
+            };
+
+            match open_handle(fs, &cred, file.id, open_flags).and_then(|_| fs.getattr(file.id)) {
+                Ok(file) => {
+                    open_files.lock().unwrap().insert(fh, OpenFile { ino: file.id as u64, flags: open_flags });
+
+                    let attr = FileAttr::from(&file);
+                    reply.created(&TTL, &attr, 0, fh, 0);
+                }
+                Err(e) => {
+                    error!("Error opening file: {:?}", e.error);
+                    reply.error(e.code);
                 }
             }
-            Err(e) => {
-                error!("Error looking up file: {:?}", e.error);
-                reply.error(e.code);
-                return;
-            }
+        });
+    }
+
+    fn getlk(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, reply: ReplyLock) {
+        trace!("FS getlk(ino: {}, file_handle: {}, lock_owner: {}, start: {}, end: {}, typ: {}, pid: {})", ino, fh, lock_owner, start, end, typ, pid);
+
+        let Some(kind) = LockKind::from_type(typ) else {
+            reply.error(EINVAL);
+            return;
         };
 
-        match self.fs.open(file.id, flags) {
-            Ok(_) => {
-                self.fh_counter += 1;
-                let fh = self.fh_counter;
-                self.open_files.insert(fh, file.id as u64);
+        let lock = FileLock { owner: lock_owner, fh, pid, start, end, kind };
 
-                let attr = FileAttr::from(&file);
-                reply.created(&self.get_ttl(), &attr, 0, fh, flags);
-            }
-            Err(e) => {
-                error!("Error opening file: {:?}", e.error);
-                reply.error(e.code);
-            }
+        match self.locks.test(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.kind.to_type(), held.pid),
+            None => reply.locked(start, end, LOCK_TYPE_UNLOCKED, pid),
         }
     }
 
-    fn getlk(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, reply: ReplyLock) {
-        trace!("FS getlk(ino: {}, file_handle: {}, lock_owner: {}, start: {}, end: {}, typ: {}, pid: {})", _ino, _fh, _lock_owner, _start, _end, _typ, _pid);
-        warn!("Getlk not implemented");
-        reply.error(ENOSYS);
-    }
+    fn setlk(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        trace!("FS setlk(ino: {}, file_handle: {}, lock_owner: {}, start: {}, end: {}, typ: {}, pid: {}, sleep: {})", ino, fh, lock_owner, start, end, typ, pid, sleep);
 
-    fn setlk(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
-        trace!("FS setlk(ino: {}, file_handle: {}, lock_owner: {}, start: {}, end: {}, typ: {}, pid: {}, sleep: {})", _ino, _fh, _lock_owner, _start, _end, _typ, _pid, _sleep);
-        warn!("Setlk not implemented");
-        reply.error(ENOSYS);
+        if typ == LOCK_TYPE_UNLOCKED {
+            Self::send_lock_replies(self.locks.unlock(ino, lock_owner, start, end));
+            reply.ok();
+            return;
+        }
+
+        let Some(kind) = LockKind::from_type(typ) else {
+            reply.error(EINVAL);
+            return;
+        };
+
+        let lock = FileLock { owner: lock_owner, fh, pid, start, end, kind };
+
+        if sleep {
+            // The request loop must keep running to process the unlock, so the reply is stored until then
+            if let Some(replies) = self.locks.set_wait(ino, lock, reply) {
+                Self::send_lock_replies(vec![replies]);
+            }
+            return;
+        }
+
+        match self.locks.set(ino, lock) {
+            Ok(()) => reply.ok(),
+            Err(code) => reply.error(code),
+        }
     }
 
     fn bmap(&mut self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
@@ -571,20 +910,43 @@ impl Filesystem for FuseFileSystem {
 
     fn ioctl(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _flags: u32, _cmd: u32, _in_data: Option<&[u8]>, _out_size: u32, reply: ReplyIoctl) {
         trace!("FS ioctl(ino: {}, file_handle: {}, flags: {}, cmd: {}, in_data: {:?}, out_size: {})", _ino, _fh, _flags, _cmd, _in_data, _out_size);
-        warn!("Operation ioctl not implemented");
-        reply.error(ENOSYS);
+        // No ioctl is supported. Cloning in the mount is out of scope: FICLONE is answered by the kernel without asking
+        // FUSE filesystems and the binding does not forward copy_file_range, files are cloned with `innerfs cp`
+        reply.error(ENOTTY);
     }
 
-    fn fallocate(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: u64, _length: u64, _mode: u32, reply: ReplyEmpty) {
-        trace!("FS fallocate(ino: {})", _ino);
-        warn!("Operation fallocate not implemented");
-        reply.error(ENOSYS);
+    fn fallocate(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: u64, length: u64, mode: u32, reply: ReplyEmpty) {
+        trace!("FS fallocate(ino: {}, file_handle: {}, offset: {}, length: {}, mode: {})", ino, fh, offset, length, mode);
+
+        self.spawn(move |fs| {
+            match fs.fallocate(ino as i64, offset, length, mode as i32) {
+                Ok(_) => {
+                    reply.ok();
+                }
+                Err(e) => {
+                    if e.code != EOPNOTSUPP {
+                        error!("Error in fallocate: {:?}", e.error);
+                    }
+                    reply.error(e.code);
+                }
+            }
+        });
     }
 
-    fn lseek(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _whence: u32, reply: ReplyLseek) {
-        trace!("FS lseek(ino: {}, file_handle: {}, offset: {}, whence: {})", _ino, _fh, _offset, _whence);
-        warn!("Operation lseek not implemented");
-        reply.error(ENOSYS);
+    fn lseek(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, whence: u32, reply: ReplyLseek) {
+        trace!("FS lseek(ino: {}, file_handle: {}, offset: {}, whence: {})", ino, fh, offset, whence);
+
+        // The kernel resolves SEEK_SET, SEEK_CUR and SEEK_END, only SEEK_DATA and SEEK_HOLE reach the filesystem
+        self.spawn(move |fs| {
+            match fs.seek(ino as i64, offset, whence as i32) {
+                Ok(offset) => {
+                    reply.offset(offset);
+                }
+                Err(e) => {
+                    reply.error(e.code);
+                }
+            }
+        });
     }
 }
 
@@ -593,18 +955,127 @@ impl From<&FileRow> for FileAttr {
         FileAttr {
             ino: value.id as u64,
             size: value.size as u64,
-            blocks: value.size as u64 / BLOCK_SIZE as u64,
+            // Always in units of 512 bytes, holes are not counted
+            blocks: (value.allocated_size as u64).div_ceil(512),
             atime: system_time_from_timestamp(value.accessed_at),
             mtime: system_time_from_timestamp(value.updated_at),
-            ctime: system_time_from_timestamp(value.updated_at),
+            ctime: system_time_from_timestamp(value.changed_at),
             crtime: system_time_from_timestamp(value.created_at),
-            kind: if value.kind == 1 { FileType::Directory } else { FileType::RegularFile },
-            perm: value.perms as u16,
+            kind: file_type_of(value.kind),
+            perm: (value.perms & 0o7777) as u16,
             nlink: if value.kind == 1 { 2 } else { 1 },
             uid: value.uid as u32,
             gid: value.gid as u32,
-            rdev: 0,
+            rdev: value.rdev as u32,
             flags: 0,
         }
     }
 }
+
+#[test]
+fn test_append_handle_writes_at_offset() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+
+    let fs = crate::sql_fs::test_file_system("append", "");
+    let file = fs.mknod(ROOT_DIRECTORY_ID, "log", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(file.id, b"hello world").unwrap();
+
+    let flags = OpenFlags::from(O_WRONLY | O_APPEND);
+    assert!(flags.append && !flags.read_only);
+    fs.open(file.id, flags.read_only).unwrap();
+
+    // With writeback caching the kernel sends the dirty pages of an O_APPEND handle at their own offsets
+    fs.write(file.id, 11, b"!").unwrap();
+    fs.write(file.id, 0, b"HELLO").unwrap();
+    assert_eq!(fs.read(file.id, 0, 64).unwrap(), b"HELLO world!");
+    fs.release(file.id).unwrap();
+
+    assert_eq!(fs.read_all(file.id).unwrap(), b"HELLO world!");
+}
+
+#[test]
+fn test_truncating_open_replaces_contents() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+
+    let fs = crate::sql_fs::test_file_system("truncate", "");
+    let cred = Credentials::new(0, 0, 0);
+    let file = fs.mknod(ROOT_DIRECTORY_ID, "file", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(file.id, b"a longer first version").unwrap();
+
+    // Overwriting with shorter contents, like `echo short > file`
+    open_handle(&fs, &cred, file.id, OpenFlags::from(O_WRONLY | O_TRUNC)).unwrap();
+    assert_eq!(fs.getattr(file.id).unwrap().size, 0);
+    fs.write(file.id, 0, b"short").unwrap();
+    fs.release(file.id).unwrap();
+    assert_eq!(fs.read_all(file.id).unwrap(), b"short");
+
+    // Read-only handles never truncate
+    open_handle(&fs, &cred, file.id, OpenFlags::from(O_RDONLY | O_TRUNC)).unwrap();
+    fs.release(file.id).unwrap();
+    assert_eq!(fs.read_all(file.id).unwrap(), b"short");
+
+    fs.write_all(file.id, b"tiny").unwrap();
+    assert_eq!(fs.read_all(file.id).unwrap(), b"tiny");
+}
+
+#[test]
+fn test_rename_flags() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+
+    let fs = crate::sql_fs::test_file_system("rename2", "");
+    let cred = Credentials::new(0, 0, 0);
+    let root = ROOT_DIRECTORY_ID as u64;
+    let a = fs.mknod(ROOT_DIRECTORY_ID, "a", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    let b = fs.mknod(ROOT_DIRECTORY_ID, "b", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(a.id, b"contents of a").unwrap();
+    fs.write_all(b.id, b"contents of b").unwrap();
+
+    // The target exists, nothing changes
+    let e = rename_entry(&fs, &cred, root, "a", root, "b", RENAME_NOREPLACE).unwrap_err();
+    assert_eq!(e.code, EEXIST);
+    assert_eq!(fs.read_all(b.id).unwrap(), b"contents of b");
+
+    rename_entry(&fs, &cred, root, "a", root, "c", RENAME_NOREPLACE).unwrap();
+    assert!(fs.lookup(ROOT_DIRECTORY_ID, "a").unwrap().is_none());
+    assert_eq!(fs.lookup(ROOT_DIRECTORY_ID, "c").unwrap().unwrap().id, a.id);
+
+    rename_entry(&fs, &cred, root, "c", root, "b", RENAME_EXCHANGE).unwrap();
+    let (b_entry, c_entry) = (fs.lookup(ROOT_DIRECTORY_ID, "b").unwrap().unwrap(), fs.lookup(ROOT_DIRECTORY_ID, "c").unwrap().unwrap());
+    assert_eq!((b_entry.id, c_entry.id), (a.id, b.id));
+    assert_eq!(fs.read_all(a.id).unwrap(), b"contents of a");
+    assert_eq!(fs.read_all(b.id).unwrap(), b"contents of b");
+
+    // Both files must exist, and the flags can't be combined
+    assert_eq!(rename_entry(&fs, &cred, root, "b", root, "d", RENAME_EXCHANGE).unwrap_err().code, ENOENT);
+    assert_eq!(rename_entry(&fs, &cred, root, "b", root, "c", RENAME_EXCHANGE | RENAME_NOREPLACE).unwrap_err().code, EINVAL);
+}
+
+#[test]
+fn test_moved_directories_point_to_new_parent() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+
+    let fs = crate::sql_fs::test_file_system("rename-dirs", "");
+    let cred = Credentials::new(0, 0, 0);
+    let root = ROOT_DIRECTORY_ID as u64;
+    let parent = |dir: i64| fs.sql.find_directory_entry(dir, "..").unwrap().unwrap().entry_file_id;
+    let src = fs.mkdir(ROOT_DIRECTORY_ID, "src", 0, 0, 0o755).unwrap();
+    let dst = fs.mkdir(ROOT_DIRECTORY_ID, "dst", 0, 0, 0o755).unwrap();
+    let dir = fs.mkdir(src.id, "dir", 0, 0, 0o755).unwrap();
+    let file = fs.mknod(dst.id, "file", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(file.id, b"contents").unwrap();
+
+    rename_entry(&fs, &cred, src.id as u64, "dir", dst.id as u64, "moved", RENAME_NOREPLACE).unwrap();
+    assert_eq!(parent(dir.id), dst.id);
+
+    // Each directory of an exchange points to the parent of the other entry
+    rename_entry(&fs, &cred, dst.id as u64, "moved", root, "src", RENAME_EXCHANGE).unwrap();
+    assert_eq!(fs.lookup(ROOT_DIRECTORY_ID, "src").unwrap().unwrap().id, dir.id);
+    assert_eq!(fs.lookup(dst.id, "moved").unwrap().unwrap().id, src.id);
+    assert_eq!((parent(dir.id), parent(src.id)), (ROOT_DIRECTORY_ID, dst.id));
+
+    // A directory exchanged with a file, the file keeps its contents at the new path
+    rename_entry(&fs, &cred, root, "src", dst.id as u64, "file", RENAME_EXCHANGE).unwrap();
+    assert_eq!(parent(dir.id), dst.id);
+    assert_eq!(fs.lookup(ROOT_DIRECTORY_ID, "src").unwrap().unwrap().id, file.id);
+    assert_eq!(fs.read_all(file.id).unwrap(), b"contents");
+}
@@ -0,0 +1,164 @@
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use anyhow::anyhow;
+use cntr_fuse::consts::{FUSE_ASYNC_READ, FUSE_ATOMIC_O_TRUNC, FUSE_DONT_MASK, FUSE_FLOCK_LOCKS, FUSE_PARALLEL_DIROPS, FUSE_POSIX_LOCKS, FUSE_WRITEBACK_CACHE};
+use cntr_fuse::{Filesystem, Session};
+use cntr_fuse_sys::{fuse_args, fuse_mount_compat25};
+use libc::{c_void, EPROTO};
+use log::debug;
+use crate::AnyError;
+
+/// Features requested to the kernel. The FUSE binding has a fixed list without locks, so the INIT request is answered
+/// here, adding POSIX and flock locks, before the session takes over the channel.
+/// FUSE_POSIX_ACL is left out: it turns on `default_permissions`, and permissions are checked by the filesystem.
+pub const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_PARALLEL_DIROPS | FUSE_ATOMIC_O_TRUNC | FUSE_WRITEBACK_CACHE
+    | FUSE_DONT_MASK | FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS;
+
+// Protocol version and write size of the binding, the rest of the requests are parsed by it
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
+const MAX_WRITE_SIZE: u32 = 128 * 1024;
+const BUFFER_SIZE: usize = MAX_WRITE_SIZE as usize + 4096;
+
+const FUSE_INIT: u32 = 26;
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+
+/// Mount the filesystem and serve it until it is unmounted
+pub fn mount<FS: Filesystem>(filesystem: FS, mount_point: &Path, options: &[&OsStr]) -> Result<(), AnyError> {
+    let mount_point = mount_point.canonicalize()?;
+    let fd = open_channel(&mount_point, options)?;
+
+    // The session closes the channel and unmounts when dropped
+    let mut session = Session::new_from_fd(filesystem, fd, &mount_point, 0, 0)?;
+    init(fd)?;
+    session.run()?;
+    Ok(())
+}
+
+fn open_channel(mount_point: &Path, options: &[&OsStr]) -> Result<RawFd, AnyError> {
+    let args: Vec<CString> = std::iter::once(OsStr::new("innerfs")).chain(options.iter().copied())
+        .map(|arg| CString::new(arg.as_bytes()))
+        .collect::<Result<_, _>>()?;
+    let argv: Vec<_> = args.iter().map(|arg| arg.as_ptr()).collect();
+    let mount_point = CString::new(mount_point.as_os_str().as_bytes())?;
+
+    let fd = unsafe {
+        fuse_mount_compat25(mount_point.as_ptr(), &fuse_args { argc: argv.len() as i32, argv: argv.as_ptr(), allocated: 0 })
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(fd)
+}
+
+/// Answer the INIT requests, the kernel sends it again if it wants an older major version
+fn init(fd: RawFd) -> Result<(), AnyError> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+
+    loop {
+        let len = unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()) };
+        if len < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error.into());
+        }
+
+        let (reply, done) = init_reply(&buffer[..len as usize])?;
+        if unsafe { libc::write(fd, reply.as_ptr() as *const c_void, reply.len()) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Reply to an INIT request, and whether the kernel accepts it
+fn init_reply(request: &[u8]) -> Result<(Vec<u8>, bool), AnyError> {
+    let field = |offset: usize| -> Result<u32, AnyError> {
+        let bytes = request.get(offset..offset + 4).ok_or_else(|| anyhow!("Truncated FUSE request"))?;
+        Ok(u32::from_ne_bytes(bytes.try_into().unwrap()))
+    };
+
+    if field(4)? != FUSE_INIT {
+        return Err(anyhow!("Expected a FUSE INIT request, got opcode {}", field(4)?));
+    }
+    let unique = u64::from_ne_bytes(request[8..16].try_into().unwrap());
+    let [major, minor, max_readahead, flags] = [0, 4, 8, 12].map(|offset| field(IN_HEADER_SIZE + offset));
+    let (major, minor) = (major?, minor?);
+
+    let header = |len: usize, error: i32| {
+        let mut out = Vec::with_capacity(len);
+        out.extend_from_slice(&(len as u32).to_ne_bytes());
+        out.extend_from_slice(&error.to_ne_bytes());
+        out.extend_from_slice(&unique.to_ne_bytes());
+        out
+    };
+
+    // Same versions as the binding: older than 7.6 is not supported, newer majors are asked to use 7
+    if major < FUSE_KERNEL_VERSION || (major == FUSE_KERNEL_VERSION && minor < 6) {
+        return Ok((header(OUT_HEADER_SIZE, -EPROTO), false));
+    }
+
+    // The reply grew in 7.23, older kernels expect the short one
+    let size = if minor < 23 { 24 } else { 64 };
+    let mut reply = header(OUT_HEADER_SIZE + size, 0);
+    reply.extend_from_slice(&FUSE_KERNEL_VERSION.to_ne_bytes());
+    reply.extend_from_slice(&FUSE_KERNEL_MINOR_VERSION.to_ne_bytes());
+    reply.extend_from_slice(&max_readahead?.to_ne_bytes());
+    reply.extend_from_slice(&(flags? & INIT_FLAGS).to_ne_bytes());
+    // Default max_background and congestion_threshold
+    reply.extend_from_slice(&[0; 4]);
+    reply.extend_from_slice(&MAX_WRITE_SIZE.to_ne_bytes());
+    if size == 64 {
+        // Timestamps with nanoseconds
+        reply.extend_from_slice(&1u32.to_ne_bytes());
+    }
+    reply.resize(OUT_HEADER_SIZE + size, 0);
+
+    debug!("INIT response: ABI {}.{}, kernel {}.{}", FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION, major, minor);
+    Ok((reply, major == FUSE_KERNEL_VERSION))
+}
+
+#[test]
+fn test_init_negotiates_locks() {
+    let request = |major: u32, minor: u32, flags: u32| {
+        let mut request = vec![];
+        for value in [IN_HEADER_SIZE as u32 + 16, FUSE_INIT, 42, 0, 1, 0, 0, 0, 0, 0, major, minor, 65536, flags] {
+            request.extend_from_slice(&value.to_ne_bytes());
+        }
+        request
+    };
+    let field = |reply: &[u8], offset: usize| u32::from_ne_bytes(reply[offset..offset + 4].try_into().unwrap());
+
+    let (reply, done) = init_reply(&request(7, 38, u32::MAX)).unwrap();
+    assert!(done);
+    assert_eq!((reply.len(), field(&reply, 0) as usize, field(&reply, 4)), (80, 80, 0));
+    assert_eq!(u64::from_ne_bytes(reply[8..16].try_into().unwrap()), 42);
+    assert_eq!((field(&reply, 16), field(&reply, 20), field(&reply, 24)), (7, 19, 65536));
+    assert_eq!(field(&reply, 28), INIT_FLAGS);
+    assert_ne!(field(&reply, 28) & FUSE_POSIX_LOCKS, 0);
+    assert_ne!(field(&reply, 28) & FUSE_FLOCK_LOCKS, 0);
+    assert_eq!(field(&reply, 28) & cntr_fuse::consts::FUSE_POSIX_ACL, 0);
+    assert_eq!((field(&reply, 36), field(&reply, 40)), (MAX_WRITE_SIZE, 1));
+
+    // Only the features the kernel supports
+    let (reply, _) = init_reply(&request(7, 22, FUSE_POSIX_LOCKS | FUSE_ASYNC_READ | 1 << 30)).unwrap();
+    assert_eq!(reply.len(), 40);
+    assert_eq!(field(&reply, 28), FUSE_POSIX_LOCKS | FUSE_ASYNC_READ);
+
+    // The kernel asks again with the major version of the reply
+    let (reply, done) = init_reply(&request(8, 0, 0)).unwrap();
+    assert!(!done);
+    assert_eq!(field(&reply, 16), 7);
+
+    let (reply, done) = init_reply(&request(7, 5, 0)).unwrap();
+    assert!(!done);
+    assert_eq!(field(&reply, 4) as i32, -EPROTO);
+}
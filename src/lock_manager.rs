@@ -0,0 +1,276 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use libc::{c_int, EAGAIN, EDEADLK, EINTR, F_RDLCK, F_UNLCK, F_WRLCK};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LockKind {
+    Read,
+    Write,
+}
+
+/// Byte-range lock held by a lock owner, ranges are inclusive like in `struct flock`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileLock {
+    pub owner: u64,
+    pub fh: u64,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub kind: LockKind,
+}
+
+pub struct PendingLock<W> {
+    pub ino: u64,
+    pub lock: FileLock,
+    pub waiter: W,
+}
+
+/// Type reported to getlk when the lock can be acquired
+pub const LOCK_TYPE_UNLOCKED: u32 = F_UNLCK as u32;
+
+/// Result for a waiter that is no longer pending, `Ok` if the lock was acquired
+pub type LockReply<W> = (W, Result<(), c_int>);
+
+/// Advisory locks (fcntl and flock) per inode. When the kernel forwards them to the filesystem it no longer tracks them,
+/// so the conflicts must be resolved here.
+/// Flock locks arrive as whole file locks owned by the open file, so they follow the same rules.
+/// Blocking requests are queued with their waiter (the pending FUSE reply) and answered once the lock is acquired.
+pub struct LockManager<W> {
+    pub locks: HashMap<u64, Vec<FileLock>>,
+    pub pending: VecDeque<PendingLock<W>>,
+}
+
+impl LockKind {
+    pub fn from_type(typ: u32) -> Option<Self> {
+        match typ as c_int {
+            F_RDLCK => Some(LockKind::Read),
+            F_WRLCK => Some(LockKind::Write),
+            _ => None,
+        }
+    }
+
+    pub fn to_type(self) -> u32 {
+        match self {
+            LockKind::Read => F_RDLCK as u32,
+            LockKind::Write => F_WRLCK as u32,
+        }
+    }
+}
+
+impl FileLock {
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    pub fn conflicts_with(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.kind == LockKind::Write || other.kind == LockKind::Write)
+    }
+}
+
+impl<W> LockManager<W> {
+    pub fn new() -> Self {
+        LockManager {
+            locks: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// First lock held by another owner that prevents acquiring `lock`
+    pub fn test(&self, ino: u64, lock: &FileLock) -> Option<&FileLock> {
+        self.locks.get(&ino)?.iter().find(|held| held.conflicts_with(lock))
+    }
+
+    /// Acquire a lock or fail with EAGAIN
+    pub fn set(&mut self, ino: u64, lock: FileLock) -> Result<(), c_int> {
+        if self.test(ino, &lock).is_some() {
+            return Err(EAGAIN);
+        }
+        self.insert(ino, lock);
+        Ok(())
+    }
+
+    /// Acquire a lock, or queue the waiter until it can be acquired.
+    /// Returns the waiter back if it must be answered right away.
+    pub fn set_wait(&mut self, ino: u64, lock: FileLock, waiter: W) -> Option<LockReply<W>> {
+        if self.test(ino, &lock).is_none() {
+            self.insert(ino, lock);
+            return Some((waiter, Ok(())));
+        }
+
+        if self.would_deadlock(ino, &lock) {
+            return Some((waiter, Err(EDEADLK)));
+        }
+
+        self.pending.push_back(PendingLock { ino, lock, waiter });
+        None
+    }
+
+    /// Remove the range from the locks of an owner, splitting locks that partially overlap
+    pub fn unlock(&mut self, ino: u64, owner: u64, start: u64, end: u64) -> Vec<LockReply<W>> {
+        if let Some(locks) = self.locks.get_mut(&ino) {
+            Self::remove_range(locks, owner, start, end);
+        }
+        self.wake(ino)
+    }
+
+    /// Release every lock of an owner, used on flush, when a process closes any descriptor of the file
+    pub fn release_owner(&mut self, ino: u64, owner: u64) -> Vec<LockReply<W>> {
+        self.unlock(ino, owner, 0, u64::MAX)
+    }
+
+    /// Release every lock acquired through a file handle, and cancel the requests still waiting on it
+    pub fn release_handle(&mut self, ino: u64, fh: u64, owner: u64) -> Vec<LockReply<W>> {
+        if let Some(locks) = self.locks.get_mut(&ino) {
+            locks.retain(|held| held.fh != fh && held.owner != owner);
+        }
+
+        let mut replies = vec![];
+        let mut index = 0;
+        while index < self.pending.len() {
+            if self.pending[index].ino == ino && self.pending[index].lock.fh == fh {
+                let pending = self.pending.remove(index).unwrap();
+                replies.push((pending.waiter, Err(EINTR)));
+            } else {
+                index += 1;
+            }
+        }
+
+        replies.extend(self.wake(ino));
+        replies
+    }
+
+    fn insert(&mut self, ino: u64, lock: FileLock) {
+        let locks = self.locks.entry(ino).or_default();
+
+        // A new lock replaces the locks of the same owner in that range (upgrade, downgrade or merge)
+        Self::remove_range(locks, lock.owner, lock.start, lock.end);
+
+        let mut lock = lock;
+        locks.retain(|held| {
+            let adjacent = held.end.checked_add(1) == Some(lock.start) || lock.end.checked_add(1) == Some(held.start);
+            let mergeable = held.owner == lock.owner && held.kind == lock.kind && held.fh == lock.fh;
+
+            if mergeable && (adjacent || held.overlaps(lock.start, lock.end)) {
+                lock.start = lock.start.min(held.start);
+                lock.end = lock.end.max(held.end);
+                false
+            } else {
+                true
+            }
+        });
+
+        locks.push(lock);
+    }
+
+    fn remove_range(locks: &mut Vec<FileLock>, owner: u64, start: u64, end: u64) {
+        let mut remaining = vec![];
+
+        for held in locks.drain(..) {
+            if held.owner != owner || !held.overlaps(start, end) {
+                remaining.push(held);
+                continue;
+            }
+            if held.start < start {
+                remaining.push(FileLock { end: start - 1, ..held.clone() });
+            }
+            if held.end > end {
+                remaining.push(FileLock { start: end + 1, ..held.clone() });
+            }
+        }
+
+        *locks = remaining;
+    }
+
+    /// Grant pending requests in arrival order, until no more can proceed
+    fn wake(&mut self, ino: u64) -> Vec<LockReply<W>> {
+        if self.locks.get(&ino).is_some_and(|locks| locks.is_empty()) {
+            self.locks.remove(&ino);
+        }
+
+        let mut replies = vec![];
+        let mut index = 0;
+
+        while index < self.pending.len() {
+            let pending = &self.pending[index];
+
+            if pending.ino == ino && self.test(ino, &pending.lock).is_none() {
+                let pending = self.pending.remove(index).unwrap();
+                self.insert(ino, pending.lock);
+                replies.push((pending.waiter, Ok(())));
+            } else {
+                index += 1;
+            }
+        }
+
+        replies
+    }
+
+    /// Owners holding locks that prevent acquiring `lock`
+    fn blockers(&self, ino: u64, lock: &FileLock) -> Vec<u64> {
+        match self.locks.get(&ino) {
+            Some(locks) => locks.iter().filter(|held| held.conflicts_with(lock)).map(|held| held.owner).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Follow the owners blocking the request through their own pending requests, looking for the requester
+    fn would_deadlock(&self, ino: u64, lock: &FileLock) -> bool {
+        let mut visited = HashSet::new();
+        let mut owners = self.blockers(ino, lock);
+
+        while let Some(owner) = owners.pop() {
+            if owner == lock.owner {
+                return true;
+            }
+            if !visited.insert(owner) {
+                continue;
+            }
+            for pending in self.pending.iter().filter(|pending| pending.lock.owner == owner) {
+                owners.extend(self.blockers(pending.ino, &pending.lock));
+            }
+        }
+
+        false
+    }
+}
+
+#[test]
+fn test_lock_conflicts_and_partial_unlock() {
+    let mut locks: LockManager<()> = LockManager::new();
+    let lock = |owner, start, end, kind| FileLock { owner, fh: owner, pid: 0, start, end, kind };
+
+    assert_eq!(locks.set(1, lock(1, 0, 99, LockKind::Read)), Ok(()));
+    assert_eq!(locks.set(1, lock(2, 50, 149, LockKind::Read)), Ok(()));
+    assert_eq!(locks.set(1, lock(3, 90, 95, LockKind::Write)), Err(EAGAIN));
+
+    // Other inodes are independent
+    assert_eq!(locks.set(2, lock(3, 90, 95, LockKind::Write)), Ok(()));
+
+    // Unlocking the middle of a range splits it in two
+    locks.unlock(1, 1, 20, 29);
+    assert_eq!(locks.locks[&1].iter().filter(|l| l.owner == 1).count(), 2);
+    assert_eq!(locks.set(1, lock(3, 20, 29, LockKind::Write)), Ok(()));
+    assert_eq!(locks.set(1, lock(3, 19, 29, LockKind::Write)), Err(EAGAIN));
+
+    // Upgrading only conflicts with other owners
+    locks.release_owner(1, 2);
+    assert_eq!(locks.set(1, lock(1, 30, 99, LockKind::Write)), Ok(()));
+}
+
+#[test]
+fn test_lock_wait_and_deadlock() {
+    let mut locks: LockManager<&str> = LockManager::new();
+    let lock = |owner, start, end| FileLock { owner, fh: owner, pid: 0, start, end, kind: LockKind::Write };
+
+    assert!(locks.set_wait(1, lock(1, 0, 9), "a").is_some());
+    assert!(locks.set_wait(1, lock(2, 10, 19), "b").is_some());
+
+    // Owner 1 waits for owner 2, then owner 2 waiting for owner 1 would never finish
+    assert!(locks.set_wait(1, lock(1, 10, 19), "c").is_none());
+    assert_eq!(locks.set_wait(1, lock(2, 0, 9), "d"), Some(("d", Err(EDEADLK))));
+
+    // Releasing the handle of owner 2 grants the pending request of owner 1
+    assert_eq!(locks.release_handle(1, 2, 2), vec![("c", Ok(()))]);
+    assert!(locks.pending.is_empty());
+}
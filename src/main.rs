@@ -1,37 +1,54 @@
-use crate::config::{check_config_changes, read_config};
+use crate::config::{check_config_changes, read_config, Config, StorageOption};
 use crate::fuse_fs::FuseFileSystem;
-use crate::metadata_db::{MetadataDB, NO_BINDINGS};
-use crate::obj_storage::{create_object_storage, ObjectStorage};
+use crate::metadata_db::{kind_name, unlinked_file_path, MetadataDB, FILE_KIND_BLOCK_DEVICE, FILE_KIND_CHAR_DEVICE, FILE_KIND_DIRECTORY, FILE_KIND_FIFO, FILE_KIND_REGULAR, FILE_KIND_SOCKET, NO_BINDINGS};
+use crate::obj_storage::{create_async_object_storage, create_backend_object_storage, create_object_storage, runtime, wrap_object_storage, ObjInfo, ObjectStorage};
 use anyhow::{anyhow, Context};
 use env_logger::Env;
 use fs::File;
 use log::{error, info, warn};
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::io::Write;
-use std::path::{PathBuf};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::{env, fs, thread};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::spawn_blocking;
 
 mod config;
 mod metadata_db;
 mod fuse_fs;
+mod fuse_session;
+mod lock_manager;
+mod permissions;
 mod sql_fs;
 mod storage;
 mod obj_storage;
 mod storage_interface;
+mod sparse;
 mod fs_tree;
 mod utils;
 mod cli;
+mod worker_pool;
 
-use crate::cli::{Cli, Commands, FileExportFormat, IndexExportFormat};
+use crate::cli::{Cli, Commands, DuSortOrder, FileExportFormat, FindArgs, FindKind, IndexExportFormat, SqlOutputFormat};
 use crate::fs_tree::{FsTree, FsTreeKind};
+use crate::obj_storage::blocking_object_storage::BlockingObjectStorage;
+use crate::obj_storage::cached_object_storage::CachedObjectStorage;
 use crate::obj_storage::replicated_object_storage::ReplicatedObjectStorage;
-use crate::sql_fs::SqlFileSystem;
+use crate::obj_storage::retrying_object_storage::RetryStats;
+use crate::sql_fs::{SqlFileSystem, CACHE_STATS_SETTING, RETRY_STATS_SETTING};
+use crate::storage::CacheStats;
 use crate::storage_interface::StorageInterface;
-use crate::utils::humanize_bytes_binary;
+use crate::utils::{csv_escape, device_numbers, format_table, humanize_bytes_binary, join_timestamp, NANOS_PER_SECOND};
 use clap::{Parser};
 use flate2::{write::GzEncoder, Compression};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use itertools::Itertools;
+use regex::Regex;
 use serde_json::json;
 use signal_hook::{consts::SIGINT, iterator::Signals};
 use utils::ask_for_confirmation;
@@ -77,7 +94,16 @@ fn main() {
     let config = read_config(&config_path).expect("Unable to read config");
     info!("Config loaded");
 
-    let sql = Rc::new(MetadataDB::open(&config.database_file));
+    // Held until the process exits, the garbage collector can't run while the filesystem is in use
+    let _lock = match lock_database(&config, matches!(cli.command, Some(Commands::Gc { .. }))) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let sql = Arc::new(MetadataDB::open(&config.database_file));
     sql.run_migrations().expect("Unable to run migrations");
 
     // Check if the nuke command is being executed
@@ -91,31 +117,48 @@ fn main() {
         check_config_changes("primary", config.primary.clone(), sql.clone()).unwrap();
     }
 
-    // Select the appropriate storage backend
-    let mut obj_storage: Box<dyn ObjectStorage> = create_object_storage(config.primary.clone(), sql.clone());
-
-    // Add replicas
-    if !config.replicas.is_empty() {
-        let mut rep = ReplicatedObjectStorage {
-            primary: obj_storage,
-            replicas: vec![],
-        };
-
+    if !is_nuke {
         for (index, replica) in config.replicas.iter().enumerate() {
-            if !is_nuke {
-                check_config_changes(&format!("replica_{}", index), replica.clone(), sql.clone()).unwrap();
-            }
-
-            rep.replicas.push(
-                create_object_storage(replica.clone(), sql.clone())
-            );
+            check_config_changes(&format!("replica_{}", index), replica.clone(), sql.clone()).unwrap();
         }
+    }
 
-        obj_storage = Box::new(rep);
+    // Object keys are specific to each backend, so the garbage collector needs them separately
+    if let Some(Commands::Gc { dry_run, older_than }) = cli.command {
+        let storages = std::iter::once(&config.primary).chain(&config.replicas)
+            .map(|storage| create_object_storage(storage.clone(), sql.clone()))
+            .collect();
+        gc(sql, config, storages, dry_run, older_than).unwrap();
+        return;
+    }
+
+    // Objects of remote backends are kept in a local cache, except to verify the ones in the backend.
+    // The cache is below the encryption and compression, so it keeps the objects as they are stored
+    let use_object_cache = !matches!(cli.command, Some(Commands::Verify));
+    let mut obj_storage = if config.primary.storage_backend == StorageOption::S3 && config.object_cache_size > 0 && use_object_cache {
+        let cache = CachedObjectStorage::new(
+            create_backend_object_storage(config.primary.clone(), sql.clone()),
+            PathBuf::from(&config.cache_directory).join("objects"),
+            config.object_cache_size,
+            config.object_cache_policy,
+        ).unwrap();
+        wrap_object_storage(config.primary.clone(), Box::new(cache))
+    } else {
+        create_object_storage(config.primary.clone(), sql.clone())
+    };
+
+    // Add replicas, they are written at the same time as the primary
+    if !config.replicas.is_empty() {
+        obj_storage = Box::new(ReplicatedObjectStorage {
+            primary: BlockingObjectStorage::new(Arc::from(obj_storage)),
+            replicas: config.replicas.iter()
+                .map(|replica| create_async_object_storage(replica.clone(), sql.clone()))
+                .collect(),
+        });
     }
 
     // Wrap the storage backend in a StorageInterface, which provides a higher-level API
-    let storage = Box::new(StorageInterface::new(obj_storage));
+    let storage = Box::new(StorageInterface::new(obj_storage, config.cache_memory_limit, PathBuf::from(&config.cache_directory).join("spill")));
     let fs = SqlFileSystem::new(sql, config.clone(), storage);
 
     let cmd = cli.command.unwrap_or_else(|| Commands::Mount);
@@ -127,7 +170,17 @@ fn main() {
         Commands::ExportFiles { format, path } => export_files(fs, format, path).unwrap(),
         Commands::GenerateConfig => unreachable!(),
         Commands::Stats => stats(fs).unwrap(),
-        Commands::Verify => verify(fs).unwrap(),
+        Commands::Verify => {
+            if verify(fs).unwrap() > 0 {
+                std::process::exit(1);
+            }
+        }
+        Commands::DedupReport { top } => dedup_report(fs, top).unwrap(),
+        Commands::Du { path, depth, sort, json } => du(fs, &path, depth, sort, json).unwrap(),
+        Commands::Find(args) => find(fs, *args).unwrap(),
+        Commands::Sql { query, format } => sql_query(fs, &query, format).unwrap(),
+        Commands::Cp { source, destination } => copy(fs, &source, &destination).unwrap(),
+        Commands::Gc { .. } => unreachable!(),
     }
 }
 
@@ -164,7 +217,9 @@ fn mount(fs: SqlFileSystem) -> Result<(), AnyError> {
     });
 
     info!("Mounting filesystem at {}", &mount_point);
-    match cntr_fuse::mount(proxy, &mount_point, &[OsStr::new("noempty"), OsStr::new("default_permissions")]) {
+    // Permissions are checked by the filesystem itself, `default_permissions` is not used and POSIX ACLs are not
+    // negotiated, as they would make the kernel check them too
+    match fuse_session::mount(proxy, Path::new(&mount_point), &[OsStr::new("noempty")]) {
         Ok(_) => {}
         Err(e) => {
             error!("Unable to mount filesystem: {}", e);
@@ -180,7 +235,7 @@ fn mount(fs: SqlFileSystem) -> Result<(), AnyError> {
 }
 
 /// Delete all data stored
-fn nuke(mut fs: SqlFileSystem, force: bool) -> Result<(), AnyError> {
+fn nuke(fs: SqlFileSystem, force: bool) -> Result<(), AnyError> {
     if !force {
         warn!("Are you sure you want to delete all data?");
         if !ask_for_confirmation("This operation is irreversible. Type 'yes' or 'y' to proceed") {
@@ -215,11 +270,51 @@ fn export_index(fs: SqlFileSystem, format: IndexExportFormat) -> Result<(), AnyE
     Ok(())
 }
 
+/// Number of files read ahead of the one being exported, so their storage requests overlap
+const EXPORT_PREFETCH: usize = 8;
+
+/// Memory for the files read ahead, in KiB. A larger file waits until it is the only one in memory
+const EXPORT_PREFETCH_KIB: u32 = 256 * 1024;
+
+/// Contents of the files in the given order, several of them are read at the same time. Each file holds a part of the
+/// memory for read ahead, given back when the contents are dropped
+fn prefetch_files(fs: Arc<SqlFileSystem>, files: Vec<(i64, u64)>, memory_kib: u32) -> BoxStream<'static, Result<(Vec<u8>, OwnedSemaphorePermit), AnyError>> {
+    let memory = Arc::new(Semaphore::new(memory_kib as usize));
+
+    stream::iter(files)
+        // Taken in order, so a file never waits for memory held by the ones after it
+        .then(move |(id, size)| {
+            let memory = memory.clone();
+            let kib = size.div_ceil(1024).min(memory_kib as u64) as u32;
+            async move { (id, memory.acquire_many_owned(kib).await) }
+        })
+        .map(move |(id, permit)| {
+            let fs = fs.clone();
+            async move {
+                let permit = permit?;
+                Ok((spawn_blocking(move || fs.read_all(id)).await??, permit))
+            }
+        })
+        .buffered(EXPORT_PREFETCH)
+        .boxed()
+}
+
 /// Export the whole filesystem to a file
-fn export_files(mut fs: SqlFileSystem, format: FileExportFormat, mut path: PathBuf) -> Result<(), AnyError> {
+fn export_files(fs: SqlFileSystem, format: FileExportFormat, mut path: PathBuf) -> Result<(), AnyError> {
     info!("Exporting files to {:?}", &path);
     let tree = fs.sql.get_tree()?;
 
+    // Files are visited in the same order by every walk of the tree
+    let mut files = vec![];
+    FsTree::for_each(tree.clone(), |child, _| {
+        if child.kind == FsTreeKind::File {
+            files.push((child.id, child.size as u64));
+        }
+        Ok(())
+    })?;
+    let mut files = prefetch_files(Arc::new(fs), files, EXPORT_PREFETCH_KIB);
+    let mut next_file = || runtime().block_on(files.next()).unwrap_or_else(|| Err(anyhow!("Missing prefetched file")));
+
     match format {
         FileExportFormat::Directory => {
             fs::create_dir_all(&path)?;
@@ -227,11 +322,23 @@ fn export_files(mut fs: SqlFileSystem, format: FileExportFormat, mut path: PathB
             FsTree::for_each(tree, |child, child_path| {
                 let child_path = path.join(child_path);
 
-                if child.kind == FsTreeKind::Directory {
-                    fs::create_dir_all(&child_path)?;
-                } else {
-                    let data = fs.read_all(child.id)?;
-                    fs::write(&child_path, data).context("Unable to write file")?;
+                match child.kind {
+                    FsTreeKind::Directory => {
+                        fs::create_dir_all(&child_path)?;
+                    }
+                    FsTreeKind::File => {
+                        let (data, _memory) = next_file()?;
+                        fs::write(&child_path, data).context("Unable to write file")?;
+                    }
+                    FsTreeKind::Socket => {
+                        warn!("Skipping socket {:?}, it can only be created by binding it", &child_path);
+                    }
+                    FsTreeKind::Fifo | FsTreeKind::CharDevice | FsTreeKind::BlockDevice => {
+                        // Device nodes usually require root, the export continues without them
+                        if let Err(e) = create_special_file(&child_path, child) {
+                            warn!("Unable to create {:?}: {}", &child_path, e);
+                        }
+                    }
                 }
 
                 Ok(())
@@ -248,19 +355,44 @@ fn export_files(mut fs: SqlFileSystem, format: FileExportFormat, mut path: PathB
 
             FsTree::for_each(tree, |child, child_path| {
                 let mut header = tar::Header::new_gnu();
-                header.set_size(child.size as u64);
-                header.set_mtime(child.updated_at as u64);
+                header.set_mtime(child.updated_at.max(0) as u64);
                 header.set_mode(child.perms as u32);
                 header.set_uid(child.uid as u64);
                 header.set_gid(child.gid as u64);
-                header.set_entry_type(if child.kind == FsTreeKind::Directory { tar::EntryType::Directory } else { tar::EntryType::Regular });
-                header.set_cksum();
-
-                if child.kind == FsTreeKind::Directory {
-                    tar.append_data(&mut header, &child_path, &mut std::io::empty())?;
-                } else {
-                    let data = fs.read_all(child.id)?;
+                header.set_entry_type(match child.kind {
+                    FsTreeKind::Directory => tar::EntryType::Directory,
+                    FsTreeKind::Fifo => tar::EntryType::Fifo,
+                    FsTreeKind::CharDevice => tar::EntryType::Char,
+                    FsTreeKind::BlockDevice => tar::EntryType::Block,
+                    FsTreeKind::File => tar::EntryType::Regular,
+                    FsTreeKind::Socket => {
+                        warn!("Skipping socket {:?}, tar has no entry type for sockets", &child_path);
+                        return Ok(());
+                    }
+                });
+
+                // The ustar header only has seconds, the full timestamps go in a PAX extended header
+                let pax = pax_timestamps(child);
+                let mut pax_header = tar::Header::new_ustar();
+                pax_header.set_entry_type(tar::EntryType::XHeader);
+                pax_header.set_size(pax.len() as u64);
+                pax_header.set_cksum();
+                tar.append_data(&mut pax_header, "././@PaxHeader", pax.as_slice())?;
+
+                if child.kind == FsTreeKind::File {
+                    let (data, _memory) = next_file()?;
+                    header.set_size(data.len() as u64);
+                    header.set_cksum();
                     tar.append_data(&mut header, &child_path, data.as_slice())?;
+                } else {
+                    if matches!(child.kind, FsTreeKind::CharDevice | FsTreeKind::BlockDevice) {
+                        let (major, minor) = device_numbers(child.rdev as u64);
+                        header.set_device_major(major)?;
+                        header.set_device_minor(minor)?;
+                    }
+                    header.set_size(0);
+                    header.set_cksum();
+                    tar.append_data(&mut header, &child_path, &mut std::io::empty())?;
                 }
                 Ok(())
             })?;
@@ -277,8 +409,10 @@ fn export_files(mut fs: SqlFileSystem, format: FileExportFormat, mut path: PathB
             FsTree::for_each(tree, |child, child_path| {
                 if child.kind == FsTreeKind::Directory {
                     zip.add_directory_from_path(&child_path, options)?;
+                } else if child.kind != FsTreeKind::File {
+                    warn!("Skipping special file {:?}, zip can only store files and directories", &child_path);
                 } else {
-                    let data = fs.read_all(child.id)?;
+                    let (data, _memory) = next_file()?;
                     zip.start_file_from_path(child_path, options)?;
                     zip.write_all(&data)?;
                 }
@@ -293,6 +427,82 @@ fn export_files(mut fs: SqlFileSystem, format: FileExportFormat, mut path: PathB
     Ok(())
 }
 
+/// Copy a file without going through the mount point, it only takes a metadata update when the object can be shared
+fn copy(fs: SqlFileSystem, source: &str, destination: &str) -> Result<(), AnyError> {
+    let split = |path: &str| {
+        let path = path.trim_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(anyhow!("Invalid file path: '{}'", path));
+        }
+        Ok((dir.to_string(), name.to_string()))
+    };
+    let find_directory = |fs: &SqlFileSystem, path: &str| {
+        match fs.sql.get_file_by_path(path)? {
+            Some(file) if file.kind == FILE_KIND_DIRECTORY => Ok(file),
+            _ => Err(anyhow!("Directory not found: /{}", path)),
+        }
+    };
+
+    let (source_dir, source_name) = split(source)?;
+    let source_parent = find_directory(&fs, &source_dir)?;
+
+    let (parent, name) = match fs.sql.get_file_by_path(destination.trim_matches('/'))? {
+        Some(file) if file.kind == FILE_KIND_DIRECTORY => (file, source_name.clone()),
+        _ => {
+            let (dir, name) = split(destination)?;
+            (find_directory(&fs, &dir)?, name)
+        }
+    };
+
+    let id = fs.copy_file(source_parent.id, &source_name, parent.id, &name)?;
+    info!("Copied {} to {}/{} (file {})", source, fs.sql.get_file_path(parent.id)?.trim_end_matches('/'), name, id);
+    Ok(())
+}
+
+/// Create a FIFO or device node with the same mode and device number
+fn create_special_file(path: &Path, node: &FsTree) -> Result<(), AnyError> {
+    let file_type = match node.kind {
+        FsTreeKind::Fifo => libc::S_IFIFO,
+        FsTreeKind::CharDevice => libc::S_IFCHR,
+        FsTreeKind::BlockDevice => libc::S_IFBLK,
+        _ => return Err(anyhow!("Not a special file")),
+    };
+    let c_path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes())?;
+    let mode = file_type | (node.perms as libc::mode_t & 0o7777);
+
+    if unsafe { libc::mknod(c_path.as_ptr(), mode, node.rdev as libc::dev_t) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // mknod applies the umask of the process
+    fs::set_permissions(path, fs::Permissions::from_mode(node.perms as u32 & 0o7777))?;
+    Ok(())
+}
+
+/// PAX extended header records with the timestamps of a node in nanosecond precision
+fn pax_timestamps(node: &FsTree) -> Vec<u8> {
+    let timestamps = [
+        ("mtime", join_timestamp(node.updated_at, node.updated_at_nsec)),
+        ("atime", join_timestamp(node.accessed_at, node.accessed_at_nsec)),
+        ("ctime", join_timestamp(node.changed_at, node.changed_at_nsec)),
+    ];
+    let mut records = vec![];
+
+    for (key, value) in timestamps {
+        let sign = if value < 0 { "-" } else { "" };
+        let value = value.unsigned_abs();
+        let record = format!(" {}={}{}.{:09}\n", key, sign, value / NANOS_PER_SECOND as u64, value % NANOS_PER_SECOND as u64);
+
+        // Each record starts with its own length in decimal, including the digits of the length
+        let mut len = record.len() + 1;
+        while len != record.len() + len.to_string().len() {
+            len = record.len() + len.to_string().len();
+        }
+        records.extend_from_slice(format!("{}{}", len, record).as_bytes());
+    }
+    records
+}
+
 /// Print stats about the filesystem
 fn stats(fs: SqlFileSystem) -> Result<(), AnyError> {
     let [total, directories, regular] = fs.sql.get_row(
@@ -362,6 +572,16 @@ fn stats(fs: SqlFileSystem) -> Result<(), AnyError> {
         },
     )?.unwrap();
 
+    // Counters of the last mount, saved when it was unmounted
+    let cache_stats = match fs.sql.get_setting(CACHE_STATS_SETTING)? {
+        Some(value) => serde_json::from_str(&value)?,
+        None => CacheStats::default(),
+    };
+    let retry_stats = match fs.sql.get_setting(RETRY_STATS_SETTING)? {
+        Some(value) => serde_json::from_str(&value)?,
+        None => RetryStats::default(),
+    };
+
     let stats = json!({
         "files": {
             "total": total,
@@ -372,6 +592,8 @@ fn stats(fs: SqlFileSystem) -> Result<(), AnyError> {
             "top_largest_files": top_largest_files,
             "top_used_extensions": top_used_extensions,
         },
+        "cache": cache_stats,
+        "retries": retry_stats,
         "sqlar": {
             "total": sqlar_total,
             "original_size": humanize_bytes_binary(sqlar_size  as usize),
@@ -385,8 +607,175 @@ fn stats(fs: SqlFileSystem) -> Result<(), AnyError> {
     Ok(())
 }
 
-// Verify integrity of the filesystem contents
-fn verify(mut fs: SqlFileSystem) -> Result<(), AnyError> {
+/// Print a report of duplicated file contents and the space saved by deduplication
+fn dedup_report(fs: SqlFileSystem, top: usize) -> Result<(), AnyError> {
+    let report = dedup_summary(&fs, top)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Report of the sets of files with the same contents and the size of each backend
+fn dedup_summary(fs: &SqlFileSystem, top: usize) -> Result<serde_json::Value, AnyError> {
+    let [logical_size, unique_size, stored_size, unique_stored_size] = fs.sql.get_row(
+        "
+        SELECT coalesce(sum(size), 0)        AS logical_size,
+               coalesce(sum(stored_size), 0) AS stored_size,
+               coalesce((SELECT sum(size) FROM (SELECT max(size) AS size FROM files WHERE kind = 0 GROUP BY sha512)), 0) AS unique_size,
+               coalesce((SELECT sum(stored_size) FROM (SELECT max(stored_size) AS stored_size FROM files WHERE kind = 0 GROUP BY sha512)), 0) AS unique_stored_size
+        FROM files
+        WHERE kind = 0",
+        NO_BINDINGS.as_ref(),
+        |row| {
+            Ok([
+                row.read::<i64, _>("logical_size")?,
+                row.read::<i64, _>("unique_size")?,
+                row.read::<i64, _>("stored_size")?,
+                row.read::<i64, _>("unique_stored_size")?,
+            ])
+        },
+    )?.unwrap();
+
+    let duplicates = fs.sql.get_rows(
+        "
+        SELECT d.sha512, d.size, d.copies, d.saved_size, p.path
+        FROM duplicate_contents d JOIN file_paths p ON p.sha512 = d.sha512 AND p.kind = 0
+        ORDER BY d.saved_size DESC, d.sha512, p.path",
+        NO_BINDINGS.as_ref(),
+        |row| {
+            Ok((
+                row.read::<String, _>("sha512")?,
+                row.read::<i64, _>("size")?,
+                row.read::<i64, _>("copies")?,
+                row.read::<i64, _>("saved_size")?,
+                row.read::<String, _>("path")?,
+            ))
+        },
+    )?;
+
+    let mut duplicate_files = 0;
+    let mut saved_size = 0;
+    let mut duplicate_sets = vec![];
+
+    for ((sha512, size, copies, set_saved_size), group) in &duplicates.into_iter().chunk_by(|(sha512, size, copies, saved_size, _)| {
+        (sha512.clone(), *size, *copies, *saved_size)
+    }) {
+        let paths: Vec<String> = group.map(|(_, _, _, _, path)| path).collect();
+        duplicate_files += copies;
+        saved_size += set_saved_size;
+
+        duplicate_sets.push(json!({
+            "sha512": sha512,
+            "size": humanize_bytes_binary(size as usize),
+            "size_bytes": size,
+            "copies": copies,
+            "saved_size": humanize_bytes_binary(set_saved_size as usize),
+            "saved_size_bytes": set_saved_size,
+            "paths": paths,
+        }));
+    }
+
+    // Objects are only shared between files when they are addressed by content, otherwise each file has its own copy.
+    // Stored sizes are the ones of the primary, replicas that compress or encrypt differently are estimated
+    // from the logical sizes.
+    let mut backends = vec![];
+    let primary = fs.config.primary.clone();
+    let storages = std::iter::once(("primary".to_string(), primary.clone()))
+        .chain(fs.config.replicas.iter().enumerate().map(|(index, r)| (format!("replica_{}", index), r.clone())));
+
+    for (name, config) in storages {
+        let same_format = config.compression_level == primary.compression_level
+            && config.encryption_key.is_empty() == primary.encryption_key.is_empty();
+        let physical_size = match (config.is_content_addressed(), same_format) {
+            (true, true) => unique_stored_size,
+            (false, true) => stored_size,
+            (true, false) => unique_size,
+            (false, false) => logical_size,
+        };
+
+        backends.push(json!({
+            "name": name,
+            "storage_backend": config.storage_backend.to_string(),
+            "shared_objects": config.is_content_addressed(),
+            "logical_size": humanize_bytes_binary(logical_size as usize),
+            "logical_size_bytes": logical_size,
+            "physical_size": humanize_bytes_binary(physical_size as usize),
+            "physical_size_bytes": physical_size,
+            "saved_size": humanize_bytes_binary((logical_size - physical_size).max(0) as usize),
+            "saved_size_bytes": (logical_size - physical_size).max(0),
+        }));
+    }
+
+    Ok(json!({
+        "summary": {
+            "duplicate_sets": duplicate_sets.len(),
+            "duplicate_files": duplicate_files,
+            "logical_size": humanize_bytes_binary(logical_size as usize),
+            "logical_size_bytes": logical_size,
+            "unique_size": humanize_bytes_binary(unique_size as usize),
+            "unique_size_bytes": unique_size,
+            "saved_size": humanize_bytes_binary(saved_size as usize),
+            "saved_size_bytes": saved_size,
+        },
+        "backends": backends,
+        "top_duplicate_sets": duplicate_sets.iter().take(top).collect::<Vec<_>>(),
+        "duplicate_sets": duplicate_sets,
+    }))
+}
+
+/// Print the disk usage of each directory
+fn du(fs: SqlFileSystem, path: &str, max_depth: Option<usize>, sort: DuSortOrder, json: bool) -> Result<(), AnyError> {
+    let base = format!("/{}", path.trim_matches('/'));
+    let depth_of = |path: &str| if path == "/" { 0 } else { path.matches('/').count() };
+    let base_depth = depth_of(&base);
+
+    let mut usage: Vec<_> = fs.sql.get_directory_usage(fs.config.primary.is_content_addressed())?
+        .into_iter()
+        .filter(|u| base == "/" || u.path == base || u.path.starts_with(&format!("{}/", base)))
+        .filter(|u| max_depth.is_none_or(|max| depth_of(&u.path) - base_depth <= max))
+        .collect();
+
+    if usage.is_empty() {
+        return Err(anyhow!("Directory not found: {}", base));
+    }
+
+    match sort {
+        DuSortOrder::Path => usage.sort_by(|a, b| a.path.cmp(&b.path)),
+        DuSortOrder::Size => usage.sort_by_key(|u| std::cmp::Reverse(u.size)),
+        DuSortOrder::Stored => usage.sort_by_key(|u| std::cmp::Reverse(u.stored_size)),
+        DuSortOrder::Files => usage.sort_by_key(|u| std::cmp::Reverse(u.files)),
+    }
+
+    if json {
+        let rows: Vec<_> = usage.iter().map(|u| json!({
+            "id": u.id,
+            "path": u.path,
+            "size": humanize_bytes_binary(u.size as usize),
+            "size_bytes": u.size,
+            "stored_size": humanize_bytes_binary(u.stored_size as usize),
+            "stored_size_bytes": u.stored_size,
+            "files": u.files,
+            "directories": u.directories,
+        })).collect();
+
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+    } else {
+        let headers = ["size", "stored", "files", "dirs", "path"].map(String::from);
+        let rows: Vec<Vec<String>> = usage.iter().map(|u| vec![
+            humanize_bytes_binary(u.size as usize),
+            humanize_bytes_binary(u.stored_size as usize),
+            u.files.to_string(),
+            u.directories.to_string(),
+            u.path.clone(),
+        ]).collect();
+
+        print!("{}", format_table(&headers, &rows));
+    }
+
+    Ok(())
+}
+
+// Verify integrity of the filesystem contents, returns the number of files with errors
+fn verify(fs: SqlFileSystem) -> Result<u64, AnyError> {
     let total = fs.sql.get_row(
         "
         SELECT count(*)                      AS total
@@ -397,14 +786,14 @@ fn verify(mut fs: SqlFileSystem) -> Result<(), AnyError> {
     )?.unwrap();
 
     if total > 0 {
-        warn!("Found {} orphan files!", total);
+        warn!("Found {} orphan files! Run the gc command to remove them", total);
     } else {
         info!("No orphan files found");
     }
 
     let tree = fs.sql.get_tree()?;
 
-    let mut verify_file = |child: &FsTree| -> Result<(), AnyError> {
+    let verify_file = |child: &FsTree| -> Result<(), AnyError> {
         let data = fs.read_all(child.id).context("Unable to read file")?;
 
         if data.len() != child.size as usize {
@@ -428,7 +817,7 @@ fn verify(mut fs: SqlFileSystem) -> Result<(), AnyError> {
     let mut errors = 0;
 
     FsTree::for_each(tree, |child, child_path| {
-        if child.kind == FsTreeKind::Directory {
+        if child.kind != FsTreeKind::File {
             return Ok(());
         }
         if let Err(e) = verify_file(child) {
@@ -445,5 +834,477 @@ fn verify(mut fs: SqlFileSystem) -> Result<(), AnyError> {
         info!("All {} files verified, no errors found", count);
     }
 
+    Ok(errors)
+}
+
+/// Lock a file next to the database, shared by the commands that use the filesystem and exclusive for the garbage
+/// collector, which would remove the files open but unlinked and the objects being written by a mount
+fn lock_database(config: &Config, exclusive: bool) -> Result<File, AnyError> {
+    let path = format!("{}.lock", config.database_file);
+    // An existing lock file can be locked without write access, for databases in read-only directories
+    let file = File::options().create(true).append(true).open(&path)
+        .or_else(|_| File::open(&path))
+        .with_context(|| format!("Unable to open lock file {}", path))?;
+
+    let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::WouldBlock {
+            return Err(anyhow!("Unable to lock {}: {}", path, error));
+        }
+        return Err(if exclusive {
+            anyhow!("The filesystem is mounted or in use by another command, unmount it before running gc")
+        } else {
+            anyhow!("Garbage collection is running, wait until it finishes")
+        });
+    }
+    Ok(file)
+}
+
+/// Remove unreachable files from the index and objects not referenced by any file from the backends. Files and objects
+/// changed after `older_than` (unix timestamp) are kept, they may belong to a write or unlink still in progress.
+fn gc(sql: Arc<MetadataDB>, config: Arc<Config>, storages: Vec<Box<dyn ObjectStorage>>, dry_run: bool, older_than: i64) -> Result<(), AnyError> {
+    let (orphan_files, recent_files): (Vec<_>, Vec<_>) = sql.get_orphan_files()?.into_iter()
+        .partition(|file| file.changed_at < older_than * NANOS_PER_SECOND);
+    let recent_ids: HashSet<i64> = recent_files.iter().map(|file| file.id).collect();
+    let (orphan_entries, recent_entries): (Vec<_>, Vec<_>) = sql.get_orphan_directory_entries()?.into_iter()
+        .partition(|entry| !recent_ids.contains(&entry.directory_file_id) && !recent_ids.contains(&entry.entry_file_id));
+
+    for file in &orphan_files {
+        info!("Orphan file ({}) {:?}, {}", file.id, file.name, humanize_bytes_binary(file.size as usize));
+    }
+    for entry in &orphan_entries {
+        info!("Orphan directory entry ({}) {:?} in directory {}", entry.id, entry.name, entry.directory_file_id);
+    }
+
+    if !dry_run {
+        sql.transaction(|| {
+            for entry in &orphan_entries {
+                sql.remove_directory_entry(entry.id)?;
+            }
+            for file in &orphan_files {
+                sql.remove_file(file.id)?;
+            }
+            Ok::<(), AnyError>(())
+        })?;
+    }
+
+    // Keys are computed from the files still reachable, objects of orphan files are collected in the same pass.
+    // Recent orphans are usually files unlinked while open, their objects are kept apart until they are closed.
+    let mut files = sql.get_files_with_content()?;
+    files.extend(recent_files.iter()
+        .filter(|file| file.kind == FILE_KIND_REGULAR && !file.sha512.is_empty())
+        .map(|file| (file.clone(), unlinked_file_path(file.id))));
+    let names = std::iter::once("primary".to_string())
+        .chain((0..config.replicas.len()).map(|index| format!("replica_{}", index)));
+    let mut backends = vec![];
+
+    for (name, storage) in names.zip(storages) {
+        let mut referenced = HashSet::new();
+
+        for (file, full_path) in &files {
+            let key = storage.key(&ObjInfo::new(file, full_path))
+                .with_context(|| format!("Unable to compute the object key of file ({}) {:?}", file.id, full_path))?;
+            referenced.insert(key);
+        }
+
+        let objects = storage.list().with_context(|| format!("Unable to list the objects of {}", name))?;
+        let total = objects.len();
+        let (recent, objects): (Vec<_>, Vec<_>) = objects.into_iter()
+            .partition(|obj| obj.modified_at.is_some_and(|time| time >= older_than));
+        let unreferenced: Vec<_> = objects.into_iter().filter(|obj| !referenced.contains(&obj.key)).collect();
+        let reclaimed_size: u64 = unreferenced.iter().map(|obj| obj.size).sum();
+
+        for obj in &unreferenced {
+            info!("Unreferenced object in {}: {:?}, {}", name, obj.key, humanize_bytes_binary(obj.size as usize));
+            if !dry_run {
+                storage.remove_key(&obj.key)?;
+            }
+        }
+
+        backends.push(json!({
+            "name": name,
+            "objects": total,
+            "unreferenced_objects": unreferenced.len(),
+            "recent_objects": recent.len(),
+            "reclaimed_size": humanize_bytes_binary(reclaimed_size as usize),
+            "reclaimed_size_bytes": reclaimed_size,
+        }));
+    }
+
+    let orphan_size: i64 = orphan_files.iter().map(|f| f.size).sum();
+    let report = json!({
+        "dry_run": dry_run,
+        "orphan_files": orphan_files.len(),
+        "orphan_files_size": humanize_bytes_binary(orphan_size as usize),
+        "orphan_files_size_bytes": orphan_size,
+        "orphan_directory_entries": orphan_entries.len(),
+        "recent_orphan_files": recent_files.len(),
+        "recent_orphan_directory_entries": recent_entries.len(),
+        "backends": backends,
+    });
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Search files in the metadata index
+fn find(fs: SqlFileSystem, args: FindArgs) -> Result<(), AnyError> {
+    let rows = find_files(&fs, &args)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+    } else {
+        for row in &rows {
+            println!("{}", row["path"].as_str().unwrap_or_default());
+        }
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Files matching the filters, with their full path, sorted by path
+fn find_files(fs: &SqlFileSystem, args: &FindArgs) -> Result<Vec<serde_json::Value>, AnyError> {
+    let regex = match &args.regex {
+        Some(pattern) => Some(Regex::new(pattern).context("Invalid regex")?),
+        None => None,
+    };
+
+    let mut conditions: Vec<&str> = vec!["f.id <> 1"];
+    let mut bindings: Vec<(&str, sqlite::Value)> = vec![];
+
+    if let Some(name) = &args.name {
+        conditions.push("f.name GLOB :name");
+        bindings.push((":name", name.as_str().into()));
+    }
+    if let Some(kind) = args.kind {
+        conditions.push("f.kind = :kind");
+        bindings.push((":kind", match kind {
+            FindKind::File => FILE_KIND_REGULAR,
+            FindKind::Directory => FILE_KIND_DIRECTORY,
+            FindKind::Fifo => FILE_KIND_FIFO,
+            FindKind::Socket => FILE_KIND_SOCKET,
+            FindKind::CharDevice => FILE_KIND_CHAR_DEVICE,
+            FindKind::BlockDevice => FILE_KIND_BLOCK_DEVICE,
+        }.into()));
+    }
+    if let Some(min_size) = args.min_size {
+        conditions.push("f.size >= :min_size");
+        bindings.push((":min_size", min_size.into()));
+    }
+    if let Some(max_size) = args.max_size {
+        conditions.push("f.size <= :max_size");
+        bindings.push((":max_size", max_size.into()));
+    }
+    if let Some(time) = args.mtime_after {
+        conditions.push("f.updated_at >= :updated_after");
+        bindings.push((":updated_after", time.into()));
+    }
+    if let Some(time) = args.mtime_before {
+        conditions.push("f.updated_at <= :updated_before");
+        bindings.push((":updated_before", time.into()));
+    }
+    if let Some(time) = args.ctime_after {
+        conditions.push("f.changed_at >= :changed_after");
+        bindings.push((":changed_after", time.into()));
+    }
+    if let Some(time) = args.ctime_before {
+        conditions.push("f.changed_at <= :changed_before");
+        bindings.push((":changed_before", time.into()));
+    }
+    if let Some(time) = args.atime_after {
+        conditions.push("f.accessed_at >= :accessed_after");
+        bindings.push((":accessed_after", time.into()));
+    }
+    if let Some(time) = args.atime_before {
+        conditions.push("f.accessed_at <= :accessed_before");
+        bindings.push((":accessed_before", time.into()));
+    }
+    if let Some(uid) = args.uid {
+        conditions.push("f.uid = :uid");
+        bindings.push((":uid", uid.into()));
+    }
+    if let Some(gid) = args.gid {
+        conditions.push("f.gid = :gid");
+        bindings.push((":gid", gid.into()));
+    }
+    if let Some(prefix) = &args.sha512 {
+        // Range over the hex alphabet, so the files_sha512 index can be used
+        conditions.push("f.sha512 >= :sha512_start AND f.sha512 < :sha512_end");
+        bindings.push((":sha512_start", prefix.to_ascii_lowercase().into()));
+        bindings.push((":sha512_end", format!("{}g", prefix.to_ascii_lowercase()).into()));
+    }
+    if args.duplicates {
+        conditions.push("f.sha512 IN (\
+            SELECT sha512 FROM files \
+            WHERE kind = 0 AND sha512 <> '' \
+            GROUP BY sha512 HAVING count(*) > 1)");
+    }
+
+    // Build the full path of each match walking up the directory entries
+    let query = format!("
+        WITH RECURSIVE
+            matches AS (SELECT f.* FROM files f WHERE {}),
+            parents(file_id, directory_file_id, path) AS (
+                SELECT m.id, e.directory_file_id, '/' || e.name
+                FROM matches m JOIN directory_entries e ON e.entry_file_id = m.id
+                WHERE e.name <> '.' AND e.name <> '..'
+                UNION ALL
+                SELECT p.file_id, e.directory_file_id, '/' || e.name || p.path
+                FROM parents p JOIN directory_entries e ON e.entry_file_id = p.directory_file_id
+                WHERE p.directory_file_id <> 1 AND e.name <> '.' AND e.name <> '..'
+            )
+        SELECT m.*, p.path
+        FROM matches m JOIN parents p ON p.file_id = m.id AND p.directory_file_id = 1
+        ORDER BY p.path", conditions.join(" AND "));
+
+    let rows = fs.sql.get_rows(&query, &bindings[..], |row| {
+        Ok(json!({
+            "id": row.read::<i64, _>("id")?,
+            "path": row.read::<String, _>("path")?,
+            "name": row.read::<String, _>("name")?,
+            "kind": kind_name(row.read::<i64, _>("kind")?),
+            "uid": row.read::<i64, _>("uid")?,
+            "gid": row.read::<i64, _>("gid")?,
+            "perms": row.read::<i64, _>("perms")?,
+            "size": row.read::<i64, _>("size")?,
+            "sha512": row.read::<String, _>("sha512")?,
+            "accessed_at": row.read::<i64, _>("accessed_at")?,
+            "created_at": row.read::<i64, _>("created_at")?,
+            "updated_at": row.read::<i64, _>("updated_at")?,
+            "changed_at": row.read::<i64, _>("changed_at")?,
+        }))
+    })?;
+
+    Ok(rows.into_iter()
+        .filter(|row| regex.as_ref().is_none_or(|r| r.is_match(row["name"].as_str().unwrap_or_default())))
+        .collect())
+}
+
+/// Run a read-only SQL query against the metadata database
+fn sql_query(fs: SqlFileSystem, query: &str, format: SqlOutputFormat) -> Result<(), AnyError> {
+    // Use a separate read-only connection, so the query is not able to modify the index
+    let db = MetadataDB::open_read_only(&fs.config.database_file)?;
+    let (columns, rows) = db.get_values(query).context("Unable to run query")?;
+
+    match format {
+        SqlOutputFormat::Table => {
+            let rows: Vec<Vec<String>> = rows.iter()
+                .map(|row| row.iter().map(|value| match value {
+                    sqlite::Value::Null => "NULL".to_string(),
+                    sqlite::Value::Binary(bytes) => format!("<{}>", humanize_bytes_binary(bytes.len())),
+                    other => sql_value_to_string(other),
+                }).collect())
+                .collect();
+
+            print!("{}", format_table(&columns, &rows));
+        }
+        SqlOutputFormat::Csv => {
+            let header: Vec<String> = columns.iter().map(|c| csv_escape(c)).collect();
+            println!("{}", header.join(","));
+
+            for row in &rows {
+                let fields: Vec<String> = row.iter().map(|v| csv_escape(&sql_value_to_string(v))).collect();
+                println!("{}", fields.join(","));
+            }
+        }
+        SqlOutputFormat::Json => {
+            let rows: Vec<serde_json::Value> = rows.into_iter()
+                .map(|row| {
+                    let object = columns.iter().cloned().zip(row).map(|(column, value)| {
+                        let value = match value {
+                            sqlite::Value::Null => serde_json::Value::Null,
+                            sqlite::Value::Integer(i) => json!(i),
+                            sqlite::Value::Float(f) => json!(f),
+                            sqlite::Value::String(s) => json!(s),
+                            sqlite::Value::Binary(bytes) => json!(hex::encode(bytes)),
+                        };
+                        (column, value)
+                    });
+                    serde_json::Value::Object(object.collect())
+                })
+                .collect();
+
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn sql_value_to_string(value: &sqlite::Value) -> String {
+    match value {
+        sqlite::Value::Null => String::new(),
+        sqlite::Value::Integer(i) => i.to_string(),
+        sqlite::Value::Float(f) => f.to_string(),
+        sqlite::Value::String(s) => s.clone(),
+        sqlite::Value::Binary(bytes) => hex::encode(bytes),
+    }
+}
+
+#[test]
+fn test_gc_keeps_open_files() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+    use crate::utils::current_timestamp;
+
+    let fs = sql_fs::test_file_system("gc", "");
+    let unlinked = fs.mknod(ROOT_DIRECTORY_ID, "open", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(unlinked.id, b"still open").unwrap();
+    let stale = fs.mknod(ROOT_DIRECTORY_ID, "stale", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(stale.id, b"left by a crash").unwrap();
+
+    // A mount holds the shared lock, the garbage collector must wait for it to end
+    let mount_lock = lock_database(&fs.config, false).unwrap();
+    fs.open(unlinked.id, true).unwrap();
+    fs.unlink(ROOT_DIRECTORY_ID, "open").unwrap();
+    assert!(lock_database(&fs.config, true).is_err());
+    drop(mount_lock);
+    let gc_lock = lock_database(&fs.config, true).unwrap();
+    assert!(lock_database(&fs.config, false).is_err());
+
+    // A lock file that can't be created is an error, not a panic
+    let missing = Config { database_file: "/nonexistent/innerfs/index.db".to_string(), ..(*fs.config).clone() };
+    assert!(lock_database(&missing, false).is_err());
+
+    // The stale file was unlinked a day ago, the open one just now
+    fs.open(stale.id, true).unwrap();
+    fs.unlink(ROOT_DIRECTORY_ID, "stale").unwrap();
+    let mut row = fs.get_file_or_err(stale.id).unwrap();
+    row.changed_at -= 24 * 60 * 60 * NANOS_PER_SECOND;
+    fs.sql.update_file(&row).unwrap();
+
+    let storages = vec![create_object_storage(fs.config.primary.clone(), fs.sql.clone())];
+    let older_than = current_timestamp() / NANOS_PER_SECOND - 60 * 60;
+    gc(fs.sql.clone(), fs.config.clone(), storages, false, older_than).unwrap();
+    drop(gc_lock);
+
+    assert!(fs.sql.get_file(stale.id).unwrap().is_none());
+    assert_eq!(fs.read(unlinked.id, 0, 64).unwrap(), b"still open");
+    fs.release(unlinked.id).unwrap();
+    assert!(fs.sql.get_file(unlinked.id).unwrap().is_none());
+}
+
+#[test]
+fn test_verify_detects_chaos() {
+    let replica_path = env::temp_dir().join(format!("innerfs-test-chaos-{}", std::process::id())).join("replica");
+    let fs = sql_fs::test_file_system("chaos", &format!(
+        "  chaos:\n    seed: 5\n    bit_flip_rate: 1.0\nreplicas:\n  - storage_backend: filesystem\n    blob_storage: {}\n",
+        replica_path.display(),
+    ));
+    let (sql, config) = (fs.sql.clone(), fs.config.clone());
+
+    // Same stack as the mount, the primary damages every object it reads
+    let obj_storage = Box::new(ReplicatedObjectStorage {
+        primary: BlockingObjectStorage::new(Arc::from(create_object_storage(config.primary.clone(), sql.clone()))),
+        replicas: config.replicas.iter().map(|replica| create_async_object_storage(replica.clone(), sql.clone())).collect(),
+    });
+    let storage = Box::new(StorageInterface::new(obj_storage, 0, PathBuf::from(&config.cache_directory).join("spill")));
+    let fs = SqlFileSystem::new(sql.clone(), config.clone(), storage);
+
+    for name in ["a", "b", "c"] {
+        let file = fs.mknod(metadata_db::ROOT_DIRECTORY_ID, name, 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_all(file.id, format!("contents of {}", name).as_bytes()).unwrap();
+    }
+    assert_eq!(verify(fs).unwrap(), 3);
+
+    // The replica keeps the contents that were written
+    let replica = create_object_storage(config.replicas[0].clone(), sql.clone());
+    for (file, full_path) in sql.get_files_with_content().unwrap() {
+        let data = replica.get(&ObjInfo::new(&file, &full_path)).unwrap();
+        assert_eq!(hex::encode(hmac_sha512::Hash::hash(&data)), file.sha512);
+    }
+}
+
+#[test]
+fn test_find_filters() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+
+    let fs = sql_fs::test_file_system("find", "");
+    let photos = fs.mkdir(ROOT_DIRECTORY_ID, "photos", 0, 0, 0o755).unwrap();
+    for (parent, name, content) in [(photos.id, "IMG_1.jpg", "same"), (photos.id, "holiday.jpg", "same"), (ROOT_DIRECTORY_ID, "notes.txt", "hello world!")] {
+        let file = fs.mknod(parent, name, 1000, 1000, libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_all(file.id, content.as_bytes()).unwrap();
+    }
+    fs.mknod(ROOT_DIRECTORY_ID, "pipe", 0, 0, libc::S_IFIFO | 0o644, 0).unwrap();
+
+    let find = |args: &[&str]| {
+        let Some(Commands::Find(args)) = Cli::parse_from(["innerfs", "find"].iter().chain(args)).command else {
+            panic!("Not a find command");
+        };
+        find_files(&fs, &args).unwrap().iter().map(|row| row["path"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(find(&[]), vec!["/notes.txt", "/photos", "/photos/IMG_1.jpg", "/photos/holiday.jpg", "/pipe"]);
+    assert_eq!(find(&["--name", "*.jpg"]), vec!["/photos/IMG_1.jpg", "/photos/holiday.jpg"]);
+    assert_eq!(find(&["--regex", r"^IMG_[0-9]+\.jpg$"]), vec!["/photos/IMG_1.jpg"]);
+    assert_eq!(find(&["--kind", "directory"]), vec!["/photos"]);
+    assert_eq!(find(&["--kind", "fifo"]), vec!["/pipe"]);
+    assert_eq!(find(&["--min-size", "5", "--kind", "file"]), vec!["/notes.txt"]);
+    assert_eq!(find(&["--max-size", "4", "--uid", "1000"]), vec!["/photos/IMG_1.jpg", "/photos/holiday.jpg"]);
+    assert_eq!(find(&["--duplicates"]), vec!["/photos/IMG_1.jpg", "/photos/holiday.jpg"]);
+    assert_eq!(find(&["--mtime-after", "1d"]).len(), 5);
+    assert!(find(&["--mtime-before", "1d"]).is_empty());
+
+    let sha512 = hex::encode(hmac_sha512::Hash::hash(b"hello world!"));
+    assert_eq!(find(&["--sha512", &sha512[..6].to_ascii_uppercase()]), vec!["/notes.txt"]);
+}
+
+#[test]
+fn test_dedup_report() {
+    let replica_path = env::temp_dir().join(format!("innerfs-test-dedup-{}", std::process::id())).join("replica");
+    let fs = sql_fs::test_file_system("dedup", &format!(
+        "  use_hash_as_filename: true\nreplicas:\n  - storage_backend: filesystem\n    blob_storage: {}\n",
+        replica_path.display(),
+    ));
+    sql_fs::test_fixture_tree(&fs);
+
+    let report = dedup_summary(&fs, 10).unwrap();
+    let summary = &report["summary"];
+    assert_eq!(summary["duplicate_sets"], 1);
+    assert_eq!(summary["duplicate_files"], 2);
+    // The replaced contents of the notes are not counted
+    assert_eq!(summary["logical_size_bytes"], 12 + 12 + 6);
+    assert_eq!(summary["unique_size_bytes"], 12 + 6);
+    assert_eq!(summary["saved_size_bytes"], 12);
+
+    let set = &report["duplicate_sets"][0];
+    assert_eq!(set["sha512"], hex::encode(hmac_sha512::Hash::hash(b"same content")));
+    assert_eq!(set["copies"], 2);
+    assert_eq!(set["paths"], json!(["/photos/2024/b.jpg", "/photos/a.jpg"]));
+    assert_eq!(report["top_duplicate_sets"], report["duplicate_sets"]);
+
+    // The primary stores shared contents once, the replica names objects by path and keeps a copy per file
+    let backends = report["backends"].as_array().unwrap();
+    assert_eq!((&backends[0]["shared_objects"], &backends[0]["physical_size_bytes"]), (&json!(true), &json!(18)));
+    assert_eq!((&backends[1]["shared_objects"], &backends[1]["physical_size_bytes"]), (&json!(false), &json!(30)));
+}
+
+#[test]
+fn test_prefetch_bounded_by_memory() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+    use std::time::Duration;
+
+    let fs = sql_fs::test_file_system("prefetch", "");
+    let mut files = vec![];
+    for (name, size) in [("a", 1500), ("b", 1500), ("c", 10)] {
+        let file = fs.mknod(ROOT_DIRECTORY_ID, name, 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_all(file.id, &vec![name.as_bytes()[0]; size]).unwrap();
+        files.push((file.id, size as u64));
+    }
+
+    // Room for a single file of 2 KiB, the second one is read after the first is dropped
+    let mut prefetched = prefetch_files(Arc::new(fs), files, 2);
+    let next = |prefetched: &mut BoxStream<_>| runtime().block_on(async {
+        tokio::time::timeout(Duration::from_millis(200), prefetched.next()).await
+    });
+    let (a, memory) = next(&mut prefetched).unwrap().unwrap().unwrap();
+    assert_eq!(a, vec![b'a'; 1500]);
+    assert!(next(&mut prefetched).is_err());
+
+    drop(memory);
+    let (b, memory) = next(&mut prefetched).unwrap().unwrap().unwrap();
+    assert_eq!(b, vec![b'b'; 1500]);
+    drop(memory);
+    assert_eq!(next(&mut prefetched).unwrap().unwrap().unwrap().0, b"cccccccccc");
+    assert!(next(&mut prefetched).unwrap().is_none());
+}
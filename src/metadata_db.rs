@@ -1,20 +1,67 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use anyhow::anyhow;
 use log::info;
-use sqlite::{Bindable, State, Statement};
+use sqlite::{Bindable, Connection, OpenFlags, State, Statement, Value};
 use crate::{AnyError, VERSION};
+use crate::utils::{join_timestamp, split_timestamp};
 use crate::fs_tree::{FsTree, FsTreeRef};
 
+/// Pool of connections to the metadata database, so requests on different threads can run at the same time.
+/// While a thread has a transaction open all its queries use the same connection.
 pub struct MetadataDB {
-    pub connection: sqlite::Connection,
+    id: usize,
+    database_file: String,
+    flags: OpenFlags,
+    pool: Mutex<Vec<Connection>>,
+}
+
+// Milliseconds a connection waits for the write lock held by another one
+const BUSY_TIMEOUT: usize = 30_000;
+
+static NEXT_DATABASE_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Connections pinned to the current thread, by database id
+    static PINNED_CONNECTIONS: RefCell<HashMap<usize, Rc<Connection>>> = RefCell::new(HashMap::new());
+    // Databases with a transaction open in the current thread
+    static OPEN_TRANSACTIONS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+/// Connection taken from the pool, given back when dropped
+pub enum PooledConnection<'a> {
+    Pooled(&'a MetadataDB, Option<Connection>),
+    Pinned(Rc<Connection>),
 }
 
 pub const ROOT_DIRECTORY_ID: i64 = 1;
 pub const FILE_KIND_REGULAR: i64 = 0;
 pub const FILE_KIND_DIRECTORY: i64 = 1;
+pub const FILE_KIND_FIFO: i64 = 2;
+pub const FILE_KIND_SOCKET: i64 = 3;
+pub const FILE_KIND_CHAR_DEVICE: i64 = 4;
+pub const FILE_KIND_BLOCK_DEVICE: i64 = 5;
+
+/// Name of a file kind, as shown in the reports
+pub fn kind_name(kind: i64) -> &'static str {
+    match kind {
+        FILE_KIND_DIRECTORY => "directory",
+        FILE_KIND_FIFO => "fifo",
+        FILE_KIND_SOCKET => "socket",
+        FILE_KIND_CHAR_DEVICE => "char-device",
+        FILE_KIND_BLOCK_DEVICE => "block-device",
+        _ => "file",
+    }
+}
+
+/// Path of a file that was unlinked while open, its object is kept apart until the last handle is released
+pub fn unlinked_file_path(id: i64) -> String {
+    format!("/.innerfs-unlinked/{}", id)
+}
 pub const NO_BINDINGS: [i64; 0] = [];
 
 #[derive(Debug, Clone)]
@@ -26,13 +73,18 @@ pub struct FileRow {
     pub uid: i64,
     pub gid: i64,
     pub perms: i64,
+    pub rdev: i64,
     pub size: i64,
+    pub stored_size: i64,
+    pub allocated_size: i64,
     pub sha512: String,
     pub encryption_key: String,
     pub compression: String,
+    /// Timestamps in nanoseconds, the database stores the seconds and the nanoseconds in separate columns
     pub accessed_at: i64,
     pub created_at: i64,
     pub updated_at: i64,
+    pub changed_at: i64,
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +97,17 @@ pub struct DirectoryEntry {
     pub kind: i64,
 }
 
+/// Recursive usage of a directory, including all nested files and directories
+#[derive(Debug, Clone)]
+pub struct DirectoryUsage {
+    pub id: i64,
+    pub path: String,
+    pub size: i64,
+    pub stored_size: i64,
+    pub files: i64,
+    pub directories: i64,
+}
+
 #[derive(Debug, Clone)]
 pub enum FileChangeKind {
     Created,
@@ -56,19 +119,82 @@ pub enum FileChangeKind {
 #[allow(dead_code)]
 impl MetadataDB {
     pub fn open(database_file: &str) -> MetadataDB {
-        let connection = sqlite::open(database_file).expect("Unable to open database");
+        let flags = OpenFlags::new().with_create().with_read_write();
+        Self::open_with_flags(database_file, flags).expect("Unable to open database")
+    }
+
+    pub fn open_read_only(database_file: &str) -> Result<MetadataDB, AnyError> {
+        Self::open_with_flags(database_file, OpenFlags::new().with_read_only())
+    }
+
+    fn open_with_flags(database_file: &str, flags: OpenFlags) -> Result<MetadataDB, AnyError> {
+        let db = MetadataDB {
+            id: NEXT_DATABASE_ID.fetch_add(1, Ordering::Relaxed),
+            database_file: database_file.to_string(),
+            flags,
+            pool: Mutex::new(vec![]),
+        };
 
-        MetadataDB { connection }
+        // Fail early if the database can not be opened
+        let connection = db.new_connection()?;
+        db.pool.lock().unwrap().push(connection);
+        Ok(db)
+    }
+
+    fn new_connection(&self) -> Result<Connection, AnyError> {
+        let mut connection = Connection::open_with_flags(&self.database_file, self.flags)?;
+        connection.set_busy_timeout(BUSY_TIMEOUT)?;
+        // Foreign keys are enforced per connection
+        connection.execute("PRAGMA foreign_keys=ON")?;
+        Ok(connection)
+    }
+
+    /// Connection pinned to the current thread, or an idle one from the pool
+    pub fn connection(&self) -> Result<PooledConnection<'_>, AnyError> {
+        if let Some(connection) = PINNED_CONNECTIONS.with(|pinned| pinned.borrow().get(&self.id).cloned()) {
+            return Ok(PooledConnection::Pinned(connection));
+        }
+
+        let idle = self.pool.lock().unwrap().pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => self.new_connection()?,
+        };
+        Ok(PooledConnection::Pooled(self, Some(connection)))
+    }
+
+    /// Run all the queries of the current thread in the same connection
+    pub fn pinned<R, E>(&self, func: impl FnOnce() -> Result<R, E>) -> Result<R, E>
+    where
+        E: From<AnyError>,
+    {
+        if PINNED_CONNECTIONS.with(|pinned| pinned.borrow().contains_key(&self.id)) {
+            return func();
+        }
+
+        // Taken out of the guard, it goes back to the pool when unpinned
+        let connection = match &mut self.connection()? {
+            PooledConnection::Pooled(_, connection) => connection.take(),
+            PooledConnection::Pinned(_) => None,
+        };
+        if let Some(connection) = connection {
+            PINNED_CONNECTIONS.with(|pinned| pinned.borrow_mut().insert(self.id, Rc::new(connection)));
+        }
+
+        let _unpin = UnpinConnection(self);
+        func()
     }
 
     pub fn run_migrations(&self) -> Result<(), AnyError> {
-        self.connection.execute(include_str!("./sql/init.sql"))?;
-        self.connection.execute(include_str!("./sql/migrations.sql"))?;
-        self.connection.execute(include_str!("./sql/persistent_settings.sql"))?;
-        self.connection.execute(include_str!("./sql/files.sql"))?;
-        self.connection.execute(include_str!("./sql/directory_entries.sql"))?;
-        self.connection.execute(include_str!("./sql/file_changes.sql"))?;
-        self.connection.execute(include_str!("./sql/sqlar.sql"))?;
+        self.connection()?.execute(include_str!("./sql/init.sql"))?;
+        self.connection()?.execute(include_str!("./sql/migrations.sql"))?;
+        self.connection()?.execute(include_str!("./sql/persistent_settings.sql"))?;
+        self.connection()?.execute(include_str!("./sql/files.sql"))?;
+        self.connection()?.execute(include_str!("./sql/directory_entries.sql"))?;
+        self.connection()?.execute(include_str!("./sql/file_changes.sql"))?;
+        self.connection()?.execute(include_str!("./sql/sqlar.sql"))?;
+        self.connection()?.execute(include_str!("./sql/indexes.sql"))?;
+        self.connection()?.execute(include_str!("./sql/views.sql"))?;
 
         // Schema version
         let version = self.get_row(
@@ -84,7 +210,7 @@ impl MetadataDB {
             None => {
                 // Initial setup from empty database
                 info!("Initializing database for first time");
-                self.connection.execute(include_str!("./sql/create_root_file.sql"))?;
+                self.connection()?.execute(include_str!("./sql/create_root_file.sql"))?;
                 self.execute1("INSERT INTO migrations (version, created_at) VALUES (:version, unixepoch('now'))", (":version", VERSION))?;
                 return Ok(());
             }
@@ -93,14 +219,32 @@ impl MetadataDB {
         if &version == "1.0.1" {
             info!("Running migration from version: '1.0.1' to '1.0.2'");
             // New version column, or ignore error if it already exists
-            let _ = self.connection.execute("ALTER TABLE files ADD COLUMN version INTEGER NOT NULL DEFAULT 1");
-            let _ = self.connection.execute("ALTER TABLE files ADD COLUMN compression TEXT NOT NULL DEFAULT ''");
-            let _ = self.connection.execute("ALTER TABLE file_changes RENAME COLUMN file_sha512 file_hash TEXT NOT NULL");
-            let _ = self.connection.execute("ALTER TABLE directory_entry RENAME TO directory_entries");
+            let _ = self.connection()?.execute("ALTER TABLE files ADD COLUMN version INTEGER NOT NULL DEFAULT 1");
+            let _ = self.connection()?.execute("ALTER TABLE files ADD COLUMN compression TEXT NOT NULL DEFAULT ''");
+            let _ = self.connection()?.execute("ALTER TABLE file_changes RENAME COLUMN file_sha512 file_hash TEXT NOT NULL");
+            let _ = self.connection()?.execute("ALTER TABLE directory_entry RENAME TO directory_entries");
             version = "1.0.2".to_string();
             self.execute1("INSERT INTO migrations (version, created_at) VALUES (:version, unixepoch('now'))", (":version", VERSION))?;
         }
 
+        if &version == "1.0.2" {
+            info!("Running migration from version: '1.0.2' to '1.1.0'");
+            // Size of the stored objects is unknown for existing files, the uncompressed size is the best estimate
+            let _ = self.connection()?.execute("ALTER TABLE files ADD COLUMN stored_size INTEGER NOT NULL DEFAULT 0");
+            self.execute0("UPDATE files SET stored_size = size")?;
+            let _ = self.connection()?.execute("ALTER TABLE files ADD COLUMN rdev INTEGER NOT NULL DEFAULT 0");
+            // Existing objects have no holes
+            let _ = self.connection()?.execute("ALTER TABLE files ADD COLUMN allocated_size INTEGER NOT NULL DEFAULT 0");
+            self.execute0("UPDATE files SET allocated_size = size")?;
+            // Timestamps had a precision of seconds, and the last metadata change was not tracked
+            for column in ["accessed_at_nsec", "created_at_nsec", "updated_at_nsec", "changed_at", "changed_at_nsec"] {
+                let _ = self.connection()?.execute(format!("ALTER TABLE files ADD COLUMN {} INTEGER NOT NULL DEFAULT 0", column));
+            }
+            self.execute0("UPDATE files SET changed_at = updated_at")?;
+            version = "1.1.0".to_string();
+            self.execute1("INSERT INTO migrations (version, created_at) VALUES (:version, unixepoch('now'))", (":version", VERSION))?;
+        }
+
         info!("Database is up to date at version: {}", version);
         Ok(())
     }
@@ -120,25 +264,21 @@ impl MetadataDB {
     }
 
     pub fn add_file(&self, file: &FileRow) -> Result<i64, AnyError> {
-        self.execute13(
-            "INSERT INTO files (version, kind, name, uid, gid, perms, size, sha512, encryption_key, compression, accessed_at, created_at, updated_at) \
-            VALUES (:version, :kind, :name, :uid, :gid, :perms, :size, :sha512, :encryption_key, :compression, :accessed_at, :created_at, :updated_at)",
-            (":version", 1),
-            (":kind", file.kind),
-            (":name", file.name.as_str()),
-            (":uid", file.uid),
-            (":gid", file.gid),
-            (":perms", file.perms),
-            (":size", file.size),
-            (":sha512", file.sha512.as_str()),
-            (":encryption_key", file.encryption_key.as_str()),
-            (":compression", file.compression.as_str()),
-            (":accessed_at", file.accessed_at),
-            (":created_at", file.created_at),
-            (":updated_at", file.updated_at),
-        )?;
+        let mut bindings = file_row_bindings(file);
+        bindings.push((":version", 1.into()));
+
+        // The id of the inserted row is tracked per connection
+        let id = self.pinned(|| {
+            self.execute1(
+                "INSERT INTO files (version, kind, name, uid, gid, perms, rdev, size, stored_size, allocated_size, sha512, encryption_key, compression, \
+                accessed_at, accessed_at_nsec, created_at, created_at_nsec, updated_at, updated_at_nsec, changed_at, changed_at_nsec) \
+                VALUES (:version, :kind, :name, :uid, :gid, :perms, :rdev, :size, :stored_size, :allocated_size, :sha512, :encryption_key, :compression, \
+                :accessed_at, :accessed_at_nsec, :created_at, :created_at_nsec, :updated_at, :updated_at_nsec, :changed_at, :changed_at_nsec)",
+                &bindings[..],
+            )?;
+            self.get_last_inserted_row_id()
+        })?;
 
-        let id = self.get_last_inserted_row_id()?;
         Ok(id)
     }
 
@@ -146,57 +286,22 @@ impl MetadataDB {
         self.get_row(
             "SELECT * FROM files WHERE id = :id",
             (":id", id),
-            |row| {
-                Ok(FileRow {
-                    id: row.read("id")?,
-                    version: row.read("version")?,
-                    kind: row.read("kind")?,
-                    name: row.read("name")?,
-                    uid: row.read("uid")?,
-                    gid: row.read("gid")?,
-                    perms: row.read("perms")?,
-                    size: row.read("size")?,
-                    sha512: row.read("sha512")?,
-                    encryption_key: row.read("encryption_key")?,
-                    compression: row.read("compression")?,
-                    accessed_at: row.read("accessed_at")?,
-                    created_at: row.read("created_at")?,
-                    updated_at: row.read("updated_at")?,
-                })
-            })
+            read_file_row)
     }
 
     pub fn get_file_by_sha512(&self, sha512: &str) -> Result<Option<FileRow>, AnyError> {
         self.get_row(
             "SELECT * FROM files WHERE sha512 = :sha512 LIMIT 1",
             (":sha512", sha512),
-            |row| {
-                Ok(FileRow {
-                    id: row.read("id")?,
-                    version: row.read("version")?,
-                    kind: row.read("kind")?,
-                    name: row.read("name")?,
-                    uid: row.read("uid")?,
-                    gid: row.read("gid")?,
-                    perms: row.read("perms")?,
-                    size: row.read("size")?,
-                    sha512: row.read("sha512")?,
-                    encryption_key: row.read("encryption_key")?,
-                    compression: row.read("compression")?,
-                    accessed_at: row.read("accessed_at")?,
-                    created_at: row.read("created_at")?,
-                    updated_at: row.read("updated_at")?,
-                })
-            })
+            read_file_row)
     }
 
     pub fn get_file_by_path(&self, path: &str) -> Result<Option<FileRow>, AnyError> {
-        let buff = PathBuf::from(path);
         let mut current = ROOT_DIRECTORY_ID;
 
-        for part in buff.iter() {
-            let name = part.to_string_lossy();
-            let entry = self.find_directory_entry(current, &name)?;
+        // The root is not an entry of any directory
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let entry = self.find_directory_entry(current, name)?;
 
             match entry {
                 Some(e) => {
@@ -212,25 +317,36 @@ impl MetadataDB {
     }
 
     pub fn update_file(&self, file: &FileRow) -> Result<(), AnyError> {
-        self.execute13(
+        let mut bindings = file_row_bindings(file);
+        bindings.push((":id", file.id.into()));
+
+        self.execute1(
             "UPDATE files SET version = version + 1, \
-            kind = :kind, name = :name, uid = :uid, gid = :gid, perms = :perms, size = :size, \
+            kind = :kind, name = :name, uid = :uid, gid = :gid, perms = :perms, rdev = :rdev, size = :size, stored_size = :stored_size, \
+            allocated_size = :allocated_size, \
             sha512 = :sha512, encryption_key = :encryption_key, compression = :compression, \
-            accessed_at = :accessed_at, created_at = :created_at, updated_at = :updated_at \
+            accessed_at = :accessed_at, accessed_at_nsec = :accessed_at_nsec, created_at = :created_at, created_at_nsec = :created_at_nsec, \
+            updated_at = :updated_at, updated_at_nsec = :updated_at_nsec, changed_at = :changed_at, changed_at_nsec = :changed_at_nsec \
             WHERE id = :id",
-            (":kind", file.kind),
-            (":name", file.name.as_str()),
-            (":uid", file.uid),
-            (":gid", file.gid),
-            (":perms", file.perms),
-            (":size", file.size),
-            (":sha512", file.sha512.as_str()),
-            (":encryption_key", file.encryption_key.as_str()),
-            (":compression", file.compression.as_str()),
-            (":accessed_at", file.accessed_at),
-            (":created_at", file.created_at),
-            (":updated_at", file.updated_at),
-            (":id", file.id),
+            &bindings[..],
+        )?;
+        Ok(())
+    }
+
+    /// Update only the fields that change when new contents are stored
+    pub fn update_file_contents(&self, file: &FileRow) -> Result<(), AnyError> {
+        let mut bindings: Vec<(&str, Value)> = file_row_bindings(file).into_iter()
+            .filter(|(name, _)| CONTENT_BINDINGS.contains(name))
+            .collect();
+        bindings.push((":id", file.id.into()));
+
+        self.execute1(
+            "UPDATE files SET version = version + 1, \
+            size = :size, stored_size = :stored_size, allocated_size = :allocated_size, \
+            sha512 = :sha512, encryption_key = :encryption_key, compression = :compression, \
+            updated_at = :updated_at, updated_at_nsec = :updated_at_nsec, changed_at = :changed_at, changed_at_nsec = :changed_at_nsec \
+            WHERE id = :id",
+            &bindings[..],
         )?;
         Ok(())
     }
@@ -308,6 +424,9 @@ impl MetadataDB {
             path_components.push(file.name.to_string());
 
             let parent_directory_id = self.find_parent_directory(current_file_id)?;
+            if parent_directory_id.is_none() && current_file_id == file_id {
+                return Ok(unlinked_file_path(file_id));
+            }
             if parent_directory_id.is_none() || parent_directory_id.unwrap() == current_file_id {
                 break;
             }
@@ -367,15 +486,17 @@ impl MetadataDB {
     }
 
     pub fn add_directory_entry(&self, entry: &DirectoryEntry) -> Result<i64, AnyError> {
-        self.execute4(
-            "INSERT INTO directory_entries (directory_file_id, entry_file_id, name, kind) \
-            VALUES (:directory_file_id, :entry_file_id, :name, :kind)",
-            (":directory_file_id", entry.directory_file_id),
-            (":entry_file_id", entry.entry_file_id),
-            (":name", entry.name.as_str()),
-            (":kind", entry.kind),
-        )?;
-        let id = self.get_last_inserted_row_id()?;
+        let id = self.pinned(|| {
+            self.execute4(
+                "INSERT INTO directory_entries (directory_file_id, entry_file_id, name, kind) \
+                VALUES (:directory_file_id, :entry_file_id, :name, :kind)",
+                (":directory_file_id", entry.directory_file_id),
+                (":entry_file_id", entry.entry_file_id),
+                (":name", entry.name.as_str()),
+                (":kind", entry.kind),
+            )?;
+            self.get_last_inserted_row_id()
+        })?;
 
         self.execute1(
             "UPDATE files SET version = version + 1 WHERE id = :id",
@@ -386,16 +507,19 @@ impl MetadataDB {
     }
 
     pub fn get_last_inserted_row_id(&self) -> Result<i64, AnyError> {
-        let mut stm2 = self.connection.prepare("SELECT last_insert_rowid()")?;
+        let connection = self.connection()?;
+        let mut stm2 = connection.prepare("SELECT last_insert_rowid()")?;
         stm2.next()?;
         let id: i64 = stm2.read::<i64, _>(0)?;
         Ok(id)
     }
 
     pub fn file_set_access_time(&self, id: i64, accessed_at: i64) -> Result<(), AnyError> {
-        self.execute2(
-            "UPDATE files SET accessed_at = :accessed_at WHERE id = :id",
-            (":accessed_at", accessed_at),
+        let (seconds, nanos) = split_timestamp(accessed_at);
+        self.execute3(
+            "UPDATE files SET accessed_at = :accessed_at, accessed_at_nsec = :accessed_at_nsec WHERE id = :id",
+            (":accessed_at", seconds),
+            (":accessed_at_nsec", nanos),
             (":id", id),
         )?;
         Ok(())
@@ -464,6 +588,101 @@ impl MetadataDB {
         Ok(root)
     }
 
+    /// Compute the recursive usage of every directory, when objects are shared by hash (deduplication)
+    /// the stored size of each content is counted only once per directory
+    pub fn get_directory_usage(&self, shared_objects: bool) -> Result<Vec<DirectoryUsage>, AnyError> {
+        self.get_rows(
+            "
+            WITH RECURSIVE
+                ancestors(directory_file_id, file_id) AS (
+                    SELECT directory_file_id, entry_file_id
+                    FROM directory_entries
+                    WHERE name <> '.' AND name <> '..'
+                    UNION ALL
+                    SELECT e.directory_file_id, a.file_id
+                    FROM ancestors a JOIN directory_entries e ON e.entry_file_id = a.directory_file_id
+                    WHERE e.name <> '.' AND e.name <> '..'
+                ),
+                usage AS (
+                    SELECT a.directory_file_id AS id,
+                           sum(iif(f.kind = 0, f.size, 0)) AS size,
+                           count(iif(f.kind = 0, 1, NULL)) AS files,
+                           count(iif(f.kind = 1, 1, NULL)) AS directories
+                    FROM ancestors a JOIN files f ON f.id = a.file_id
+                    GROUP BY a.directory_file_id
+                ),
+                contents AS (
+                    SELECT a.directory_file_id AS id,
+                           iif(:shared_objects, max(f.stored_size), sum(f.stored_size)) AS stored_size
+                    FROM ancestors a JOIN files f ON f.id = a.file_id
+                    WHERE f.kind = 0
+                    GROUP BY a.directory_file_id, f.sha512
+                ),
+                stored AS (
+                    SELECT id, sum(stored_size) AS stored_size FROM contents GROUP BY id
+                )
+            SELECT p.id, p.path,
+                   coalesce(u.size, 0) AS size,
+                   coalesce(s.stored_size, 0) AS stored_size,
+                   coalesce(u.files, 0) AS files,
+                   coalesce(u.directories, 0) AS directories
+            FROM file_paths p
+                LEFT JOIN usage u ON u.id = p.id
+                LEFT JOIN stored s ON s.id = p.id
+            WHERE p.kind = 1",
+            (":shared_objects", shared_objects as i64),
+            |row| {
+                Ok(DirectoryUsage {
+                    id: row.read("id")?,
+                    path: row.read("path")?,
+                    size: row.read("size")?,
+                    stored_size: row.read("stored_size")?,
+                    files: row.read("files")?,
+                    directories: row.read("directories")?,
+                })
+            })
+    }
+
+    /// Files that cannot be reached from the root directory
+    pub fn get_orphan_files(&self) -> Result<Vec<FileRow>, AnyError> {
+        self.get_rows(
+            "SELECT * FROM files WHERE id NOT IN (SELECT id FROM file_paths) ORDER BY id",
+            NO_BINDINGS.as_ref(),
+            read_file_row)
+    }
+
+    /// Directory entries that belong to, or point to, a file that cannot be reached from the root directory
+    pub fn get_orphan_directory_entries(&self) -> Result<Vec<DirectoryEntry>, AnyError> {
+        self.get_rows(
+            "
+            SELECT * FROM directory_entries
+            WHERE directory_file_id NOT IN (SELECT id FROM file_paths)
+               OR entry_file_id NOT IN (SELECT id FROM file_paths)
+            ORDER BY id",
+            NO_BINDINGS.as_ref(),
+            |row| {
+                Ok(DirectoryEntry {
+                    id: row.read("id")?,
+                    directory_file_id: row.read("directory_file_id")?,
+                    entry_file_id: row.read("entry_file_id")?,
+                    name: row.read("name")?,
+                    kind: row.read("kind")?,
+                })
+            })
+    }
+
+    /// Regular files with content that can be reached from the root directory, with their full paths
+    pub fn get_files_with_content(&self) -> Result<Vec<(FileRow, String)>, AnyError> {
+        self.get_rows(
+            "
+            SELECT f.*, p.path AS full_path
+            FROM files f JOIN file_paths p ON p.id = f.id
+            WHERE f.kind = 0 AND f.sha512 <> ''
+            ORDER BY f.id",
+            NO_BINDINGS.as_ref(),
+            |row| Ok((read_file_row(row)?, row.read::<String, _>("full_path")?)))
+    }
+
     pub fn nuke(&self) -> Result<(), AnyError> {
         self.execute0("DELETE FROM directory_entries")?;
         self.execute0("DELETE FROM files")?;
@@ -473,13 +692,13 @@ impl MetadataDB {
         Ok(())
     }
 
-    pub fn get_row<'l, 'q, T, M, R>(self: &'q MetadataDB, query: &str, bindings: T, mapper: M) -> Result<Option<R>, AnyError>
+    pub fn get_row<T, M, R>(&self, query: &str, bindings: T, mapper: M) -> Result<Option<R>, AnyError>
     where
         T: Bindable + Clone,
-        M: FnOnce(&Statement<'l>) -> Result<R, AnyError>,
-        'q: 'l,
+        M: FnOnce(&Statement) -> Result<R, AnyError>,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(bindings)?;
 
         if let State::Row = statement.next()? {
@@ -489,13 +708,13 @@ impl MetadataDB {
         Ok(None)
     }
 
-    pub fn get_rows<'l, 'q, T, M, R>(self: &'q MetadataDB, query: &str, bindings: T, mapper: M) -> Result<Vec<R>, AnyError>
+    pub fn get_rows<T, M, R>(&self, query: &str, bindings: T, mapper: M) -> Result<Vec<R>, AnyError>
     where
         T: Bindable + Clone,
-        M: Fn(&Statement<'l>) -> Result<R, AnyError>,
-        'q: 'l,
+        M: Fn(&Statement) -> Result<R, AnyError>,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(bindings)?;
         let mut result = vec![];
 
@@ -506,8 +725,27 @@ impl MetadataDB {
         Ok(result)
     }
 
+    /// Run an arbitrary query, returning the column names and the raw values of every row
+    pub fn get_values(&self, query: &str) -> Result<(Vec<String>, Vec<Vec<Value>>), AnyError> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
+        let columns = statement.column_names().to_vec();
+        let mut rows = vec![];
+
+        while let State::Row = statement.next()? {
+            let mut row = Vec::with_capacity(columns.len());
+            for index in 0..columns.len() {
+                row.push(statement.read::<Value, _>(index)?);
+            }
+            rows.push(row);
+        }
+
+        Ok((columns, rows))
+    }
+
     pub fn execute0(&self, query: &str) -> Result<(), AnyError> {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.next()?;
         Ok(())
     }
@@ -516,7 +754,8 @@ impl MetadataDB {
     where
         B0: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.next()?;
         Ok(())
@@ -527,7 +766,8 @@ impl MetadataDB {
         B0: Bindable + Clone,
         B1: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.next()?;
@@ -540,7 +780,8 @@ impl MetadataDB {
         B1: Bindable + Clone,
         B2: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -555,7 +796,8 @@ impl MetadataDB {
         B2: Bindable + Clone,
         B3: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -572,7 +814,8 @@ impl MetadataDB {
         B3: Bindable + Clone,
         B4: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -591,7 +834,8 @@ impl MetadataDB {
         B4: Bindable + Clone,
         B5: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -612,7 +856,8 @@ impl MetadataDB {
         B5: Bindable + Clone,
         B6: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -635,7 +880,8 @@ impl MetadataDB {
         B6: Bindable + Clone,
         B7: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -660,7 +906,8 @@ impl MetadataDB {
         B7: Bindable + Clone,
         B8: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -687,7 +934,8 @@ impl MetadataDB {
         B8: Bindable + Clone,
         B9: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -716,7 +964,8 @@ impl MetadataDB {
         B9: Bindable + Clone,
         B10: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -747,7 +996,8 @@ impl MetadataDB {
         B10: Bindable + Clone,
         B11: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -780,7 +1030,8 @@ impl MetadataDB {
         B11: Bindable + Clone,
         B12: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -815,7 +1066,8 @@ impl MetadataDB {
         B12: Bindable + Clone,
         B13: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -852,7 +1104,8 @@ impl MetadataDB {
         B13: Bindable + Clone,
         B14: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -891,7 +1144,8 @@ impl MetadataDB {
         B14: Bindable + Clone,
         B15: Bindable + Clone,
     {
-        let mut statement = self.connection.prepare(query)?;
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(query)?;
         statement.bind(b0)?;
         statement.bind(b1)?;
         statement.bind(b2)?;
@@ -912,15 +1166,152 @@ impl MetadataDB {
         Ok(())
     }
 
-    pub fn transaction<R>(&self, func: impl FnOnce() -> Result<R, AnyError>) -> Result<R, AnyError> {
-        self.connection.execute("BEGIN TRANSACTION")?;
-        let res = func();
-        if res.is_ok() {
-            self.connection.execute("COMMIT")?;
-        } else {
-            self.connection.execute("ROLLBACK")?;
+    /// Run the queries of the function in a transaction of the current thread, other threads use other connections.
+    /// Inside another transaction, the queries are part of it.
+    pub fn transaction<R, E>(&self, func: impl FnOnce() -> Result<R, E>) -> Result<R, E>
+    where
+        E: From<AnyError>,
+    {
+        if OPEN_TRANSACTIONS.with(|open| open.borrow().contains(&self.id)) {
+            return func();
+        }
+
+        self.pinned(|| {
+            let mut close = CloseTransaction::new(self);
+            // Take the write lock right away, a deferred transaction fails if another connection writes first
+            self.connection()?.execute("BEGIN IMMEDIATE TRANSACTION").map_err(AnyError::from)?;
+            let value = func()?;
+            // A failed COMMIT leaves the transaction open, it is rolled back when closed
+            self.connection()?.execute("COMMIT").map_err(AnyError::from)?;
+            close.committed = true;
+            Ok(value)
+        })
+    }
+}
+
+fn read_file_row(row: &Statement) -> Result<FileRow, AnyError> {
+    Ok(FileRow {
+        id: row.read("id")?,
+        version: row.read("version")?,
+        kind: row.read("kind")?,
+        name: row.read("name")?,
+        uid: row.read("uid")?,
+        gid: row.read("gid")?,
+        perms: row.read("perms")?,
+        rdev: row.read("rdev")?,
+        size: row.read("size")?,
+        stored_size: row.read("stored_size")?,
+        allocated_size: row.read("allocated_size")?,
+        sha512: row.read("sha512")?,
+        encryption_key: row.read("encryption_key")?,
+        compression: row.read("compression")?,
+        accessed_at: join_timestamp(row.read("accessed_at")?, row.read("accessed_at_nsec")?),
+        created_at: join_timestamp(row.read("created_at")?, row.read("created_at_nsec")?),
+        updated_at: join_timestamp(row.read("updated_at")?, row.read("updated_at_nsec")?),
+        changed_at: join_timestamp(row.read("changed_at")?, row.read("changed_at_nsec")?),
+    })
+}
+
+// Bindings of the columns updated by update_file_contents
+const CONTENT_BINDINGS: [&str; 10] = [
+    ":size", ":stored_size", ":allocated_size", ":sha512", ":encryption_key", ":compression",
+    ":updated_at", ":updated_at_nsec", ":changed_at", ":changed_at_nsec",
+];
+
+/// Named values of every column of a file except id and version, timestamps are split into seconds and nanoseconds
+fn file_row_bindings(file: &FileRow) -> Vec<(&'static str, Value)> {
+    let (accessed_at, accessed_at_nsec) = split_timestamp(file.accessed_at);
+    let (created_at, created_at_nsec) = split_timestamp(file.created_at);
+    let (updated_at, updated_at_nsec) = split_timestamp(file.updated_at);
+    let (changed_at, changed_at_nsec) = split_timestamp(file.changed_at);
+
+    vec![
+        (":kind", file.kind.into()),
+        (":name", file.name.as_str().into()),
+        (":uid", file.uid.into()),
+        (":gid", file.gid.into()),
+        (":perms", file.perms.into()),
+        (":rdev", file.rdev.into()),
+        (":size", file.size.into()),
+        (":stored_size", file.stored_size.into()),
+        (":allocated_size", file.allocated_size.into()),
+        (":sha512", file.sha512.as_str().into()),
+        (":encryption_key", file.encryption_key.as_str().into()),
+        (":compression", file.compression.as_str().into()),
+        (":accessed_at", accessed_at.into()),
+        (":accessed_at_nsec", accessed_at_nsec.into()),
+        (":created_at", created_at.into()),
+        (":created_at_nsec", created_at_nsec.into()),
+        (":updated_at", updated_at.into()),
+        (":updated_at_nsec", updated_at_nsec.into()),
+        (":changed_at", changed_at.into()),
+        (":changed_at_nsec", changed_at_nsec.into()),
+    ]
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            PooledConnection::Pooled(_, connection) => connection.as_ref().unwrap(),
+            PooledConnection::Pinned(connection) => connection,
+        }
+    }
+}
+
+/// Forgets the transaction of the thread when it finishes, and rolls it back unless it was committed, also when the
+/// thread is unwinding from a panic, so the connection goes back to the pool without an open transaction
+struct CloseTransaction<'a> {
+    db: &'a MetadataDB,
+    committed: bool,
+}
+
+impl<'a> CloseTransaction<'a> {
+    fn new(db: &'a MetadataDB) -> CloseTransaction<'a> {
+        OPEN_TRANSACTIONS.with(|open| open.borrow_mut().insert(db.id));
+        CloseTransaction { db, committed: false }
+    }
+}
+
+impl Drop for CloseTransaction<'_> {
+    fn drop(&mut self) {
+        OPEN_TRANSACTIONS.with(|open| open.borrow_mut().remove(&self.db.id));
+
+        if !self.committed {
+            // Fails when there is no transaction: BEGIN failed or SQLite already rolled it back
+            if let Ok(connection) = self.db.connection() {
+                let _ = connection.execute("ROLLBACK");
+            }
+        }
+    }
+}
+
+/// Gives the pinned connection of the thread back to the pool, also when the thread is unwinding from a panic
+struct UnpinConnection<'a>(&'a MetadataDB);
+
+impl Drop for UnpinConnection<'_> {
+    fn drop(&mut self) {
+        let db = self.0;
+        let connection = PINNED_CONNECTIONS.with(|pinned| pinned.borrow_mut().remove(&db.id));
+
+        if let Some(connection) = connection.and_then(|c| Rc::try_unwrap(c).ok()) {
+            // A transaction interrupted by a panic must not stay open in the pool
+            if std::thread::panicking() {
+                let _ = connection.execute("ROLLBACK");
+            }
+            db.pool.lock().unwrap().push(connection);
+        }
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let PooledConnection::Pooled(db, connection) = self {
+            if let Some(connection) = connection.take() {
+                db.pool.lock().unwrap().push(connection);
+            }
         }
-        res
     }
 }
 
@@ -939,6 +1330,7 @@ impl FileRow {
         hash.update(&self.compression);
         hash.update(&self.created_at.to_string());
         hash.update(&self.updated_at.to_string());
+        hash.update(self.changed_at.to_string());
         hex::encode(hash.finalize())
     }
 }
@@ -952,4 +1344,101 @@ impl FileChangeKind {
             FileChangeKind::Deleted => 3,
         }
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_views() {
+    let fs = crate::sql_fs::test_file_system("views", "");
+    crate::sql_fs::test_fixture_tree(&fs);
+
+    let (columns, rows) = fs.sql.get_values("SELECT path, kind, size FROM file_paths ORDER BY path").unwrap();
+    assert_eq!(columns, vec!["path", "kind", "size"]);
+    let paths: Vec<_> = rows.iter().map(|row| (row[0].clone(), row[2].clone())).collect();
+    assert_eq!(paths, vec![
+        (Value::String("/".into()), Value::Integer(0)),
+        (Value::String("/docs".into()), Value::Integer(0)),
+        (Value::String("/docs/notes.txt".into()), Value::Integer(6)),
+        (Value::String("/empty".into()), Value::Integer(0)),
+        (Value::String("/photos".into()), Value::Integer(0)),
+        (Value::String("/photos/2024".into()), Value::Integer(0)),
+        (Value::String("/photos/2024/b.jpg".into()), Value::Integer(12)),
+        (Value::String("/photos/a.jpg".into()), Value::Integer(12)),
+    ]);
+
+    // Only the contents of the two photos are shared, the replaced notes are not counted
+    let (_, rows) = fs.sql.get_values("SELECT sha512, size, copies, saved_size FROM duplicate_contents").unwrap();
+    assert_eq!(rows, vec![vec![
+        Value::String(hex::encode(hmac_sha512::Hash::hash(b"same content"))),
+        Value::Integer(12),
+        Value::Integer(2),
+        Value::Integer(12),
+    ]]);
+
+    // The sql command uses a read-only connection
+    let db = MetadataDB::open_read_only(&fs.config.database_file).unwrap();
+    assert_eq!(db.get_values("SELECT count(*) FROM file_paths").unwrap().1, vec![vec![Value::Integer(8)]]);
+    assert!(db.get_values("DELETE FROM files").is_err());
+}
+
+#[test]
+fn test_directory_usage() {
+    use crate::obj_storage::create_object_storage;
+
+    let usage = |fs: &crate::sql_fs::SqlFileSystem, shared_objects: bool| {
+        let mut usage: Vec<_> = fs.sql.get_directory_usage(shared_objects).unwrap().into_iter()
+            .map(|u| (u.path, u.size, u.stored_size, u.files, u.directories))
+            .collect();
+        usage.sort();
+        usage
+    };
+
+    // Objects named by path, the notes were replaced in the same path
+    let fs = crate::sql_fs::test_file_system("du", "");
+    crate::sql_fs::test_fixture_tree(&fs);
+    assert_eq!(usage(&fs, false), vec![
+        ("/".to_string(), 30, 30, 3, 4),
+        ("/docs".to_string(), 6, 6, 1, 0),
+        ("/empty".to_string(), 0, 0, 0, 0),
+        ("/photos".to_string(), 24, 24, 2, 1),
+        ("/photos/2024".to_string(), 12, 12, 1, 0),
+    ]);
+    assert_eq!(create_object_storage(fs.config.primary.clone(), fs.sql.clone()).list().unwrap().len(), 3);
+    assert_eq!(fs.read_all(fs.sql.get_file_by_path("/docs/notes.txt").unwrap().unwrap().id).unwrap(), b"second");
+
+    // Objects named by hash, the photos share one
+    let fs = crate::sql_fs::test_file_system("du-shared", "  use_hash_as_filename: true\n");
+    crate::sql_fs::test_fixture_tree(&fs);
+    assert_eq!(usage(&fs, true), vec![
+        ("/".to_string(), 30, 18, 3, 4),
+        ("/docs".to_string(), 6, 6, 1, 0),
+        ("/empty".to_string(), 0, 0, 0, 0),
+        ("/photos".to_string(), 24, 12, 2, 1),
+        ("/photos/2024".to_string(), 12, 12, 1, 0),
+    ]);
+    assert_eq!(create_object_storage(fs.config.primary.clone(), fs.sql.clone()).list().unwrap().len(), 2);
+}
+
+#[test]
+fn test_transactions_roll_back() {
+    let fs = crate::sql_fs::test_file_system("transactions", "");
+    let db = &fs.sql;
+
+    let failed: Result<(), AnyError> = db.transaction(|| {
+        db.set_setting("test", "failed")?;
+        Err(anyhow!("failed"))
+    });
+    assert!(failed.is_err());
+    assert_eq!(db.get_setting("test").unwrap(), None);
+
+    // A panic does not give the connection back to the pool in the middle of a transaction
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        db.transaction(|| -> Result<(), AnyError> {
+            db.set_setting("test", "panicked")?;
+            panic!("panicked");
+        })
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(db.get_setting("test").unwrap(), None);
+
+    db.transaction(|| db.set_setting("test", "committed")).unwrap();
+    assert_eq!(db.get_setting("test").unwrap().as_deref(), Some("committed"));
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+use crate::obj_storage::{AsyncObjectStorage, ObjFuture, ObjInfo, ObjectStorage};
+use crate::storage::ObjInUseFn;
+use crate::AnyError;
+
+/// Adapter to use a synchronous storage as an AsyncObjectStorage, each request runs in a blocking
+/// thread of the runtime so several of them can be awaited at the same time
+#[derive(Clone)]
+pub struct BlockingObjectStorage {
+    pub inner: Arc<dyn ObjectStorage>,
+}
+
+impl BlockingObjectStorage {
+    pub fn new(inner: Arc<dyn ObjectStorage>) -> BlockingObjectStorage {
+        BlockingObjectStorage { inner }
+    }
+}
+
+impl AsyncObjectStorage for BlockingObjectStorage {
+    fn get<'a>(&'a self, info: &'a ObjInfo) -> ObjFuture<'a, Vec<u8>> {
+        let inner = self.inner.clone();
+        let info = info.clone();
+
+        Box::pin(async move {
+            spawn_blocking(move || inner.get(&info)).await?
+        })
+    }
+
+    fn put<'a>(&'a self, info: &'a mut ObjInfo, content: &'a [u8]) -> ObjFuture<'a, ()> {
+        let inner = self.inner.clone();
+        let mut updated = info.clone();
+        let content = content.to_vec();
+
+        Box::pin(async move {
+            // The backend sets the stored size on the copy, it is moved back once the upload is done
+            *info = spawn_blocking(move || {
+                inner.put(&mut updated, &content)?;
+                Ok::<ObjInfo, AnyError>(updated)
+            }).await??;
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(&'a self, info: &'a ObjInfo, is_in_use: ObjInUseFn) -> ObjFuture<'a, ()> {
+        let inner = self.inner.clone();
+        let info = info.clone();
+
+        Box::pin(async move {
+            spawn_blocking(move || inner.remove(&info, is_in_use)).await?
+        })
+    }
+
+    fn rename<'a>(&'a self, prev_info: &'a ObjInfo, new_info: &'a ObjInfo) -> ObjFuture<'a, ()> {
+        let inner = self.inner.clone();
+        let prev_info = prev_info.clone();
+        let new_info = new_info.clone();
+
+        Box::pin(async move {
+            spawn_blocking(move || inner.rename(&prev_info, &new_info)).await?
+        })
+    }
+
+    fn nuke(&self) -> ObjFuture<'_, ()> {
+        let inner = self.inner.clone();
+
+        Box::pin(async move {
+            spawn_blocking(move || inner.nuke()).await?
+        })
+    }
+}
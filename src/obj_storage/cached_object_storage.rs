@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use log::{debug, warn};
+use crate::AnyError;
+use crate::config::CachePolicy;
+use crate::obj_storage::{slice_range, ObjEntry, ObjInfo, ObjectStorage, UniquenessTest};
+use crate::storage::ObjInUseFn;
+
+// SHA512 of the copy, appended to it
+const CHECKSUM_LEN: usize = 64;
+// Version of the layout of the directory, copies of the first one were plaintext without a checksum
+const CACHE_FORMAT: &str = "2";
+
+/// Local copy of the objects read from a remote backend, kept between restarts. It wraps the backend below the
+/// encryption and compression, so the copies are the bytes stored in the backend, never the plaintext of an encrypted
+/// object. Copies are stored by the SHA512 of their contents (with the key and compression of encoded objects), so files
+/// with the same stored object share them, and end with a checksum so a damaged copy is downloaded again.
+pub struct CachedObjectStorage {
+    pub proxy: Box<dyn ObjectStorage>,
+    pub directory: PathBuf,
+    pub size_limit: u64,
+    pub policy: CachePolicy,
+    index: Mutex<CacheIndex>,
+    tmp_counter: AtomicU64,
+}
+
+/// Copies by cache key
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    size: u64,
+}
+
+struct CacheEntry {
+    size: u64,
+    used: SystemTime,
+    // The checksum was checked by this process, ranges of the copy can be read without reading it whole
+    verified: bool,
+}
+
+impl CachedObjectStorage {
+    pub fn new(proxy: Box<dyn ObjectStorage>, directory: PathBuf, size_limit: u64, policy: CachePolicy) -> Result<CachedObjectStorage, AnyError> {
+        fs::create_dir_all(&directory)?;
+        if fs::read_to_string(directory.join("format")).ok().as_deref() != Some(CACHE_FORMAT) {
+            Self::clear_directory(&directory)?;
+        }
+        let mut index = CacheIndex::default();
+
+        // The modification time of each copy is its last use
+        for prefix in fs::read_dir(&directory)? {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(prefix.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+
+                // Copy interrupted by a crash
+                if name.contains(".tmp-") {
+                    let _ = fs::remove_file(entry.path());
+                    continue;
+                }
+
+                let metadata = entry.metadata()?;
+                index.size += metadata.len();
+                index.entries.insert(name, CacheEntry { size: metadata.len(), used: metadata.modified()?, verified: false });
+            }
+        }
+        debug!("Object cache: {} objects, {} bytes", index.entries.len(), index.size);
+
+        let storage = CachedObjectStorage {
+            proxy,
+            directory,
+            size_limit,
+            policy,
+            index: Mutex::new(index),
+            tmp_counter: AtomicU64::new(0),
+        };
+
+        // The limit may have been lowered since the last run
+        storage.evict(&mut storage.index.lock().unwrap());
+        Ok(storage)
+    }
+
+    fn clear_directory(directory: &Path) -> Result<(), AnyError> {
+        fs::remove_dir_all(directory)?;
+        fs::create_dir_all(directory)?;
+        fs::write(directory.join("format"), CACHE_FORMAT)?;
+        Ok(())
+    }
+
+    /// Objects are stored as they are for the files without encryption or compression, encoded objects of the same
+    /// contents are different for each key and compression
+    fn cache_key(info: &ObjInfo) -> Option<String> {
+        if info.sha512.len() < 2 {
+            return None;
+        }
+        if info.encryption_key.is_empty() && info.compression.is_empty() {
+            return Some(info.sha512.clone());
+        }
+        let encoding = format!("{}:{}:{}", info.sha512, info.encryption_key, info.compression);
+        Some(hex::encode(hmac_sha512::Hash::hash(encoding.as_bytes())))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(&key[..2]).join(key)
+    }
+
+    fn read_cached(&self, key: &str) -> Option<Vec<u8>> {
+        if !self.index.lock().unwrap().entries.contains_key(key) {
+            return None;
+        }
+
+        let path = self.path(key);
+        let mut data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Unable to read cached object {}: {}", key, e);
+                self.forget(key);
+                return None;
+            }
+        };
+
+        let len = data.len().saturating_sub(CHECKSUM_LEN);
+        if data.len() < CHECKSUM_LEN || hmac_sha512::Hash::hash(&data[..len])[..] != data[len..] {
+            warn!("Cached object {} is damaged, it will be downloaded again", key);
+            self.forget(key);
+            return None;
+        }
+        data.truncate(len);
+
+        let now = SystemTime::now();
+        if self.policy == CachePolicy::Lru {
+            let _ = File::options().write(true).open(&path).and_then(|file| file.set_modified(now));
+        }
+        if let Some(entry) = self.index.lock().unwrap().entries.get_mut(key) {
+            entry.verified = true;
+            if self.policy == CachePolicy::Lru {
+                entry.used = now;
+            }
+        }
+        Some(data)
+    }
+
+    /// Keep a copy of an object, if it fails the object is just downloaded again the next time
+    fn store(&self, key: &str, data: &[u8]) {
+        if let Err(e) = self.try_store(key, data) {
+            warn!("Unable to cache object {}: {}", key, e);
+        }
+    }
+
+    fn try_store(&self, key: &str, data: &[u8]) -> Result<(), AnyError> {
+        let size = (data.len() + CHECKSUM_LEN) as u64;
+        if size > self.size_limit || self.index.lock().unwrap().entries.contains_key(key) {
+            return Ok(());
+        }
+
+        // Written aside and then renamed, so there are never partial copies
+        let path = self.path(key);
+        let tmp_path = path.with_extension(format!("tmp-{}-{}", process::id(), self.tmp_counter.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(self.directory.join(&key[..2]))?;
+
+        let write = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(data).and_then(|_| file.write_all(&hmac_sha512::Hash::hash(data))))
+            .and_then(|_| fs::rename(&tmp_path, &path));
+        if let Err(e) = write {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e.into());
+        }
+
+        let mut index = self.index.lock().unwrap();
+        if let Some(previous) = index.entries.insert(key.to_string(), CacheEntry { size, used: SystemTime::now(), verified: true }) {
+            index.size -= previous.size;
+        }
+        index.size += size;
+        self.evict(&mut index);
+        Ok(())
+    }
+
+    fn forget(&self, key: &str) {
+        let mut index = self.index.lock().unwrap();
+        if let Some(entry) = index.entries.remove(key) {
+            index.size -= entry.size;
+        }
+        let _ = fs::remove_file(self.path(key));
+    }
+
+    /// Remove the first copies of the policy until the size is within the limit
+    fn evict(&self, index: &mut CacheIndex) {
+        if index.size <= self.size_limit {
+            return;
+        }
+
+        let mut entries: Vec<(SystemTime, String, u64)> = index.entries.iter()
+            .map(|(key, entry)| (entry.used, key.clone(), entry.size))
+            .collect();
+        entries.sort();
+
+        for (_, key, size) in entries {
+            if index.size <= self.size_limit {
+                break;
+            }
+            debug!("Evict cached object: {}", key);
+            let _ = fs::remove_file(self.path(&key));
+            index.entries.remove(&key);
+            index.size -= size;
+        }
+    }
+}
+
+impl ObjectStorage for CachedObjectStorage {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+        let Some(key) = Self::cache_key(info) else {
+            return self.proxy.get(info);
+        };
+
+        if let Some(data) = self.read_cached(&key) {
+            return Ok(data);
+        }
+
+        let data = self.proxy.get(info)?;
+        self.store(&key, &data);
+        Ok(data)
+    }
+
+    // Ranges of objects that are not cached are not worth a whole download, they go to the backend
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        let Some(key) = Self::cache_key(info) else {
+            return self.proxy.get_range(info, offset, len);
+        };
+        let verified = self.index.lock().unwrap().entries.get(&key).map(|entry| entry.verified);
+
+        // The first read of a copy checks it whole
+        if verified == Some(false) {
+            if let Some(data) = self.read_cached(&key) {
+                return Ok(slice_range(&data, offset, len));
+            }
+        }
+        if verified == Some(true) {
+            let read = File::open(self.path(&key)).and_then(|mut file| {
+                // The checksum at the end is not part of the object
+                let end = file.metadata()?.len().saturating_sub(CHECKSUM_LEN as u64);
+                let mut data = vec![];
+                file.seek(SeekFrom::Start(offset.min(end)))?;
+                file.take(len.min(end.saturating_sub(offset))).read_to_end(&mut data)?;
+                Ok(data)
+            });
+            match read {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    warn!("Unable to read cached object {}: {}", key, e);
+                    self.forget(&key);
+                }
+            }
+        }
+        self.proxy.get_range(info, offset, len)
+    }
+
+    fn partial_reads(&self, info: &ObjInfo) -> bool {
+        Self::cache_key(info).is_some_and(|key| self.index.lock().unwrap().entries.contains_key(&key)) || self.proxy.partial_reads(info)
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+        self.proxy.put(info, content)?;
+
+        // Recently written contents are likely to be read again, the key and compression of the object are set by now
+        if let Some(key) = Self::cache_key(info) {
+            self.store(&key, content);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+        self.proxy.remove(info, is_in_use.clone())?;
+
+        // The copy is shared by all the files with the same stored object
+        if let Some(key) = Self::cache_key(info) {
+            if !is_in_use(info, UniquenessTest::Sha512)? {
+                self.forget(&key);
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+        self.proxy.rename(prev_info, new_info)
+    }
+
+    fn nuke(&self) -> Result<(), AnyError> {
+        self.proxy.nuke()?;
+
+        let mut index = self.index.lock().unwrap();
+        *index = CacheIndex::default();
+        Self::clear_directory(&self.directory)
+    }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        self.proxy.list()
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        self.proxy.key(info)
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        self.proxy.remove_key(key)
+    }
+}
+
+#[test]
+fn test_cache_keeps_stored_objects() {
+    use crate::obj_storage::{create_backend_object_storage, wrap_object_storage};
+
+    let fs = crate::sql_fs::test_file_system("object-cache", "  encryption_key: secret\n");
+    let directory = PathBuf::from(&fs.config.cache_directory).join("objects");
+    let open = || {
+        let backend = create_backend_object_storage(fs.config.primary.clone(), fs.sql.clone());
+        let cache = CachedObjectStorage::new(backend, directory.clone(), 1 << 20, CachePolicy::Lru).unwrap();
+        wrap_object_storage(fs.config.primary.clone(), Box::new(cache))
+    };
+    let storage = open();
+
+    let content = b"plaintext that must not be cached".repeat(8);
+    let mut info = ObjInfo { full_path: "/file".to_string(), sha512: hex::encode(hmac_sha512::Hash::hash(&content)), ..ObjInfo::default() };
+    storage.put(&mut info, &content).unwrap();
+
+    // Only the ciphertext is cached
+    let copies: Vec<PathBuf> = fs::read_dir(&directory).unwrap()
+        .filter_map(|entry| entry.ok().filter(|entry| entry.path().is_dir()))
+        .flat_map(|prefix| fs::read_dir(prefix.path()).unwrap().map(|entry| entry.unwrap().path()))
+        .collect();
+    assert_eq!(copies.len(), 1);
+    let mut copy = fs::read(&copies[0]).unwrap();
+    assert!(!copy.windows(9).any(|window| window == b"plaintext"));
+
+    // A copy damaged between two runs is downloaded again
+    copy[0] ^= 1;
+    fs::write(&copies[0], &copy).unwrap();
+    let storage = open();
+    assert_eq!(storage.get(&info).unwrap(), content);
+
+    // And then read from the cache
+    fs::remove_dir_all(&fs.config.primary.blob_storage).unwrap();
+    assert_eq!(storage.get(&info).unwrap(), content);
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use anyhow::anyhow;
+use log::{debug, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::AnyError;
+use crate::config::ChaosConfig;
+use crate::obj_storage::{ObjEntry, ObjInfo, ObjectStorage, TransientError};
+use crate::storage::ObjInUseFn;
+
+/// Injects faults in the operations of a real backend, to test replication, verification and the
+/// filesystem when a backend fails. It wraps the retries, so the injected errors are returned to the filesystem.
+/// Faults of each operation come from the seed, the object and the number of operations done on it before, so the same
+/// seed reproduces them even if requests of different objects are served by several threads in a different order.
+pub struct ChaosObjectStorage {
+    proxy: Box<dyn ObjectStorage>,
+    config: ChaosConfig,
+    seed: u64,
+    operations: Mutex<HashMap<String, u64>>,
+}
+
+impl ChaosObjectStorage {
+    pub fn new(proxy: Box<dyn ObjectStorage>, config: ChaosConfig) -> ChaosObjectStorage {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        warn!("Chaos storage enabled, faults are injected on purpose (seed {})", seed);
+
+        ChaosObjectStorage {
+            proxy,
+            config,
+            seed,
+            operations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Generator of the faults of the next operation on an object
+    fn faults(&self, object: &str) -> StdRng {
+        let count = {
+            let mut operations = self.operations.lock().unwrap();
+            let count = operations.entry(object.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        let hash = hmac_sha512::Hash::hash(format!("{}:{}:{}", self.seed, object, count).as_bytes());
+        StdRng::seed_from_u64(u64::from_le_bytes(hash[..8].try_into().unwrap()))
+    }
+
+    fn object(info: &ObjInfo) -> String {
+        format!("{}:{}", info.full_path, info.sha512)
+    }
+
+    fn chance(rng: &mut StdRng, rate: f64) -> bool {
+        rate > 0.0 && rng.gen_bool(rate)
+    }
+
+    /// Latency and random errors of every operation
+    fn inject(&self, rng: &mut StdRng, operation: &str) -> Result<(), AnyError> {
+        if self.config.latency_ms > 0 {
+            thread::sleep(Duration::from_millis(rng.gen_range(0..=self.config.latency_ms)));
+        }
+        if Self::chance(rng, self.config.error_rate) {
+            debug!("Chaos: {} failed", operation);
+            return Err(TransientError(anyhow!("Injected error in {}", operation)).into());
+        }
+        Ok(())
+    }
+
+    fn inject_read(&self, rng: &mut StdRng, info: &ObjInfo, mut content: Vec<u8>) -> Result<Vec<u8>, AnyError> {
+        if Self::chance(rng, self.config.missing_rate) {
+            debug!("Chaos: {} is missing", info);
+            return Err(std::io::Error::new(ErrorKind::NotFound, format!("Injected missing object {}", info)).into());
+        }
+        if !content.is_empty() && Self::chance(rng, self.config.bit_flip_rate) {
+            let bit = rng.gen_range(0..content.len() * 8);
+            debug!("Chaos: bit {} of {} flipped", bit, info);
+            content[bit / 8] ^= 1 << (bit % 8);
+        }
+        Ok(content)
+    }
+}
+
+impl ObjectStorage for ChaosObjectStorage {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+        let mut rng = self.faults(&Self::object(info));
+        self.inject(&mut rng, "Get")?;
+        let content = self.proxy.get(info)?;
+        self.inject_read(&mut rng, info, content)
+    }
+
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        let mut rng = self.faults(&Self::object(info));
+        self.inject(&mut rng, "Get range")?;
+        let content = self.proxy.get_range(info, offset, len)?;
+        self.inject_read(&mut rng, info, content)
+    }
+
+    fn partial_reads(&self, info: &ObjInfo) -> bool {
+        self.proxy.partial_reads(info)
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+        let mut rng = self.faults(&Self::object(info));
+        self.inject(&mut rng, "Put")?;
+        if !content.is_empty() && Self::chance(&mut rng, self.config.partial_write_rate) {
+            let len = rng.gen_range(0..content.len());
+            debug!("Chaos: only {} of {} bytes of {} written", len, content.len(), info);
+            // The write reports the whole size, like a backend that lost the end of the object
+            self.proxy.put(info, &content[..len])?;
+            info.stored_size = content.len() as u64;
+            return Ok(());
+        }
+        self.proxy.put(info, content)
+    }
+
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+        self.inject(&mut self.faults(&Self::object(info)), "Remove")?;
+        self.proxy.remove(info, is_in_use)
+    }
+
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+        self.inject(&mut self.faults(&Self::object(prev_info)), "Rename")?;
+        self.proxy.rename(prev_info, new_info)
+    }
+
+    fn nuke(&self) -> Result<(), AnyError> {
+        self.inject(&mut self.faults(""), "Nuke")?;
+        self.proxy.nuke()
+    }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        self.inject(&mut self.faults(""), "List")?;
+        self.proxy.list()
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        self.proxy.key(info)
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        self.inject(&mut self.faults(key), "Remove")?;
+        self.proxy.remove_key(key)
+    }
+}
+
+#[test]
+fn test_chaos_faults() {
+    use crate::obj_storage::debug_object_storage::DebugObjectStorage;
+    use crate::obj_storage::retrying_object_storage::is_transient;
+
+    let config = ChaosConfig { seed: Some(7), error_rate: 0.5, ..ChaosConfig::default() };
+    let outcomes = || {
+        let storage = ChaosObjectStorage::new(Box::new(DebugObjectStorage {}), config.clone());
+        (0..32).map(|_| storage.list().is_ok()).collect::<Vec<_>>()
+    };
+    // Same seed, same faults
+    let first = outcomes();
+    assert_eq!(first, outcomes());
+    assert!(first.contains(&true) && first.contains(&false));
+
+    // Faults of an object don't depend on the operations done on other objects meanwhile
+    let outcomes = |keys: &[&str]| {
+        let storage = ChaosObjectStorage::new(Box::new(DebugObjectStorage {}), config.clone());
+        keys.iter().map(|key| (*key == "a", storage.remove_key(key).is_ok())).filter(|(a, _)| *a).map(|(_, ok)| ok).collect::<Vec<_>>()
+    };
+    assert_eq!(outcomes(&["a", "b", "a", "b", "b", "a", "a"]), outcomes(&["b", "b", "b", "a", "a", "a", "a"]));
+
+    let storage = ChaosObjectStorage::new(Box::new(DebugObjectStorage {}), ChaosConfig {
+        error_rate: 1.0,
+        ..ChaosConfig::default()
+    });
+    assert!(is_transient(&storage.nuke().unwrap_err()));
+
+    let storage = ChaosObjectStorage::new(Box::new(DebugObjectStorage {}), ChaosConfig {
+        bit_flip_rate: 1.0,
+        partial_write_rate: 1.0,
+        ..ChaosConfig::default()
+    });
+    let content = vec![0u8; 64];
+    let flipped = storage.inject_read(&mut storage.faults(""), &ObjInfo::default(), content.clone()).unwrap();
+    assert_eq!(flipped.iter().map(|b| b.count_ones()).sum::<u32>(), 1);
+
+    let mut info = ObjInfo::default();
+    storage.put(&mut info, &content).unwrap();
+    assert_eq!(info.stored_size, content.len() as u64);
+}
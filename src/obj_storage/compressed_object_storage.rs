@@ -1,9 +1,52 @@
 use std::io::{Read, Write};
+use std::ops::Range;
+use anyhow::anyhow;
 use flate2::Compression;
 use crate::AnyError;
-use crate::obj_storage::{ObjInfo, ObjectStorage};
+use crate::obj_storage::{slice_range, ObjEntry, ObjInfo, ObjectStorage};
+use crate::sparse::SparseBuffer;
 use crate::storage::ObjInUseFn;
 
+// Framed objects are blocks compressed independently, after a header with the block length, the block count
+// and the compressed length of each block, so a range only needs the header and the blocks it touches.
+// Each block is a gzip member, with the CRC32 and length of its contents. Objects framed before used raw deflate.
+const FRAMED_PREFIX: &str = "framed-gzip:";
+const DEFLATE_FRAMED_PREFIX: &str = "framed:";
+const BLOCK_LEN: usize = 64 * 1024;
+// Enough for the header of files up to 64 MiB, larger ones need a second request
+const HEADER_PREFETCH: u64 = 4096;
+
+/// Position of the compressed blocks of a framed object
+struct FrameIndex {
+    block_len: u64,
+    // Start of each block in the object, and the end of the last one
+    offsets: Vec<u64>,
+}
+
+impl FrameIndex {
+    fn header_len(count: u64) -> u64 {
+        8 + 4 * count
+    }
+
+    fn parse(header: &[u8]) -> Result<Option<FrameIndex>, AnyError> {
+        if header.len() < 8 {
+            return Err(anyhow!("Invalid framed object: missing header"));
+        }
+        let block_len = u32::from_le_bytes(header[0..4].try_into()?) as u64;
+        let count = u32::from_le_bytes(header[4..8].try_into()?) as u64;
+        let header_len = Self::header_len(count);
+        if (header.len() as u64) < header_len {
+            return Ok(None);
+        }
+
+        let mut offsets = vec![header_len];
+        for size in header[8..header_len as usize].chunks(4) {
+            offsets.push(offsets.last().unwrap() + u32::from_le_bytes(size.try_into()?) as u64);
+        }
+        Ok(Some(FrameIndex { block_len, offsets }))
+    }
+}
+
 pub struct CompressedObjectStorage {
     pub proxy: Box<dyn ObjectStorage>,
     pub level: u32,
@@ -13,10 +56,96 @@ impl CompressedObjectStorage {
     pub fn new(proxy: Box<dyn ObjectStorage>, level: u32) -> CompressedObjectStorage {
         CompressedObjectStorage { proxy, level }
     }
+
+    /// Whether the object is framed, and if its blocks are gzip members
+    fn framing(compression: &str) -> Option<bool> {
+        if compression.starts_with(FRAMED_PREFIX) {
+            Some(true)
+        } else if compression.starts_with(DEFLATE_FRAMED_PREFIX) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    fn compress_framed(level: u32, content: &[u8]) -> Result<Vec<u8>, AnyError> {
+        let mut blocks = vec![];
+        for block in content.chunks(BLOCK_LEN) {
+            let mut encoder = flate2::write::GzEncoder::new(vec![], Compression::new(level));
+            encoder.write_all(block)?;
+            blocks.push(encoder.finish()?);
+        }
+
+        let mut buff = Vec::with_capacity(FrameIndex::header_len(blocks.len() as u64) as usize + blocks.iter().map(Vec::len).sum::<usize>());
+        buff.extend((BLOCK_LEN as u32).to_le_bytes());
+        buff.extend(u32::try_from(blocks.len())?.to_le_bytes());
+        for block in &blocks {
+            buff.extend((block.len() as u32).to_le_bytes());
+        }
+        for block in &blocks {
+            buff.extend(block);
+        }
+        Ok(buff)
+    }
+
+    /// Range of a framed object, reading only the header and the blocks it touches
+    fn read_framed(fetch: impl Fn(u64, u64) -> Result<Vec<u8>, AnyError>, gzip: bool, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut header = fetch(0, HEADER_PREFETCH)?;
+        let index = match FrameIndex::parse(&header)? {
+            Some(index) => index,
+            None => {
+                let count = u32::from_le_bytes(header[4..8].try_into()?) as u64;
+                header.extend(fetch(HEADER_PREFETCH, FrameIndex::header_len(count) - HEADER_PREFETCH)?);
+                FrameIndex::parse(&header)?.ok_or_else(|| anyhow!("Invalid framed object: truncated header"))?
+            }
+        };
+
+        let count = index.offsets.len() as u64 - 1;
+        let first = offset / index.block_len;
+        if first >= count {
+            return Ok(vec![]);
+        }
+        let last = (offset.saturating_add(len - 1) / index.block_len).min(count - 1);
+
+        let start = index.offsets[first as usize];
+        let blocks = fetch(start, index.offsets[last as usize + 1] - start)?;
+        let content = Self::decompress_blocks(&index, first..last + 1, &blocks, gzip)?;
+        Ok(slice_range(&content, offset - first * index.block_len, len))
+    }
+
+    /// Contents of consecutive blocks of a framed object, the bytes given start at the first one. Every block must be
+    /// complete and have the block length, except the last block of the object
+    fn decompress_blocks(index: &FrameIndex, range: Range<u64>, blocks: &[u8], gzip: bool) -> Result<Vec<u8>, AnyError> {
+        let base = index.offsets[range.start as usize];
+        let count = index.offsets.len() as u64 - 1;
+        let mut content = vec![];
+
+        for block in range {
+            let (start, end) = ((index.offsets[block as usize] - base) as usize, (index.offsets[block as usize + 1] - base) as usize);
+            let data = blocks.get(start..end).ok_or_else(|| anyhow!("Invalid framed object: block {} is missing", block))?;
+
+            let len = content.len();
+            let decoded = match gzip {
+                true => flate2::read::GzDecoder::new(data).read_to_end(&mut content),
+                false => flate2::read::DeflateDecoder::new(data).read_to_end(&mut content),
+            };
+            decoded.map_err(|e| anyhow!("Invalid framed object: block {} is corrupted: {}", block, e))?;
+
+            let block_len = (content.len() - len) as u64;
+            if block_len > index.block_len || (block_len < index.block_len && block + 1 < count) {
+                return Err(anyhow!("Invalid framed object: block {} has {} bytes", block, block_len));
+            }
+        }
+        Ok(content)
+    }
 }
 
 impl ObjectStorage for CompressedObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
         let bytes = self.proxy.get(info)?;
 
         // No compression was used for this object
@@ -24,6 +153,17 @@ impl ObjectStorage for CompressedObjectStorage {
             return Ok(bytes);
         }
 
+        if let Some(gzip) = Self::framing(&info.compression) {
+            let index = FrameIndex::parse(&bytes)?.ok_or_else(|| anyhow!("Invalid framed object: truncated header"))?;
+            let content = Self::decompress_blocks(&index, 0..index.offsets.len() as u64 - 1, &bytes[index.offsets[0] as usize..], gzip)?;
+
+            // Sparse contents are shorter or longer than the file, their encoding has its own size
+            if content.len() as u64 != info.size && !SparseBuffer::is_encoded(&content) {
+                return Err(anyhow!("Invalid framed object: {} bytes instead of {}", content.len(), info.size));
+            }
+            return Ok(content);
+        }
+
         let mut buff = vec![];
         {
             let mut gz = flate2::read::GzDecoder::new(&bytes[..]);
@@ -33,31 +173,98 @@ impl ObjectStorage for CompressedObjectStorage {
         Ok(buff)
     }
 
-    fn put(&mut self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
-        let mut buff = vec![];
-        {
-            let mut gz = flate2::write::GzEncoder::new(&mut buff, Compression::new(self.level));
-            gz.write_all(content)?;
-            gz.finish()?;
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        if info.compression.is_empty() {
+            return self.proxy.get_range(info, offset, len);
         }
+        // Objects compressed as a single stream can only be read whole
+        let Some(gzip) = Self::framing(&info.compression) else {
+            return Ok(slice_range(&self.get(info)?, offset, len));
+        };
+        Self::read_framed(|start, len| self.proxy.get_range(info, start, len), gzip, offset, len)
+    }
+
+    fn partial_reads(&self, info: &ObjInfo) -> bool {
+        (info.compression.is_empty() || Self::framing(&info.compression).is_some()) && self.proxy.partial_reads(info)
+    }
 
-        info.compression = format!("gzip:{}", self.level);
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+        let buff = Self::compress_framed(self.level, content)?;
+        info.compression = format!("{}{}", FRAMED_PREFIX, self.level);
         self.proxy.put(info, buff.as_slice())?;
         Ok(())
     }
 
-    fn remove(&mut self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
         self.proxy.remove(info, is_in_use)?;
         Ok(())
     }
 
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
         self.proxy.rename(prev_info, new_info)?;
         Ok(())
     }
 
-    fn nuke(&mut self) -> Result<(), AnyError> {
+    fn nuke(&self) -> Result<(), AnyError> {
         self.proxy.nuke()?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        self.proxy.list()
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        self.proxy.key(info)
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        self.proxy.remove_key(key)
+    }
+}
+
+#[test]
+fn test_framed_compression() {
+    let content: Vec<u8> = (0..3 * BLOCK_LEN as u32 + 100).map(|i| (i % 7 + i / 1000) as u8).collect();
+    let stored = CompressedObjectStorage::compress_framed(6, &content).unwrap();
+    let fetch = |offset, len| Ok(slice_range(&stored, offset, len));
+
+    for (offset, len) in [(0, 10), (BLOCK_LEN as u64 - 5, 10), (100, 2 * BLOCK_LEN as u64), (0, u64::MAX), (content.len() as u64 - 3, 10), (content.len() as u64, 10)] {
+        assert_eq!(CompressedObjectStorage::read_framed(fetch, true, offset, len).unwrap(), slice_range(&content, offset, len));
+    }
+}
+
+#[test]
+fn test_damaged_framed_objects() {
+    let content: Vec<u8> = (0..3 * BLOCK_LEN as u32 + 100).map(|i| (i % 7 + i / 1000) as u8).collect();
+    let stored = CompressedObjectStorage::compress_framed(6, &content).unwrap();
+
+    // A missing block is an error instead of shorter contents
+    let truncated = |offset, len| Ok(slice_range(&stored[..stored.len() - 10], offset, len));
+    let e = CompressedObjectStorage::read_framed(truncated, true, 0, u64::MAX).unwrap_err();
+    assert!(e.to_string().contains("block 3 is missing"), "{}", e);
+
+    // The CRC32 at the end of each block
+    let mut damaged = stored.clone();
+    let crc = damaged.len() - 6;
+    damaged[crc] ^= 1;
+    let fetch = |offset, len| Ok(slice_range(&damaged, offset, len));
+    assert!(CompressedObjectStorage::read_framed(fetch, true, 0, 10).is_ok());
+    let e = CompressedObjectStorage::read_framed(fetch, true, 3 * BLOCK_LEN as u64, 10).unwrap_err();
+    assert!(e.to_string().contains("block 3 is corrupted"), "{}", e);
+}
+
+#[test]
+fn test_compressed_size_is_checked() {
+    use crate::obj_storage::create_object_storage;
+
+    let fs = crate::sql_fs::test_file_system("compressed-size", "  compression_level: 6\n");
+    let storage = create_object_storage(fs.config.primary.clone(), fs.sql.clone());
+    let mut info = ObjInfo { full_path: "/file".to_string(), sha512: "sha".to_string(), size: 11, ..ObjInfo::default() };
+    storage.put(&mut info, b"hello world").unwrap();
+    assert!(info.compression.starts_with(FRAMED_PREFIX));
+    assert_eq!(storage.get(&info).unwrap(), b"hello world");
+
+    info.size = 12;
+    assert!(storage.get(&info).unwrap_err().to_string().contains("11 bytes instead of 12"));
+}
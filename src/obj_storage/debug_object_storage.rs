@@ -1,4 +1,4 @@
-use crate::obj_storage::{ObjInfo, ObjectStorage};
+use crate::obj_storage::{ObjEntry, ObjInfo, ObjectStorage};
 use crate::storage::ObjInUseFn;
 use crate::AnyError;
 use log::info;
@@ -6,28 +6,43 @@ use log::info;
 pub struct DebugObjectStorage {}
 
 impl ObjectStorage for DebugObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
         info!("Get: {}", info);
         Ok(vec![])
     }
 
-    fn put(&mut self, info: &mut ObjInfo, _content: &[u8]) -> Result<(), AnyError> {
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
         info!("Put: {}", info);
+        info.stored_size = content.len() as u64;
         Ok(())
     }
 
-    fn remove(&mut self, info: &ObjInfo, _is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+    fn remove(&self, info: &ObjInfo, _is_in_use: ObjInUseFn) -> Result<(), AnyError> {
         info!("Remove: {}", info);
         Ok(())
     }
 
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
         info!("Rename: {} to {}", prev_info, new_info);
         Ok(())
     }
 
-    fn nuke(&mut self) -> Result<(), AnyError> {
+    fn nuke(&self) -> Result<(), AnyError> {
         info!("Nuke");
         Ok(())
     }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        info!("List");
+        Ok(vec![])
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        Ok(info.full_path.clone())
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        info!("Remove: {}", key);
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use crate::config::StorageConfig;
-use crate::obj_storage::{ObjInfo, ObjectStorage};
+use crate::obj_storage::{slice_range, ObjEntry, ObjInfo, ObjectStorage};
 use crate::storage::ObjInUseFn;
 use crate::AnyError;
 use aes_gcm::aead::consts::U12;
@@ -11,7 +11,7 @@ use anyhow::{anyhow, Error};
 use pbkdf2::pbkdf2_hmac;
 use sha2::Sha256;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::Arc;
 
 const AES_KEY_LEN: usize = 32;
 const SALT_LEN: usize = 32;
@@ -20,9 +20,18 @@ const AEAD_LEN: usize = 10;
 // More rounds are better, but slower, since they are used every file access, we need to keep them low
 // Technically, we are not storing the password nor the salted password, so it's **fine** (tm)
 const PBKDF2_ITERATIONS: u32 = 256;
+// Chunked objects are a sequence of segments encrypted on their own, so a range only needs to fetch and
+// decrypt the segments it touches. The nonce of each segment is derived from the file nonce, its index and
+// whether it is the last one, so segments can't be reordered or dropped at the end without failing.
+const CHUNKED_PREFIX: &str = "chunked:";
+const SEGMENT_LEN: u64 = 64 * 1024;
+const TAG_LEN: u64 = 16;
+const STORED_SEGMENT_LEN: u64 = SEGMENT_LEN + TAG_LEN;
+// Segments fetched in each request when reading the whole object from a backend with range reads
+const SEGMENTS_PER_REQUEST: u64 = 64;
 
 pub struct EncryptedObjectStorage {
-    config: Rc<StorageConfig>,
+    config: Arc<StorageConfig>,
     fs: Box<dyn ObjectStorage>,
 }
 
@@ -30,6 +39,7 @@ pub struct FileKey {
     salt: [u8; SALT_LEN],
     nonce: [u8; NONCE_LEN],
     aead: String,
+    chunked: bool,
 }
 
 fn vec_to_array<T, const N: usize>(v: Vec<T>) -> Result<[T; N], Error> {
@@ -39,10 +49,16 @@ fn vec_to_array<T, const N: usize>(v: Vec<T>) -> Result<[T; N], Error> {
 
 impl FileKey {
     pub fn serialize(&self) -> String {
-        format!("{}:{}:{}", hex::encode(self.salt), hex::encode(self.nonce), self.aead)
+        let prefix = if self.chunked { CHUNKED_PREFIX } else { "" };
+        format!("{}{}:{}:{}", prefix, hex::encode(self.salt), hex::encode(self.nonce), self.aead)
     }
 
     pub fn deserialize(s: &str) -> Result<FileKey, Error> {
+        // Keys without the prefix are from objects encrypted as a single message
+        let (s, chunked) = match s.strip_prefix(CHUNKED_PREFIX) {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
         if s.len() != 100 {
             return Err(anyhow!("Invalid file key: incorrect length"));
         }
@@ -69,12 +85,13 @@ impl FileKey {
             salt: vec_to_array(salt)?,
             nonce: vec_to_array(nonce)?,
             aead: parts[2].to_string(),
+            chunked,
         })
     }
 }
 
 impl EncryptedObjectStorage {
-    pub fn new(config: Rc<StorageConfig>, fs: Box<dyn ObjectStorage>) -> EncryptedObjectStorage {
+    pub fn new(config: Arc<StorageConfig>, fs: Box<dyn ObjectStorage>) -> EncryptedObjectStorage {
         EncryptedObjectStorage { config, fs }
     }
 
@@ -100,29 +117,36 @@ impl EncryptedObjectStorage {
 
         let aead = content_sha512[..AEAD_LEN].to_string();
 
-        let file_key = FileKey { salt, nonce, aead };
-        let ciphertext = Self::encrypt_internal(&aes_key, &file_key, content)?;
+        let file_key = FileKey { salt, nonce, aead, chunked: true };
+        let ciphertext = Self::encrypt_chunked(&aes_key, &file_key, content)?;
 
         Ok((file_key, ciphertext))
     }
 
-    pub fn encrypt_internal(aes_key: &[u8; AES_KEY_LEN], key: &FileKey, content: &[u8]) -> Result<Vec<u8>, Error> {
-        let mut nonce: GenericArray<u8, U12> = Nonce::<Aes256Gcm>::default();
-        nonce.copy_from_slice(&key.nonce);
-
+    pub fn encrypt_chunked(aes_key: &[u8; AES_KEY_LEN], key: &FileKey, content: &[u8]) -> Result<Vec<u8>, Error> {
         let cipher = Aes256Gcm::new_from_slice(aes_key)?;
-
-        let ciphertext = cipher.encrypt(&nonce, Payload {
-            msg: content,
-            aad: key.aead.as_bytes(),
-        }).map_err(|_| anyhow!("Encryption failed"))?;
+        // Empty contents are still a segment, so a truncated object is always detected
+        let count = (content.len() as u64).div_ceil(SEGMENT_LEN).max(1);
+        let mut ciphertext = Vec::with_capacity(content.len() + (count * TAG_LEN) as usize);
+
+        for index in 0..count {
+            let segment = slice_range(content, index * SEGMENT_LEN, SEGMENT_LEN);
+            ciphertext.extend(cipher.encrypt(&Self::segment_nonce(key, index, index + 1 == count)?, Payload {
+                msg: &segment,
+                aad: key.aead.as_bytes(),
+            }).map_err(|_| anyhow!("Encryption failed"))?);
+        }
 
         Ok(ciphertext)
     }
 
     pub fn decrypt(private_key: &str, file_key: &FileKey, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
         let aes_key = Self::salt_password(private_key, &file_key.salt);
-        let plaintext = Self::decrypt_internal(&aes_key, file_key, ciphertext)?;
+        let plaintext = if file_key.chunked {
+            Self::decrypt_segments(&aes_key, file_key, |offset, len| Ok(slice_range(ciphertext, offset, len)), 0, u64::MAX)?
+        } else {
+            Self::decrypt_internal(&aes_key, file_key, ciphertext)?
+        };
 
         Ok(plaintext)
     }
@@ -141,6 +165,50 @@ impl EncryptedObjectStorage {
         Ok(plaintext)
     }
 
+    fn segment_nonce(key: &FileKey, index: u64, last: bool) -> Result<Nonce<Aes256Gcm>, Error> {
+        let mut nonce = Nonce::<Aes256Gcm>::default();
+        nonce[..7].copy_from_slice(&key.nonce[..7]);
+        nonce[7..11].copy_from_slice(&u32::try_from(index)?.to_be_bytes());
+        nonce[11] = last as u8;
+        Ok(nonce)
+    }
+
+    /// Plaintext of the segments from first to last of a chunked object, fewer at the end of the object.
+    /// Each request asks for one byte more than the segments, to know if the last of them ends the object.
+    fn decrypt_segments(aes_key: &[u8; AES_KEY_LEN], key: &FileKey, fetch: impl Fn(u64, u64) -> Result<Vec<u8>, Error>, first: u64, last: u64) -> Result<Vec<u8>, Error> {
+        let cipher = Aes256Gcm::new_from_slice(aes_key)?;
+        let mut plaintext = vec![];
+        let mut index = first;
+
+        while index <= last {
+            let count = (last - index).min(SEGMENTS_PER_REQUEST - 1) + 1;
+            let data = fetch(index * STORED_SEGMENT_LEN, count * STORED_SEGMENT_LEN + 1)?;
+            if data.is_empty() {
+                if index == 0 {
+                    return Err(anyhow!("Decryption failed: empty object"));
+                }
+                break;
+            }
+
+            let at_end = data.len() as u64 <= count * STORED_SEGMENT_LEN;
+            let segments: Vec<&[u8]> = data[..data.len().min((count * STORED_SEGMENT_LEN) as usize)].chunks(STORED_SEGMENT_LEN as usize).collect();
+            for (i, segment) in segments.iter().enumerate() {
+                let nonce = Self::segment_nonce(key, index, at_end && i + 1 == segments.len())?;
+                plaintext.extend(cipher.decrypt(&nonce, Payload {
+                    msg: segment,
+                    aad: key.aead.as_bytes(),
+                }).map_err(|_| anyhow!("Decryption failed"))?);
+                index += 1;
+            }
+
+            if at_end {
+                break;
+            }
+        }
+
+        Ok(plaintext)
+    }
+
     fn path(&self, key: &FileKey, original_path: &str) -> String {
         if self.config.use_hash_as_filename {
             let uniq = hex::encode(&key.nonce);
@@ -155,18 +223,56 @@ impl EncryptedObjectStorage {
 }
 
 impl ObjectStorage for EncryptedObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, Error> {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, Error> {
         let key = FileKey::deserialize(&info.encryption_key)?;
         let mut info = info.clone();
         info.full_path = self.path(&key, &info.full_path);
 
+        if key.chunked {
+            let aes_key = Self::salt_password(&self.config.encryption_key, &key.salt);
+
+            // Objects larger than a request are fetched by ranges of segments, so the whole ciphertext is never in
+            // memory with the plaintext. Smaller ones take a single request, which an object cache also keeps
+            if info.size > SEGMENTS_PER_REQUEST * SEGMENT_LEN && self.fs.partial_reads(&info) {
+                return Self::decrypt_segments(&aes_key, &key, |offset, len| self.fs.get_range(&info, offset, len), 0, u64::MAX);
+            }
+
+            // Otherwise fetched in a single request, the segments are decrypted from memory
+            let bytes = self.fs.get(&info)?;
+            return Self::decrypt_segments(&aes_key, &key, |offset, len| Ok(slice_range(&bytes, offset, len)), 0, u64::MAX);
+        }
+
         let bytes = self.fs.get(&info)?;
+
         let original_bytes = Self::decrypt(&self.config.encryption_key, &key, &bytes)?;
 
         Ok(original_bytes)
     }
 
-    fn put(&mut self, info: &mut ObjInfo, content: &[u8]) -> Result<(), Error> {
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        let key = FileKey::deserialize(&info.encryption_key)?;
+        if !key.chunked {
+            return Ok(slice_range(&self.get(info)?, offset, len));
+        }
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut info = info.clone();
+        info.full_path = self.path(&key, &info.full_path);
+        let aes_key = Self::salt_password(&self.config.encryption_key, &key.salt);
+        let first = offset / SEGMENT_LEN;
+        let last = offset.saturating_add(len - 1) / SEGMENT_LEN;
+
+        let plaintext = Self::decrypt_segments(&aes_key, &key, |offset, len| self.fs.get_range(&info, offset, len), first, last)?;
+        Ok(slice_range(&plaintext, offset - first * SEGMENT_LEN, len))
+    }
+
+    fn partial_reads(&self, info: &ObjInfo) -> bool {
+        FileKey::deserialize(&info.encryption_key).is_ok_and(|key| key.chunked) && self.fs.partial_reads(info)
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), Error> {
         let (key, bytes) = Self::encrypt(&self.config.encryption_key, &content, &info.sha512)?;
         let full_path = self.path(&key, &info.full_path);
         let prev_path = info.full_path.clone();
@@ -178,16 +284,16 @@ impl ObjectStorage for EncryptedObjectStorage {
         Ok(())
     }
 
-    fn remove(&mut self, info: &ObjInfo, _is_in_use: ObjInUseFn) -> Result<(), Error> {
+    fn remove(&self, info: &ObjInfo, _is_in_use: ObjInUseFn) -> Result<(), Error> {
         let key = FileKey::deserialize(&info.encryption_key)?;
         let mut info = info.clone();
         info.full_path = self.path(&key, &info.full_path);
-        let always_unique: ObjInUseFn = Rc::new(|_, _| Ok(false));
+        let always_unique: ObjInUseFn = Arc::new(|_, _| Ok(false));
 
         self.fs.remove(&info, always_unique)
     }
 
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
         let key = FileKey::deserialize(&prev_info.encryption_key)?;
         let prev_path = self.path(&key, &prev_info.full_path);
         let new_path = self.path(&key, &new_info.full_path);
@@ -205,9 +311,25 @@ impl ObjectStorage for EncryptedObjectStorage {
         Ok(())
     }
 
-    fn nuke(&mut self) -> Result<(), Error> {
+    fn nuke(&self) -> Result<(), Error> {
         self.fs.nuke()
     }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, Error> {
+        self.fs.list()
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, Error> {
+        let key = FileKey::deserialize(&info.encryption_key)?;
+        let mut info = info.clone();
+        info.full_path = self.path(&key, &info.full_path);
+
+        self.fs.key(&info)
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), Error> {
+        self.fs.remove_key(key)
+    }
 }
 
 #[test]
@@ -247,5 +369,39 @@ fn test_encryption() {
     println!("Plaintext: {:?}", String::from_utf8_lossy(&plaintext));
 
     // Using the provided script to decrypt the ciphertext with all the parameters
-    println!(r#"deno run -A ./scripts/aes_decrypt.ts "{}" "{}" "{}" "{}" "{}""#, password, hex::encode(file_key.salt), hex::encode(file_key.nonce), file_key.aead, hex::encode(&ciphertext));
+    println!(r#"deno run -A ./scripts/aes_decrypt.ts "{}" "{}" "{}" "{}" "{}" chunked"#, password, hex::encode(file_key.salt), hex::encode(file_key.nonce), file_key.aead, hex::encode(&ciphertext));
+}
+
+#[test]
+fn test_chunked_encryption() {
+    let aes_key = EncryptedObjectStorage::salt_password("1234", b"1234");
+    let key = FileKey { salt: [0; SALT_LEN], nonce: [7; NONCE_LEN], aead: "0123456789".to_string(), chunked: true };
+
+    for size in [0, 10, SEGMENT_LEN, 3 * SEGMENT_LEN + 100] {
+        let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let ciphertext = EncryptedObjectStorage::encrypt_chunked(&aes_key, &key, &content).unwrap();
+        let fetch = |offset, len| Ok(slice_range(&ciphertext, offset, len));
+        assert_eq!(EncryptedObjectStorage::decrypt_segments(&aes_key, &key, fetch, 0, u64::MAX).unwrap(), content);
+        assert_eq!(EncryptedObjectStorage::decrypt_segments(&aes_key, &key, fetch, 1, 2).unwrap(), slice_range(&content, SEGMENT_LEN, 2 * SEGMENT_LEN));
+
+        // Dropping the last segments is detected
+        if size > SEGMENT_LEN {
+            let truncated = |offset, len| Ok(slice_range(&ciphertext[..STORED_SEGMENT_LEN as usize], offset, len));
+            assert!(EncryptedObjectStorage::decrypt_segments(&aes_key, &key, truncated, 0, u64::MAX).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_encrypted_get_by_ranges() {
+    use crate::obj_storage::create_object_storage;
+
+    let fs = crate::sql_fs::test_file_system("encrypted-get", "  encryption_key: \"1234\"\n");
+    let storage = create_object_storage(fs.config.primary.clone(), fs.sql.clone());
+    let content: Vec<u8> = (0..SEGMENTS_PER_REQUEST * SEGMENT_LEN + 100).map(|i| (i % 251) as u8).collect();
+    let mut info = ObjInfo { full_path: "/file".to_string(), sha512: hex::encode(hmac_sha512::Hash::hash(&content)), size: content.len() as u64, ..ObjInfo::default() };
+
+    storage.put(&mut info, &content).unwrap();
+    assert!(storage.partial_reads(&info));
+    assert_eq!(storage.get(&info).unwrap(), content);
 }
@@ -1,16 +1,18 @@
 use crate::config::StorageConfig;
-use crate::obj_storage::{ObjInfo, ObjectStorage, UniquenessTest};
+use crate::obj_storage::{ObjEntry, ObjInfo, ObjectStorage, UniquenessTest};
 use crate::storage::ObjInUseFn;
 use crate::AnyError;
 use anyhow::{anyhow, Context};
 use log::{debug, error};
-use std::fs;
-use std::path::PathBuf;
-use std::rc::Rc;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 pub struct FsObjectStorage {
     pub base_path: PathBuf,
-    pub config: Rc<StorageConfig>,
+    pub config: Arc<StorageConfig>,
 }
 
 impl FsObjectStorage {
@@ -19,27 +21,61 @@ impl FsObjectStorage {
         path.push(self.config.path_of(&info));
         path
     }
+
+    fn list_dir(&self, dir: &Path, result: &mut Vec<ObjEntry>) -> Result<(), AnyError> {
+        for entry in fs::read_dir(dir).context("FS failed to read dir")? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+
+            if meta.is_dir() {
+                self.list_dir(&entry.path(), result)?;
+            } else {
+                let key = entry.path().strip_prefix(&self.base_path)?.to_string_lossy().to_string();
+                let modified_at = meta.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).ok();
+                result.push(ObjEntry { key, size: meta.len(), modified_at });
+            }
+        }
+        Ok(())
+    }
 }
 
 impl ObjectStorage for FsObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
         let path = self.path(&info);
         debug!("Get: {:?}", &path);
 
         fs::read(&path).context("FS failed to read file")
     }
 
-    fn put(&mut self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        let path = self.path(info);
+        debug!("Get range: {:?} ({} + {})", &path, offset, len);
+
+        let mut file = File::open(&path).context("FS failed to open file")?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![];
+        file.take(len).read_to_end(&mut data).context("FS failed to read file")?;
+        Ok(data)
+    }
+
+    fn partial_reads(&self, _info: &ObjInfo) -> bool {
+        true
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
         let path = self.path(&info);
         debug!("Put: {:?}", &path);
 
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent).context("FS failed to create dir")?;
         }
-        fs::write(&path, content).context("FS failed to write file")
+        fs::write(&path, content).context("FS failed to write file")?;
+        info.stored_size = content.len() as u64;
+        Ok(())
     }
 
-    fn remove(&mut self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
         let path = self.path(&info);
         let test = if self.config.use_hash_as_filename {
             UniquenessTest::Sha512
@@ -52,6 +88,11 @@ impl ObjectStorage for FsObjectStorage {
             return Ok(());
         }
 
+        // Noop, already removed, a file stored and removed before the cleanup queues the same path twice
+        if fs::metadata(&path).is_err() {
+            return Ok(());
+        }
+
         debug!("Remove: {:?}", &path);
 
         fs::remove_file(&path).map_err(|e| {
@@ -59,7 +100,7 @@ impl ObjectStorage for FsObjectStorage {
         })
     }
 
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
         let prev_path = self.path(&prev_info);
         let new_path = self.path(&new_info);
 
@@ -82,7 +123,7 @@ impl ObjectStorage for FsObjectStorage {
         })
     }
 
-    fn nuke(&mut self) -> Result<(), AnyError> {
+    fn nuke(&self) -> Result<(), AnyError> {
         debug!("Nuke: {:?}", &self.base_path);
 
         for entry_res in fs::read_dir(&self.base_path)? {
@@ -109,4 +150,25 @@ impl ObjectStorage for FsObjectStorage {
 
         Ok(())
     }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        let mut result = vec![];
+        if fs::metadata(&self.base_path).is_ok() {
+            self.list_dir(&self.base_path, &mut result)?;
+        }
+        Ok(result)
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        Ok(self.config.path_of(info))
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        let path = self.base_path.join(key);
+        debug!("Remove: {:?}", &path);
+
+        fs::remove_file(&path).map_err(|e| {
+            anyhow!("FS failed to remove file '{:?}': {:?}", path, e)
+        })
+    }
 }
\ No newline at end of file
@@ -1,16 +1,22 @@
 use crate::config::{StorageConfig, StorageOption};
 use crate::metadata_db::{FileRow, MetadataDB};
+use crate::obj_storage::blocking_object_storage::BlockingObjectStorage;
+use crate::obj_storage::chaos_object_storage::ChaosObjectStorage;
 use crate::obj_storage::compressed_object_storage::CompressedObjectStorage;
 use crate::obj_storage::encrypted_object_storage::EncryptedObjectStorage;
 use crate::obj_storage::fs_object_storage::FsObjectStorage;
+use crate::obj_storage::retrying_object_storage::{RetryPolicy, RetryingObjectStorage};
 use crate::obj_storage::rocks_db_object_storage::RocksDbObjectStorage;
 use crate::obj_storage::s3_object_storage::S3ObjectStorage;
 use crate::obj_storage::sqlar_object_storage::SqlarObjectStorage;
 use crate::storage::ObjInUseFn;
 use crate::AnyError;
 use std::fmt::Display;
+use std::future::Future;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::{Builder, Runtime};
 
 // Storage backends
 pub mod fs_object_storage;
@@ -20,11 +26,15 @@ pub mod rocks_db_object_storage;
 pub mod debug_object_storage;
 
 // Wrappers
+pub mod blocking_object_storage;
+pub mod cached_object_storage;
 pub mod encrypted_object_storage;
 pub mod replicated_object_storage;
 pub mod compressed_object_storage;
+pub mod retrying_object_storage;
+pub mod chaos_object_storage;
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct ObjInfo {
     pub name: String,
     pub full_path: String,
@@ -34,10 +44,21 @@ pub struct ObjInfo {
     pub updated_at: i64,
     pub mode: u32,
     pub size: u64,
+    // Bytes used by the object in the storage backend, set by the backend on put
+    pub stored_size: u64,
     pub encryption_key: String,
     pub compression: String,
 }
 
+/// Object as stored in a backend, identified by the backend specific key
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct ObjEntry {
+    pub key: String,
+    pub size: u64,
+    // Unix time in seconds of the last write, for the backends that keep it
+    pub modified_at: Option<i64>,
+}
+
 /// Method to test is a file exists, to handle deletion of de-duplicated files.
 /// When multiple files share the same object in storage, we need to check if the object is still
 /// being used by any other file before deleting it.
@@ -49,12 +70,75 @@ pub enum UniquenessTest {
     Sha512,
 }
 
-pub trait ObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, AnyError>;
-    fn put(&mut self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError>;
-    fn remove(&mut self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError>;
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError>;
-    fn nuke(&mut self) -> Result<(), AnyError>;
+pub trait ObjectStorage: Send + Sync {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError>;
+    // Bytes of the stored object from the offset, fewer than requested at the end of the object.
+    // Backends that can read part of an object without fetching the rest should implement it.
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        Ok(slice_range(&self.get(info)?, offset, len))
+    }
+    // Whether get_range fetches only the requested part of this object, instead of the whole of it
+    fn partial_reads(&self, _info: &ObjInfo) -> bool {
+        false
+    }
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError>;
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError>;
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError>;
+    fn nuke(&self) -> Result<(), AnyError>;
+    // Listing and removal by key, used to find objects not referenced by any file
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError>;
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError>;
+    fn remove_key(&self, key: &str) -> Result<(), AnyError>;
+}
+
+/// Future returned by the asynchronous storage methods
+pub type ObjFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, AnyError>> + Send + 'a>>;
+
+/// Asynchronous variant of ObjectStorage, so several requests can be in flight at the same time.
+/// Synchronous backends are adapted with BlockingObjectStorage.
+pub trait AsyncObjectStorage: Send + Sync {
+    fn get<'a>(&'a self, info: &'a ObjInfo) -> ObjFuture<'a, Vec<u8>>;
+    fn put<'a>(&'a self, info: &'a mut ObjInfo, content: &'a [u8]) -> ObjFuture<'a, ()>;
+    fn remove<'a>(&'a self, info: &'a ObjInfo, is_in_use: ObjInUseFn) -> ObjFuture<'a, ()>;
+    fn rename<'a>(&'a self, prev_info: &'a ObjInfo, new_info: &'a ObjInfo) -> ObjFuture<'a, ()>;
+    fn nuke(&self) -> ObjFuture<'_, ()>;
+}
+
+/// Error of a backend that may not happen again if the operation is retried, like a timeout or a
+/// throttled request. RetryingObjectStorage repeats the operations that fail with it.
+#[derive(Debug)]
+pub struct TransientError(pub AnyError);
+
+impl Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TransientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Part of the contents of a whole object, as returned by get_range
+pub fn slice_range(data: &[u8], offset: u64, len: u64) -> Vec<u8> {
+    let start = offset.min(data.len() as u64) as usize;
+    let end = offset.saturating_add(len).min(data.len() as u64) as usize;
+    data[start..end].to_vec()
+}
+
+/// Runtime shared by the asynchronous storages, requests sent from different threads run at the same time
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_time()
+            .enable_io()
+            .build()
+            .expect("Unable to start the storage runtime")
+    })
 }
 
 impl Display for ObjInfo {
@@ -74,13 +158,33 @@ impl ObjInfo {
             updated_at: file.updated_at,
             mode: file.perms as u32,
             size: file.size as u64,
+            stored_size: file.stored_size as u64,
             encryption_key: file.encryption_key.to_string(),
             compression: file.compression.to_string(),
         }
     }
 }
 
-pub fn create_object_storage(config: Rc<StorageConfig>, sql: Rc<MetadataDB>) -> Box<dyn ObjectStorage> {
+/// Asynchronous version of the storage described by the config. S3 is used directly when no wrapper
+/// is needed, other backends run in blocking threads.
+pub fn create_async_object_storage(config: Arc<StorageConfig>, sql: Arc<MetadataDB>) -> Box<dyn AsyncObjectStorage> {
+    let is_wrapped = !config.encryption_key.is_empty() || config.compression_level > 0 || config.chaos.is_some();
+
+    if config.storage_backend == StorageOption::S3 && !is_wrapped {
+        let policy = RetryPolicy::new(&config);
+        return Box::new(RetryingObjectStorage::<dyn AsyncObjectStorage>::new(Box::new(S3ObjectStorage::new(config)), policy));
+    }
+
+    Box::new(BlockingObjectStorage::new(Arc::from(create_object_storage(config, sql))))
+}
+
+pub fn create_object_storage(config: Arc<StorageConfig>, sql: Arc<MetadataDB>) -> Box<dyn ObjectStorage> {
+    let backend = create_backend_object_storage(config.clone(), sql);
+    wrap_object_storage(config, backend)
+}
+
+/// Backend of the config, with the injected faults and retries, it reads and writes the objects as they are stored
+pub fn create_backend_object_storage(config: Arc<StorageConfig>, sql: Arc<MetadataDB>) -> Box<dyn ObjectStorage> {
     let mut obj_storage: Box<dyn ObjectStorage> = match &config.storage_backend {
         StorageOption::FileSystem => {
             Box::new(FsObjectStorage {
@@ -102,6 +206,18 @@ pub fn create_object_storage(config: Rc<StorageConfig>, sql: Rc<MetadataDB>) ->
         }
     };
 
+    // Retry the backend operations, so wrappers don't encrypt or compress the content again
+    obj_storage = Box::new(RetryingObjectStorage::new(obj_storage, RetryPolicy::new(&config)));
+
+    if let Some(chaos) = &config.chaos {
+        // Injected faults are not retried, they reach the replicas, verify and the mount
+        obj_storage = Box::new(ChaosObjectStorage::new(obj_storage, chaos.clone()));
+    }
+    obj_storage
+}
+
+/// Encryption or compression of the contents stored in a backend
+pub fn wrap_object_storage(config: Arc<StorageConfig>, mut obj_storage: Box<dyn ObjectStorage>) -> Box<dyn ObjectStorage> {
     if !config.encryption_key.is_empty() {
         // Apply encryption if a key is provided
         obj_storage = Box::new(EncryptedObjectStorage::new(config.clone(), obj_storage));
@@ -1,46 +1,81 @@
+use futures_util::future::{join, join_all};
 use crate::AnyError;
-use crate::obj_storage::{ObjInfo, ObjectStorage};
+use crate::obj_storage::blocking_object_storage::BlockingObjectStorage;
+use crate::obj_storage::{runtime, AsyncObjectStorage, ObjEntry, ObjInfo, ObjectStorage};
 use crate::storage::ObjInUseFn;
 
+/// Writes go to the primary and all the replicas at the same time, reads only use the primary
 pub struct ReplicatedObjectStorage {
-    pub primary: Box<dyn ObjectStorage>,
-    pub replicas: Vec<Box<dyn ObjectStorage>>,
+    pub primary: BlockingObjectStorage,
+    pub replicas: Vec<Box<dyn AsyncObjectStorage>>,
+}
+
+impl ReplicatedObjectStorage {
+    /// Wait for the primary and every replica, the first error reported is the one of the primary
+    fn all(primary: Result<(), AnyError>, replicas: Vec<Result<(), AnyError>>) -> Result<(), AnyError> {
+        primary?;
+        replicas.into_iter().collect()
+    }
 }
 
 impl ObjectStorage for ReplicatedObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
-        self.primary.get(info)
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+        self.primary.inner.get(info)
+    }
+
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        self.primary.inner.get_range(info, offset, len)
+    }
+
+    fn partial_reads(&self, info: &ObjInfo) -> bool {
+        self.primary.inner.partial_reads(info)
     }
 
-    fn put(&mut self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
-        self.primary.put(info, content)?;
-        for replica in &mut self.replicas {
-            replica.put(info, content)?;
-        }
-        Ok(())
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+        // The index tracks the stored size of the primary, that is the one used for reads
+        let mut replica_infos = vec![info.clone(); self.replicas.len()];
+
+        let (primary, replicas) = runtime().block_on(join(
+            self.primary.put(info, content),
+            join_all(self.replicas.iter().zip(replica_infos.iter_mut()).map(|(replica, info)| replica.put(info, content))),
+        ));
+        Self::all(primary, replicas)
     }
 
-    fn remove(&mut self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
-        self.primary.remove(info, is_in_use.clone())?;
-        for replica in &mut self.replicas {
-            replica.remove(info, is_in_use.clone())?;
-        }
-        Ok(())
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+        let (primary, replicas) = runtime().block_on(join(
+            self.primary.remove(info, is_in_use.clone()),
+            join_all(self.replicas.iter().map(|replica| replica.remove(info, is_in_use.clone()))),
+        ));
+        Self::all(primary, replicas)
     }
 
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
-        self.primary.rename(prev_info, new_info)?;
-        for replica in &mut self.replicas {
-            replica.rename(prev_info, new_info)?;
-        }
-        Ok(())
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+        let (primary, replicas) = runtime().block_on(join(
+            self.primary.rename(prev_info, new_info),
+            join_all(self.replicas.iter().map(|replica| replica.rename(prev_info, new_info))),
+        ));
+        Self::all(primary, replicas)
     }
 
-    fn nuke(&mut self) -> Result<(), AnyError> {
-        self.primary.nuke()?;
-        for replica in &mut self.replicas {
-            replica.nuke()?;
-        }
-        Ok(())
+    fn nuke(&self) -> Result<(), AnyError> {
+        let (primary, replicas) = runtime().block_on(join(
+            self.primary.nuke(),
+            join_all(self.replicas.iter().map(|replica| replica.nuke())),
+        ));
+        Self::all(primary, replicas)
     }
-}
\ No newline at end of file
+
+    // Keys are backend specific, replicas must be listed through their own storage
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        self.primary.inner.list()
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        self.primary.inner.key(info)
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        self.primary.inner.remove_key(key)
+    }
+}
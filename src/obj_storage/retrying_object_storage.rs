@@ -0,0 +1,279 @@
+use std::future::Future;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::AnyError;
+use crate::config::StorageConfig;
+use crate::obj_storage::{AsyncObjectStorage, ObjEntry, ObjFuture, ObjInfo, ObjectStorage, TransientError};
+use crate::storage::ObjInUseFn;
+
+const SQLITE_BUSY: isize = 5;
+const SQLITE_LOCKED: isize = 6;
+
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static RECOVERED: AtomicU64 = AtomicU64::new(0);
+static EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the retried operations of every storage, since the process started
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetryStats {
+    // Attempts repeated after a transient error
+    pub retries: u64,
+    // Operations that succeeded after being retried
+    pub recovered: u64,
+    // Operations that still failed with a transient error after the last attempt
+    pub exhausted: u64,
+}
+
+pub fn retry_stats() -> RetryStats {
+    RetryStats {
+        retries: RETRIES.load(Ordering::Relaxed),
+        recovered: RECOVERED.load(Ordering::Relaxed),
+        exhausted: EXHAUSTED.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether an operation that failed with the error may succeed if it's tried again:
+/// - S3: errors marked by the backend, timeouts, connection failures, throttling and 5xx responses
+/// - filesystem: interrupted calls, EAGAIN, EBUSY and timeouts
+/// - sqlar: database busy or locked
+/// - rocksdb: busy, timed out or try again
+pub fn is_transient(error: &AnyError) -> bool {
+    error.chain().any(|cause| {
+        if cause.is::<TransientError>() {
+            return true;
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                || e.raw_os_error() == Some(libc::EBUSY);
+        }
+        if let Some(e) = cause.downcast_ref::<sqlite::Error>() {
+            return matches!(e.code, Some(SQLITE_BUSY | SQLITE_LOCKED));
+        }
+        if let Some(e) = cause.downcast_ref::<rocksdb::Error>() {
+            return matches!(e.kind(), rocksdb::ErrorKind::Busy | rocksdb::ErrorKind::TimedOut | rocksdb::ErrorKind::TryAgain);
+        }
+        false
+    })
+}
+
+/// How many times an operation is tried, and how long to wait between attempts
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &StorageConfig) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: config.retry_max_attempts,
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+        }
+    }
+
+    /// Exponential backoff, half of it random so clients that failed together don't retry together
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(1 << (attempt - 1).min(20)).min(self.max_delay);
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen::<f64>() / 2.0)
+    }
+
+    /// Time to wait before the next attempt, or the error if it must be returned
+    fn backoff(&self, operation: &str, attempt: u32, error: AnyError) -> Result<Duration, AnyError> {
+        if !is_transient(&error) {
+            return Err(error);
+        }
+        if attempt >= self.max_attempts {
+            if self.max_attempts > 1 {
+                EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+            }
+            return Err(error);
+        }
+
+        let delay = self.delay(attempt);
+        warn!("{} failed (attempt {} of {}), retrying in {:?}: {}", operation, attempt, self.max_attempts, delay, error);
+        RETRIES.fetch_add(1, Ordering::Relaxed);
+        Ok(delay)
+    }
+
+    fn succeeded(&self, attempt: u32) {
+        if attempt > 1 {
+            RECOVERED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Repeats the operations of a backend that fail with transient errors, instead of returning EIO to the
+/// filesystem. It wraps the backends directly, so the encrypted or compressed bytes are only built once.
+pub struct RetryingObjectStorage<T: ?Sized> {
+    pub proxy: Box<T>,
+    pub policy: RetryPolicy,
+}
+
+impl<T: ?Sized> RetryingObjectStorage<T> {
+    pub fn new(proxy: Box<T>, policy: RetryPolicy) -> RetryingObjectStorage<T> {
+        RetryingObjectStorage { proxy, policy }
+    }
+
+    fn retry<R>(&self, operation: &str, mut run: impl FnMut() -> Result<R, AnyError>) -> Result<R, AnyError> {
+        let mut attempt = 1;
+        loop {
+            match run() {
+                Ok(result) => {
+                    self.policy.succeeded(attempt);
+                    return Ok(result);
+                }
+                Err(e) => thread::sleep(self.policy.backoff(operation, attempt, e)?),
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn retry_async<R, F>(&self, operation: &str, mut run: impl FnMut() -> F) -> Result<R, AnyError>
+    where
+        F: Future<Output = Result<R, AnyError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match run().await {
+                Ok(result) => {
+                    self.policy.succeeded(attempt);
+                    return Ok(result);
+                }
+                Err(e) => tokio::time::sleep(self.policy.backoff(operation, attempt, e)?).await,
+            }
+            attempt += 1;
+        }
+    }
+}
+
+impl ObjectStorage for RetryingObjectStorage<dyn ObjectStorage> {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+        self.retry("Get", || self.proxy.get(info))
+    }
+
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        self.retry("Get range", || self.proxy.get_range(info, offset, len))
+    }
+
+    fn partial_reads(&self, info: &ObjInfo) -> bool {
+        self.proxy.partial_reads(info)
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+        self.retry("Put", || self.proxy.put(info, content))
+    }
+
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+        self.retry("Remove", || self.proxy.remove(info, is_in_use.clone()))
+    }
+
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+        self.retry("Rename", || self.proxy.rename(prev_info, new_info))
+    }
+
+    fn nuke(&self) -> Result<(), AnyError> {
+        self.retry("Nuke", || self.proxy.nuke())
+    }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        self.retry("List", || self.proxy.list())
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        self.proxy.key(info)
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        self.retry("Remove", || self.proxy.remove_key(key))
+    }
+}
+
+impl AsyncObjectStorage for RetryingObjectStorage<dyn AsyncObjectStorage> {
+    fn get<'a>(&'a self, info: &'a ObjInfo) -> ObjFuture<'a, Vec<u8>> {
+        Box::pin(self.retry_async("Get", move || self.proxy.get(info)))
+    }
+
+    fn put<'a>(&'a self, info: &'a mut ObjInfo, content: &'a [u8]) -> ObjFuture<'a, ()> {
+        // The future of each attempt borrows the info, so it can't be built by a closure
+        Box::pin(async move {
+            let mut attempt = 1;
+            loop {
+                match self.proxy.put(info, content).await {
+                    Ok(()) => {
+                        self.policy.succeeded(attempt);
+                        return Ok(());
+                    }
+                    Err(e) => tokio::time::sleep(self.policy.backoff("Put", attempt, e)?).await,
+                }
+                attempt += 1;
+            }
+        })
+    }
+
+    fn remove<'a>(&'a self, info: &'a ObjInfo, is_in_use: ObjInUseFn) -> ObjFuture<'a, ()> {
+        Box::pin(self.retry_async("Remove", move || self.proxy.remove(info, is_in_use.clone())))
+    }
+
+    fn rename<'a>(&'a self, prev_info: &'a ObjInfo, new_info: &'a ObjInfo) -> ObjFuture<'a, ()> {
+        Box::pin(self.retry_async("Rename", move || self.proxy.rename(prev_info, new_info)))
+    }
+
+    fn nuke(&self) -> ObjFuture<'_, ()> {
+        Box::pin(self.retry_async("Nuke", move || self.proxy.nuke()))
+    }
+}
+
+#[test]
+fn test_retry_policy() {
+    let policy = RetryPolicy { max_attempts: 4, base_delay: Duration::from_millis(100), max_delay: Duration::from_millis(1000) };
+    for (attempt, full) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+        let delay = policy.delay(attempt);
+        assert!(delay >= Duration::from_millis(full / 2) && delay <= Duration::from_millis(full), "{:?}", delay);
+    }
+
+    let eagain: AnyError = std::io::Error::from_raw_os_error(libc::EAGAIN).into();
+    let not_found: AnyError = std::io::Error::from(ErrorKind::NotFound).into();
+    let busy: AnyError = sqlite::Error { code: Some(SQLITE_BUSY), message: None }.into();
+    assert!(is_transient(&eagain));
+    assert!(is_transient(&busy));
+    assert!(is_transient(&TransientError(anyhow::anyhow!("503")).into()));
+    assert!(!is_transient(&not_found));
+    assert!(!is_transient(&anyhow::anyhow!("Decryption failed")));
+}
+
+#[test]
+fn test_retry() {
+    let policy = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1) };
+    let storage = RetryingObjectStorage::new(Box::new(()), policy);
+
+    let mut attempts = 0;
+    let result = storage.retry("Get", || {
+        attempts += 1;
+        match attempts {
+            1 | 2 => Err(TransientError(anyhow::anyhow!("Timeout")).into()),
+            _ => Ok(attempts),
+        }
+    });
+    assert_eq!(result.unwrap(), 3);
+
+    attempts = 0;
+    let result: Result<(), AnyError> = storage.retry("Get", || {
+        attempts += 1;
+        Err(TransientError(anyhow::anyhow!("Timeout")).into())
+    });
+    assert!(result.is_err() && attempts == 3);
+
+    attempts = 0;
+    let result: Result<(), AnyError> = storage.retry("Get", || {
+        attempts += 1;
+        Err(anyhow::anyhow!("Not found"))
+    });
+    assert!(result.is_err() && attempts == 1);
+}
@@ -1,21 +1,21 @@
 use crate::config::StorageConfig;
-use crate::obj_storage::{ObjInfo, ObjectStorage, UniquenessTest};
+use crate::obj_storage::{slice_range, ObjEntry, ObjInfo, ObjectStorage, UniquenessTest};
 use crate::storage::ObjInUseFn;
 use crate::AnyError;
 use log::{debug};
-use rocksdb::{DBWithThreadMode, Options, SingleThreaded, DB};
-use std::rc::Rc;
+use rocksdb::{DBWithThreadMode, IteratorMode, MultiThreaded, Options};
+use std::sync::Arc;
 
 pub struct RocksDbObjectStorage {
-    db: DBWithThreadMode<SingleThreaded>,
-    config: Rc<StorageConfig>,
+    db: DBWithThreadMode<MultiThreaded>,
+    config: Arc<StorageConfig>,
 }
 
 impl RocksDbObjectStorage {
-    pub fn new(config: Rc<StorageConfig>) -> RocksDbObjectStorage {
+    pub fn new(config: Arc<StorageConfig>) -> RocksDbObjectStorage {
         let mut opts = Options::default();
         opts.create_if_missing(true);
-        let db = DB::open_cf(&opts, &config.blob_storage, ["default"]).unwrap();
+        let db = DBWithThreadMode::open_cf(&opts, &config.blob_storage, ["default"]).unwrap();
         RocksDbObjectStorage { db, config }
     }
 
@@ -29,7 +29,7 @@ impl RocksDbObjectStorage {
 }
 
 impl ObjectStorage for RocksDbObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
         let path = self.path(info);
         debug!("Get: {:?}", &path);
 
@@ -39,15 +39,31 @@ impl ObjectStorage for RocksDbObjectStorage {
         }
     }
 
-    fn put(&mut self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        let path = self.path(info);
+        debug!("Get range: {:?} ({} + {})", &path, offset, len);
+
+        // The pinned value is not copied, only the requested part
+        match self.db.get_pinned(&path)? {
+            Some(v) => Ok(slice_range(&v, offset, len)),
+            None => Err(AnyError::msg("Object not found")),
+        }
+    }
+
+    fn partial_reads(&self, _info: &ObjInfo) -> bool {
+        true
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
         let path = self.path(info);
         debug!("Put: {:?}", &path);
 
         self.db.put(&path, content)?;
+        info.stored_size = content.len() as u64;
         Ok(())
     }
 
-    fn remove(&mut self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
         let path = self.path(info);
         let test = if self.config.use_hash_as_filename {
             UniquenessTest::Sha512
@@ -66,7 +82,7 @@ impl ObjectStorage for RocksDbObjectStorage {
         Ok(())
     }
 
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
         let prev_path = self.path(prev_info);
         let new_path = self.path(new_info);
 
@@ -82,9 +98,32 @@ impl ObjectStorage for RocksDbObjectStorage {
         Ok(())
     }
 
-    fn nuke(&mut self) -> Result<(), AnyError> {
+    fn nuke(&self) -> Result<(), AnyError> {
         debug!("Nuke");
         self.db.drop_cf("default")?;
         Ok(())
     }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        let mut result = vec![];
+        for item in self.db.iterator(IteratorMode::Start) {
+            let (key, value) = item?;
+            result.push(ObjEntry {
+                key: String::from_utf8_lossy(&key).to_string(),
+                size: value.len() as u64,
+                modified_at: None,
+            });
+        }
+        Ok(result)
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        Ok(self.path(info))
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        debug!("Remove: {:?}", key);
+        self.db.delete(key)?;
+        Ok(())
+    }
 }
\ No newline at end of file
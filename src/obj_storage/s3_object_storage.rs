@@ -1,42 +1,157 @@
-use crate::config::StorageConfig;
-use crate::obj_storage::{ObjInfo, ObjectStorage, UniquenessTest};
+use crate::config::{S3Encryption, StorageConfig};
+use crate::obj_storage::{runtime, AsyncObjectStorage, ObjEntry, ObjFuture, ObjInfo, ObjectStorage, TransientError, UniquenessTest};
 use crate::storage::ObjInUseFn;
 use crate::AnyError;
 use anyhow::{anyhow, Error};
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::retry::RetryConfig;
+use aws_sdk_s3::config::timeout::TimeoutConfig;
 use aws_sdk_s3::config::{Credentials, SharedCredentialsProvider};
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
 use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::{Delete, ObjectIdentifier};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier, ServerSideEncryption, StorageClass};
 use aws_sdk_s3::Client;
+use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
+use aws_smithy_runtime_api::client::http::SharedHttpClient;
+use aws_smithy_runtime_api::client::orchestrator::HttpResponse;
 use aws_types::region::Region;
-use log::{debug};
-use std::rc::Rc;
-use tokio::runtime::{Builder, Runtime};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use log::{debug, warn};
+use md5::{Digest, Md5};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Region used to sign the requests when neither the config nor the environment have one
+const DEFAULT_REGION: &str = "us-east-1";
+// Largest number of parts of a multipart upload
+const MAX_PARTS: u64 = 10_000;
+// Error codes of S3 for requests that may succeed later
+const TRANSIENT_ERROR_CODES: [&str; 5] = ["SlowDown", "RequestTimeout", "RequestTimeTooSkewed", "InternalError", "ServiceUnavailable"];
+
+/// Error of a request, marked as transient if it may succeed when sent again: timeouts, connection
+/// failures, throttling and server errors. Requests that S3 rejected, like a missing object, are not.
+fn request_error<E>(error: SdkError<E, HttpResponse>) -> Error
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    let transient = match &error {
+        SdkError::TimeoutError(_) | SdkError::ResponseError(_) => true,
+        SdkError::DispatchFailure(failure) => !failure.is_user(),
+        SdkError::ServiceError(service) => {
+            let status = service.raw().status().as_u16();
+            status >= 500 || status == 429
+                || service.err().code().is_some_and(|code| TRANSIENT_ERROR_CODES.contains(&code))
+        }
+        _ => false,
+    };
+
+    if transient {
+        Error::from(TransientError(error.into()))
+    } else {
+        error.into()
+    }
+}
 
 pub struct S3ObjectStorage {
-    pub config: Rc<StorageConfig>,
+    pub config: Arc<StorageConfig>,
     pub client: Client,
-    pub rt: Runtime,
+    // SSE-C key and its MD5, both in base64 as sent in the headers
+    sse_customer_key: Option<(String, String)>,
 }
 
 impl S3ObjectStorage {
-    pub fn new(config: Rc<StorageConfig>) -> Self {
-        let rt = Builder::new_current_thread()
-            .enable_time()
-            .enable_io()
-            .build()
-            .unwrap();
-
-        let creds = Credentials::new(&config.s3_access_key, &config.s3_secret_key, None, None, "config.yml");
-
-        let s3_config = aws_types::sdk_config::Builder::default()
-            .region(Region::new(config.s3_region.to_string()))
-            .endpoint_url(config.s3_endpoint_url.to_string())
-            .credentials_provider(SharedCredentialsProvider::new(creds))
+    pub fn new(config: Arc<StorageConfig>) -> Self {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+
+        // Keys in the config take precedence, otherwise the standard chain is used: environment variables,
+        // `~/.aws` profiles, web identity tokens and instance metadata
+        if !config.s3_access_key.is_empty() {
+            let session_token = Some(config.s3_session_token.clone()).filter(|token| !token.is_empty());
+            let creds = Credentials::new(&config.s3_access_key, &config.s3_secret_key, session_token, None, "config.yml");
+            loader = loader.credentials_provider(SharedCredentialsProvider::new(creds));
+        }
+        if !config.s3_profile.is_empty() {
+            loader = loader.profile_name(&config.s3_profile);
+        }
+        if !config.s3_region.is_empty() {
+            loader = loader.region(Region::new(config.s3_region.to_string()));
+        }
+        if !config.s3_endpoint_url.is_empty() {
+            loader = loader.endpoint_url(&config.s3_endpoint_url);
+        }
+        // Also used to get the credentials, the token services may be behind the same proxy
+        if !config.s3_ca_bundle.is_empty() {
+            loader = loader.http_client(Self::http_client(&config.s3_ca_bundle).expect("Unable to load the S3 CA bundle"));
+        }
+
+        let sdk_config = runtime().block_on(loader.load());
+        // Requests are retried by RetryingObjectStorage, with the same policy as the other backends
+        let mut timeout_config = TimeoutConfig::builder();
+        if config.operation_timeout_secs > 0 {
+            timeout_config = timeout_config.operation_attempt_timeout(Duration::from_secs(config.operation_timeout_secs));
+        }
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(timeout_config.build())
+            .force_path_style(config.s3_force_path_style);
+        if sdk_config.region().is_none() {
+            s3_config = s3_config.region(Region::new(DEFAULT_REGION));
+        }
+
+        let sse_customer_key = (config.s3_server_side_encryption == S3Encryption::CustomerKey).then(|| {
+            let key = config.s3_sse_customer_key.as_bytes();
+            (BASE64.encode(key), BASE64.encode(Md5::digest(key)))
+        });
+
+        let client = Client::from_conf(s3_config.build());
+
+        S3ObjectStorage { config, client, sse_customer_key }
+    }
+
+    /// HTTPS client trusting the certificates of the bundle, besides the ones of the system
+    fn http_client(ca_bundle: &str) -> Result<SharedHttpClient, AnyError> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs()? {
+            // Certificates of the system that rustls can't parse are skipped, like it does by default
+            let _ = roots.add(&rustls::Certificate(cert.0));
+        }
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca_bundle)?))? {
+            roots.add(&rustls::Certificate(cert))?;
+        }
+
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
             .build();
 
-        let client = Client::new(&s3_config);
+        Ok(HyperClientBuilder::new().build(connector))
+    }
+
+    /// Algorithm, key and key MD5 of the SSE-C headers, required to write and read the objects
+    fn sse_customer(&self) -> (Option<String>, Option<String>, Option<String>) {
+        match &self.sse_customer_key {
+            Some((key, key_md5)) => (Some("AES256".to_string()), Some(key.clone()), Some(key_md5.clone())),
+            None => (None, None, None),
+        }
+    }
+
+    fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        (self.config.s3_server_side_encryption == S3Encryption::S3Managed).then_some(ServerSideEncryption::Aes256)
+    }
 
-        S3ObjectStorage { config, client, rt }
+    fn storage_class(&self) -> Option<StorageClass> {
+        Some(self.config.s3_storage_class.as_str()).filter(|class| !class.is_empty()).map(StorageClass::from)
     }
 
     pub fn path(&self, info: &ObjInfo) -> String {
@@ -45,95 +160,280 @@ impl S3ObjectStorage {
         let filename = path.trim_start_matches('/');
         format!("{}/{}", basename, filename).trim_matches('/').to_string()
     }
+
+    /// Read the body as it arrives, instead of collecting the chunks and copying them afterwards
+    async fn read_body(res: GetObjectOutput) -> Result<Vec<u8>, Error> {
+        let mut content = Vec::with_capacity(res.content_length().unwrap_or(0).max(0) as usize);
+        let mut body = res.body;
+
+        // The connection may be closed in the middle of the body
+        while let Some(bytes) = body.try_next().await.map_err(|e| TransientError(e.into()))? {
+            content.extend_from_slice(&bytes);
+        }
+        Ok(content)
+    }
+
+    async fn upload_part(&self, path: &str, upload_id: &str, part_number: i32, part: &[u8]) -> Result<CompletedPart, Error> {
+        let bucket_name = &self.config.s3_bucket;
+        debug!("Put part: {:?} {} ({:?})", path, part_number, bucket_name);
+        let (sse_algorithm, sse_key, sse_key_md5) = self.sse_customer();
+
+        let res = self.client
+            .upload_part()
+            .bucket(bucket_name)
+            .key(path)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .set_sse_customer_algorithm(sse_algorithm)
+            .set_sse_customer_key(sse_key)
+            .set_sse_customer_key_md5(sse_key_md5)
+            .body(ByteStream::from(part.to_vec()))
+            .send().await.map_err(request_error)?;
+
+        Ok(CompletedPart::builder()
+            .set_e_tag(res.e_tag().map(str::to_string))
+            .part_number(part_number)
+            .build())
+    }
+
+    async fn upload_part_copy(&self, source: &str, path: &str, upload_id: &str, part_number: i32, range: (u64, u64)) -> Result<CompletedPart, Error> {
+        let bucket_name = &self.config.s3_bucket;
+        debug!("Copy part: {:?} -> {:?} {} ({:?})", source, path, part_number, bucket_name);
+        let (sse_algorithm, sse_key, sse_key_md5) = self.sse_customer();
+
+        let res = self.client
+            .upload_part_copy()
+            .bucket(bucket_name)
+            .copy_source(format!("{}/{}", bucket_name, source))
+            .copy_source_range(format!("bytes={}-{}", range.0, range.1 - 1))
+            .key(path)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .set_copy_source_sse_customer_algorithm(sse_algorithm.clone())
+            .set_copy_source_sse_customer_key(sse_key.clone())
+            .set_copy_source_sse_customer_key_md5(sse_key_md5.clone())
+            .set_sse_customer_algorithm(sse_algorithm)
+            .set_sse_customer_key(sse_key)
+            .set_sse_customer_key_md5(sse_key_md5)
+            .send().await.map_err(request_error)?;
+
+        Ok(CompletedPart::builder()
+            .set_e_tag(res.copy_part_result().and_then(|result| result.e_tag()).map(str::to_string))
+            .part_number(part_number)
+            .build())
+    }
+
+    async fn create_multipart(&self, path: &str) -> Result<String, Error> {
+        let (sse_algorithm, sse_key, sse_key_md5) = self.sse_customer();
+        let upload = self.client
+            .create_multipart_upload()
+            .bucket(&self.config.s3_bucket)
+            .key(path)
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_sse_customer_algorithm(sse_algorithm)
+            .set_sse_customer_key(sse_key)
+            .set_sse_customer_key_md5(sse_key_md5)
+            .set_storage_class(self.storage_class())
+            .send().await.map_err(request_error)?;
+        upload.upload_id().map(str::to_string).ok_or_else(|| anyhow!("Failed to get upload id"))
+    }
+
+    async fn complete_multipart(&self, path: &str, upload_id: &str, parts: Result<Vec<CompletedPart>, Error>) -> Result<(), Error> {
+        let bucket_name = &self.config.s3_bucket;
+        let result = match parts {
+            Ok(parts) => self.client
+                .complete_multipart_upload()
+                .bucket(bucket_name)
+                .key(path)
+                .upload_id(upload_id)
+                .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                .send().await
+                .map(|_| ())
+                .map_err(request_error),
+            Err(e) => Err(e),
+        };
+
+        // The parts of an incomplete upload are kept, and billed, until it is aborted
+        if result.is_err() {
+            let abort = self.client
+                .abort_multipart_upload()
+                .bucket(bucket_name)
+                .key(path)
+                .upload_id(upload_id)
+                .send().await;
+
+            if let Err(e) = abort {
+                warn!("Unable to abort the upload of {:?} ({:?}): {}", path, bucket_name, e);
+            }
+        }
+        result
+    }
+
+    /// Upload an object in parts, a single request can't be larger than 5 GB.
+    /// Only the parts being sent are copied, at most `s3_upload_concurrency` of them at the same time.
+    async fn put_multipart(&self, path: &str, content: &[u8]) -> Result<(), Error> {
+        let upload_id = self.create_multipart(path).await?;
+
+        // Futures do nothing until polled, so the parts are only copied once their upload starts
+        let part_size = part_size(self.config.s3_part_size, content.len() as u64);
+        let uploads: Vec<_> = content.chunks(part_size as usize)
+            .enumerate()
+            .map(|(index, part)| self.upload_part(path, &upload_id, index as i32 + 1, part))
+            .collect();
+        let parts = stream::iter(uploads)
+            .buffered(self.config.s3_upload_concurrency)
+            .try_collect::<Vec<CompletedPart>>().await;
+
+        self.complete_multipart(path, &upload_id, parts).await
+    }
+
+    /// Copy an object in parts on the server, `copy_object` is limited to objects of 5 GB
+    async fn copy_multipart(&self, source: &str, path: &str, size: u64) -> Result<(), Error> {
+        let upload_id = self.create_multipart(path).await?;
+
+        let part_size = part_size(self.config.s3_part_size, size);
+        let copies: Vec<_> = (0..size).step_by(part_size as usize)
+            .enumerate()
+            .map(|(index, start)| self.upload_part_copy(source, path, &upload_id, index as i32 + 1, (start, size.min(start + part_size))))
+            .collect();
+        let parts = stream::iter(copies)
+            .buffered(self.config.s3_upload_concurrency)
+            .try_collect::<Vec<CompletedPart>>().await;
+
+        self.complete_multipart(path, &upload_id, parts).await
+    }
 }
 
-impl ObjectStorage for S3ObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, Error> {
+/// Size of the parts of an upload of `len` bytes, S3 accepts at most 10000 parts
+fn part_size(min_part_size: u64, len: u64) -> u64 {
+    min_part_size.max(len.div_ceil(MAX_PARTS)).max(1)
+}
+
+impl AsyncObjectStorage for S3ObjectStorage {
+    fn get<'a>(&'a self, info: &'a ObjInfo) -> ObjFuture<'a, Vec<u8>> {
         let path = self.path(info);
         let bucket_name = &self.config.s3_bucket;
         debug!("Get: {:?} ({:?})", &path, bucket_name);
 
-        self.rt.block_on(async {
+        Box::pin(async move {
+            let (sse_algorithm, sse_key, sse_key_md5) = self.sse_customer();
             let res = self.client
                 .get_object()
                 .bucket(bucket_name)
                 .key(&path)
-                .send().await?;
+                .set_sse_customer_algorithm(sse_algorithm)
+                .set_sse_customer_key(sse_key)
+                .set_sse_customer_key_md5(sse_key_md5)
+                .send().await.map_err(request_error)?;
 
-            let content = res.body.collect().await?.to_vec();
-            Ok(content)
+            Self::read_body(res).await
         })
     }
 
-    fn put(&mut self, info: &mut ObjInfo, content: &[u8]) -> Result<(), Error> {
+    fn put<'a>(&'a self, info: &'a mut ObjInfo, content: &'a [u8]) -> ObjFuture<'a, ()> {
         let path = self.path(info);
         let bucket_name = &self.config.s3_bucket;
         debug!("Put: {:?} ({:?})", &path, bucket_name);
 
-        self.rt.block_on(async {
-            self.client
-                .put_object()
-                .bucket(bucket_name)
-                .key(&path)
-                .body(ByteStream::from(content.to_vec()))
-                .send().await?;
+        Box::pin(async move {
+            if content.len() as u64 > self.config.s3_part_size {
+                self.put_multipart(&path, content).await?;
+            } else {
+                let (sse_algorithm, sse_key, sse_key_md5) = self.sse_customer();
+                self.client
+                    .put_object()
+                    .bucket(bucket_name)
+                    .key(&path)
+                    .set_server_side_encryption(self.server_side_encryption())
+                    .set_sse_customer_algorithm(sse_algorithm)
+                    .set_sse_customer_key(sse_key)
+                    .set_sse_customer_key_md5(sse_key_md5)
+                    .set_storage_class(self.storage_class())
+                    .body(ByteStream::from(content.to_vec()))
+                    .send().await.map_err(request_error)?;
+            }
 
+            info.stored_size = content.len() as u64;
             Ok(())
         })
     }
 
-    fn remove(&mut self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), Error> {
-        let test = if self.config.use_hash_as_filename {
-            UniquenessTest::Sha512
-        } else {
-            UniquenessTest::Path
-        };
-
-        // If is object in use by other file (deduplication), do not remove it
-        if is_in_use(info, test)? {
-            return Ok(());
-        }
+    fn remove<'a>(&'a self, info: &'a ObjInfo, is_in_use: ObjInUseFn) -> ObjFuture<'a, ()> {
+        Box::pin(async move {
+            let test = if self.config.use_hash_as_filename {
+                UniquenessTest::Sha512
+            } else {
+                UniquenessTest::Path
+            };
+
+            // If is object in use by other file (deduplication), do not remove it
+            if is_in_use(info, test)? {
+                return Ok(());
+            }
 
-        let path = self.path(info);
-        let bucket_name = &self.config.s3_bucket;
-        debug!("Remove: {:?} ({:?})", &path, bucket_name);
+            let path = self.path(info);
+            let bucket_name = &self.config.s3_bucket;
+            debug!("Remove: {:?} ({:?})", &path, bucket_name);
 
-        self.rt.block_on(async {
             self.client
                 .delete_object()
                 .bucket(bucket_name)
                 .key(&path)
-                .send().await?;
+                .send().await.map_err(request_error)?;
 
             Ok(())
         })
     }
 
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+    fn rename<'a>(&'a self, prev_info: &'a ObjInfo, new_info: &'a ObjInfo) -> ObjFuture<'a, ()> {
         let prev_path = self.path(prev_info);
         let new_path = self.path(new_info);
         let bucket_name = &self.config.s3_bucket;
         debug!("Rename: {:?} -> {:?} ({:?})", &prev_path, &new_path, bucket_name);
 
-        self.rt.block_on(async {
-            self.client
-                .copy_object()
+        Box::pin(async move {
+            // The copy is a new object, it needs the same encryption and class as the rest
+            let (sse_algorithm, sse_key, sse_key_md5) = self.sse_customer();
+            let head = self.client
+                .head_object()
                 .bucket(bucket_name)
-                .copy_source(format!("{}/{}", bucket_name, prev_path))
-                .key(&new_path)
-                .send().await?;
+                .key(&prev_path)
+                .set_sse_customer_algorithm(sse_algorithm.clone())
+                .set_sse_customer_key(sse_key.clone())
+                .set_sse_customer_key_md5(sse_key_md5.clone())
+                .send().await.map_err(request_error)?;
+            let size = head.content_length().unwrap_or(0).max(0) as u64;
+
+            if size > self.config.s3_part_size {
+                self.copy_multipart(&prev_path, &new_path, size).await?;
+            } else {
+                self.client
+                    .copy_object()
+                    .bucket(bucket_name)
+                    .copy_source(format!("{}/{}", bucket_name, prev_path))
+                    .key(&new_path)
+                    .set_server_side_encryption(self.server_side_encryption())
+                    .set_copy_source_sse_customer_algorithm(sse_algorithm.clone())
+                    .set_copy_source_sse_customer_key(sse_key.clone())
+                    .set_copy_source_sse_customer_key_md5(sse_key_md5.clone())
+                    .set_sse_customer_algorithm(sse_algorithm)
+                    .set_sse_customer_key(sse_key)
+                    .set_sse_customer_key_md5(sse_key_md5)
+                    .set_storage_class(self.storage_class())
+                    .send().await.map_err(request_error)?;
+            }
 
             self.client
                 .delete_object()
                 .bucket(bucket_name)
                 .key(&prev_path)
-                .send().await?;
+                .send().await.map_err(request_error)?;
 
             Ok(())
         })
     }
 
-    fn nuke(&mut self) -> Result<(), Error> {
+    fn nuke(&self) -> ObjFuture<'_, ()> {
         let path = self.config.s3_base_path.trim_matches('/').to_string();
         let bucket_name = &self.config.s3_bucket;
         debug!("Nuke: {:?} ({:?})", &path, bucket_name);
@@ -146,7 +446,8 @@ impl ObjectStorage for S3ObjectStorage {
                     .prefix(base_path)
                     .max_keys(1000)
                     .send()
-                    .await?;
+                    .await
+                    .map_err(request_error)?;
 
                 let key_count = objects.key_count().ok_or_else(|| anyhow!("Failed to get object count"))?;
 
@@ -176,13 +477,182 @@ impl ObjectStorage for S3ObjectStorage {
                             .map_err(Error::from)?,
                     )
                     .send()
-                    .await?;
+                    .await
+                    .map_err(request_error)?;
             }
         }
 
-        self.rt.block_on(async {
-            delete_objects(&self.client, &bucket_name, &path).await?;
+        Box::pin(async move {
+            delete_objects(&self.client, bucket_name, &path).await
+        })
+    }
+}
+
+// The synchronous interface blocks on the shared runtime, requests from different threads still run at the same time
+impl ObjectStorage for S3ObjectStorage {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, Error> {
+        runtime().block_on(AsyncObjectStorage::get(self, info))
+    }
+
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let path = self.path(info);
+        let bucket_name = &self.config.s3_bucket;
+        debug!("Get range: {:?} ({} + {}) ({:?})", &path, offset, len, bucket_name);
+
+        runtime().block_on(async {
+            // The end of an HTTP range is inclusive, and it may go past the end of the object
+            let (sse_algorithm, sse_key, sse_key_md5) = self.sse_customer();
+            let res = self.client
+                .get_object()
+                .bucket(bucket_name)
+                .key(&path)
+                .set_sse_customer_algorithm(sse_algorithm)
+                .set_sse_customer_key(sse_key)
+                .set_sse_customer_key_md5(sse_key_md5)
+                .range(format!("bytes={}-{}", offset, offset.saturating_add(len - 1)))
+                .send().await.map_err(request_error)?;
+
+            Self::read_body(res).await
+        })
+    }
+
+    fn partial_reads(&self, _info: &ObjInfo) -> bool {
+        true
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), Error> {
+        runtime().block_on(AsyncObjectStorage::put(self, info, content))
+    }
+
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), Error> {
+        runtime().block_on(AsyncObjectStorage::remove(self, info, is_in_use))
+    }
+
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+        runtime().block_on(AsyncObjectStorage::rename(self, prev_info, new_info))
+    }
+
+    fn nuke(&self) -> Result<(), Error> {
+        runtime().block_on(AsyncObjectStorage::nuke(self))
+    }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, Error> {
+        let path = self.config.s3_base_path.trim_matches('/').to_string();
+        let bucket_name = &self.config.s3_bucket;
+        debug!("List: {:?} ({:?})", &path, bucket_name);
+
+        runtime().block_on(async {
+            let mut result = vec![];
+            let mut continuation_token: Option<String> = None;
+
+            loop {
+                let objects = self.client.list_objects_v2()
+                    .bucket(bucket_name)
+                    .prefix(&path)
+                    .max_keys(1000)
+                    .set_continuation_token(continuation_token)
+                    .send()
+                    .await
+                    .map_err(request_error)?;
+
+                for obj in objects.contents() {
+                    if let Some(key) = obj.key() {
+                        result.push(ObjEntry {
+                            key: key.to_string(),
+                            size: obj.size().unwrap_or(0) as u64,
+                            modified_at: obj.last_modified().map(|time| time.secs()),
+                        });
+                    }
+                }
+
+                continuation_token = objects.next_continuation_token().map(|t| t.to_string());
+
+                if continuation_token.is_none() {
+                    return Ok(result);
+                }
+            }
+        })
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, Error> {
+        Ok(self.path(info))
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), Error> {
+        let bucket_name = &self.config.s3_bucket;
+        debug!("Remove: {:?} ({:?})", key, bucket_name);
+
+        runtime().block_on(async {
+            self.client
+                .delete_object()
+                .bucket(bucket_name)
+                .key(key)
+                .send().await.map_err(request_error)?;
+
             Ok(())
         })
     }
-}
\ No newline at end of file
+}
+// Needs an S3 compatible endpoint with an existing bucket, like MinIO:
+// INNERFS_TEST_S3_ENDPOINT=http://127.0.0.1:9000 INNERFS_TEST_S3_BUCKET=innerfs-test cargo test -- --ignored
+#[test]
+#[ignore]
+fn test_s3_multipart() {
+    use crate::config::StorageOption;
+    let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+
+    let storage = S3ObjectStorage::new(Arc::new(StorageConfig {
+        storage_backend: StorageOption::S3,
+        blob_storage: "".to_string(),
+        s3_endpoint_url: env("INNERFS_TEST_S3_ENDPOINT", "http://127.0.0.1:9000"),
+        s3_region: env("INNERFS_TEST_S3_REGION", "us-east-1"),
+        s3_bucket: env("INNERFS_TEST_S3_BUCKET", "innerfs-test"),
+        s3_base_path: "multipart-test".to_string(),
+        s3_access_key: env("INNERFS_TEST_S3_ACCESS_KEY", "minioadmin"),
+        s3_secret_key: env("INNERFS_TEST_S3_SECRET_KEY", "minioadmin"),
+        s3_session_token: "".to_string(),
+        s3_profile: "".to_string(),
+        s3_force_path_style: true,
+        s3_ca_bundle: "".to_string(),
+        s3_server_side_encryption: crate::config::S3Encryption::None,
+        s3_sse_customer_key: "".to_string(),
+        s3_storage_class: "".to_string(),
+        s3_part_size: 5 * 1024 * 1024,
+        s3_upload_concurrency: 2,
+        retry_max_attempts: 1,
+        retry_base_delay_ms: 0,
+        retry_max_delay_ms: 0,
+        operation_timeout_secs: 60,
+        encryption_key: "".to_string(),
+        compression_level: 0,
+        use_hash_as_filename: false,
+        chaos: None,
+    }));
+
+    let content: Vec<u8> = (0..12 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let mut info = ObjInfo { full_path: "/large.bin".to_string(), ..ObjInfo::default() };
+    ObjectStorage::put(&storage, &mut info, &content).unwrap();
+    assert_eq!(info.stored_size, content.len() as u64);
+    assert!(ObjectStorage::get(&storage, &info).unwrap() == content);
+    assert_eq!(storage.get_range(&info, 5 * 1024 * 1024 - 10, 20).unwrap(), content[5 * 1024 * 1024 - 10..5 * 1024 * 1024 + 10]);
+
+    let renamed = ObjInfo { full_path: "/renamed.bin".to_string(), ..ObjInfo::default() };
+    ObjectStorage::rename(&storage, &info, &renamed).unwrap();
+    assert!(ObjectStorage::get(&storage, &renamed).unwrap() == content);
+
+    storage.remove_key(&storage.path(&renamed)).unwrap();
+}
+
+#[test]
+fn test_part_size() {
+    let mib = 1024 * 1024;
+    assert_eq!(part_size(5 * mib, 12 * mib), 5 * mib);
+    assert_eq!(part_size(5 * mib, 10_000 * 5 * mib), 5 * mib);
+    assert_eq!(part_size(5 * mib, 10_000 * 5 * mib + 1), 5 * mib + 1);
+    assert_eq!(part_size(5 * mib, 5_000_000 * mib), 500 * mib);
+    assert!(part_size(5 * mib, u64::MAX / 2).saturating_mul(MAX_PARTS) >= u64::MAX / 2);
+}
@@ -1,14 +1,16 @@
 use crate::config::StorageConfig;
-use crate::metadata_db::MetadataDB;
-use crate::obj_storage::{ObjInfo, ObjectStorage, UniquenessTest};
+use crate::metadata_db::{MetadataDB, NO_BINDINGS};
+use crate::obj_storage::{ObjEntry, ObjInfo, ObjectStorage, UniquenessTest};
 use crate::storage::ObjInUseFn;
+use crate::utils::NANOS_PER_SECOND;
 use crate::AnyError;
 use log::{debug};
-use std::rc::Rc;
+use sqlite::Value;
+use std::sync::Arc;
 
 pub struct SqlarObjectStorage {
-    pub sql: Rc<MetadataDB>,
-    pub config: Rc<StorageConfig>,
+    pub sql: Arc<MetadataDB>,
+    pub config: Arc<StorageConfig>,
 }
 
 // https://sqlite.org/sqlar.html
@@ -29,7 +31,7 @@ pub struct SqlarFile {
 }
 
 impl ObjectStorage for SqlarObjectStorage {
-    fn get(&mut self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
         debug!("Get: {}", info);
         let name = self.path(&info);
         let file = self.get_sqlar_file(&name)?;
@@ -39,22 +41,45 @@ impl ObjectStorage for SqlarObjectStorage {
         Ok(file.unwrap().data)
     }
 
-    fn put(&mut self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        debug!("Get range: {} ({} + {})", info, offset, len);
+        let name = self.path(info);
+
+        // Positions of substr start at 1
+        let data = self.sql.get_row(
+            "SELECT substr(data, :start, :len) FROM sqlar WHERE name = :name",
+            &[
+                (":start", Value::Integer(offset as i64 + 1)),
+                (":len", Value::Integer(len.min(i64::MAX as u64) as i64)),
+                (":name", Value::String(name)),
+            ][..],
+            |row| Ok(row.read::<Option<Vec<u8>>, _>(0)?.unwrap_or_default()),
+        )?;
+        data.ok_or_else(|| anyhow::anyhow!("File not found ({})", info.name))
+    }
+
+    fn partial_reads(&self, _info: &ObjInfo) -> bool {
+        true
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
         let name = self.path(&info);
         debug!("Put: {}", name);
 
         let file = SqlarFile {
             name: name.clone(),
             mode: info.mode as i64,
-            mtime: info.updated_at,
+            // sqlar stores seconds, the index nanoseconds
+            mtime: info.updated_at.div_euclid(NANOS_PER_SECOND),
             sz: info.size as i64,
             data: content.to_vec(),
         };
         self.set_sqlar_file(&name, &file)?;
+        info.stored_size = content.len() as u64;
         Ok(())
     }
 
-    fn remove(&mut self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
         let test = if self.config.use_hash_as_filename {
             UniquenessTest::Sha512
         } else {
@@ -73,7 +98,7 @@ impl ObjectStorage for SqlarObjectStorage {
         Ok(())
     }
 
-    fn rename(&mut self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
         let prev_name = self.path(&prev_info);
         let new_name = self.path(&new_info);
         debug!("Rename: {} -> {}", prev_name, new_name);
@@ -82,15 +107,38 @@ impl ObjectStorage for SqlarObjectStorage {
         Ok(())
     }
 
-    fn nuke(&mut self) -> Result<(), AnyError> {
+    fn nuke(&self) -> Result<(), AnyError> {
         debug!("Nuke");
         self.sql.execute0("DELETE FROM sqlar")?;
         Ok(())
     }
+
+    fn list(&self) -> Result<Vec<ObjEntry>, AnyError> {
+        self.sql.get_rows(
+            "SELECT name, length(data) FROM sqlar ORDER BY name",
+            NO_BINDINGS.as_ref(),
+            |row| {
+                Ok(ObjEntry {
+                    key: row.read::<String, _>(0)?,
+                    size: row.read::<Option<i64>, _>(1)?.unwrap_or(0) as u64,
+                    // mtime is the one of the file, not the time the object was written
+                    modified_at: None,
+                })
+            })
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        Ok(self.path(info))
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        debug!("Remove: {}", key);
+        self.remove_sqlar_file(key)
+    }
 }
 
 impl SqlarObjectStorage {
-    pub fn get_sqlar_file(&mut self, name: &str) -> Result<Option<SqlarFile>, AnyError> {
+    pub fn get_sqlar_file(&self, name: &str) -> Result<Option<SqlarFile>, AnyError> {
         self.sql.get_row(
             "SELECT mode, mtime, sz, data FROM sqlar WHERE name = :name",
             (":name", name),
@@ -105,7 +153,7 @@ impl SqlarObjectStorage {
             })
     }
 
-    pub fn set_sqlar_file(&mut self, name: &str, file: &SqlarFile) -> Result<(), AnyError> {
+    pub fn set_sqlar_file(&self, name: &str, file: &SqlarFile) -> Result<(), AnyError> {
         self.sql.execute5(
             "INSERT OR REPLACE INTO sqlar (name, mode, mtime, sz, data) VALUES (:name, :mode, :mtime, :sz, :data)",
             (":name", name),
@@ -117,7 +165,7 @@ impl SqlarObjectStorage {
         Ok(())
     }
 
-    pub fn rename_sqlar_file(&mut self, prev_name: &str, new_name: &str) -> Result<(), AnyError> {
+    pub fn rename_sqlar_file(&self, prev_name: &str, new_name: &str) -> Result<(), AnyError> {
         self.sql.execute2(
             "UPDATE sqlar SET name = :new_name WHERE name = :prev_name",
             (":new_name", new_name),
@@ -126,7 +174,7 @@ impl SqlarObjectStorage {
         Ok(())
     }
 
-    pub fn remove_sqlar_file(&mut self, name: &str) -> Result<(), AnyError> {
+    pub fn remove_sqlar_file(&self, name: &str) -> Result<(), AnyError> {
         self.sql.execute1("DELETE FROM sqlar WHERE name = :name", (":name", name))?;
         Ok(())
     }
@@ -0,0 +1,193 @@
+use std::fs;
+use libc::{R_OK, S_ISGID, S_ISUID, S_ISVTX, S_IXGRP, W_OK, X_OK};
+use crate::metadata_db::{FileRow, FILE_KIND_DIRECTORY};
+
+/// Identity of the process performing an operation, used for permission checks
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl Credentials {
+    pub fn new(uid: u32, gid: u32, pid: u32) -> Self {
+        Credentials { uid, gid, pid }
+    }
+
+    /// Credentials of this process, to apply the same checks in the CLI commands
+    #[allow(dead_code)]
+    pub fn current() -> Self {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Credentials { uid, gid, pid: std::process::id() }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn is_owner(&self, file: &FileRow) -> bool {
+        self.is_root() || self.uid as i64 == file.uid
+    }
+
+    /// Primary or supplementary group of the process
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || supplementary_groups(self.pid).contains(&gid)
+    }
+}
+
+/// FUSE requests only include the primary group, the supplementary groups are read from /proc
+pub fn supplementary_groups(pid: u32) -> Vec<u32> {
+    let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) else {
+        return vec![];
+    };
+
+    status.lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| groups.split_whitespace().filter_map(|gid| gid.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Check the permission bits of a file for an access mask of R_OK, W_OK and X_OK
+pub fn has_access(file: &FileRow, cred: &Credentials, mask: i32) -> bool {
+    let mask = mask & (R_OK | W_OK | X_OK);
+    let perms = file.perms as i32;
+
+    if cred.is_root() {
+        // Root bypasses the checks, but can only execute files with an execute bit set
+        return mask & X_OK == 0 || file.kind == FILE_KIND_DIRECTORY || perms & 0o111 != 0;
+    }
+
+    // Only the first matching class applies, an owner without permissions is denied even if others are allowed
+    let bits = if cred.uid as i64 == file.uid {
+        (perms >> 6) & 0o7
+    } else if cred.in_group(file.gid as u32) {
+        (perms >> 3) & 0o7
+    } else {
+        perms & 0o7
+    };
+
+    bits & mask == mask
+}
+
+/// In sticky directories, entries can only be removed or renamed by their owner or the owner of the directory
+pub fn can_remove_entry(directory: &FileRow, file: &FileRow, cred: &Credentials) -> bool {
+    directory.perms & S_ISVTX as i64 == 0 || cred.is_owner(file) || cred.is_owner(directory)
+}
+
+/// Mode bits that a chmod can set, the setgid bit is silently dropped if the caller is not in the group of the file
+pub fn chmod_mode(file: &FileRow, cred: &Credentials, mode: u32) -> u32 {
+    if !cred.is_root() && file.kind != FILE_KIND_DIRECTORY && !cred.in_group(file.gid as u32) {
+        mode & !S_ISGID
+    } else {
+        mode
+    }
+}
+
+/// Only root can give away a file, the owner can only change the group to one they belong to
+pub fn can_chown(file: &FileRow, cred: &Credentials, uid: Option<u32>, gid: Option<u32>) -> bool {
+    if cred.is_root() {
+        return true;
+    }
+
+    let uid_changed = uid.is_some_and(|uid| uid as i64 != file.uid);
+    let gid_changed = gid.is_some_and(|gid| gid as i64 != file.gid);
+
+    cred.is_owner(file) && !uid_changed && (!gid_changed || gid.is_some_and(|gid| cred.in_group(gid)))
+}
+
+/// Mode after a write or a change of owner, the setuid bit is removed, and the setgid bit when it makes the file
+/// run with the group (a setgid bit without group execute marks mandatory locking instead)
+pub fn clear_privileges(perms: i64) -> i64 {
+    let mut perms = perms & !(S_ISUID as i64);
+    if perms & S_IXGRP as i64 != 0 {
+        perms &= !(S_ISGID as i64);
+    }
+    perms
+}
+
+/// Access mask needed to open a file with the given flags
+pub fn open_access_mask(flags: i32) -> i32 {
+    let mut mask = match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => W_OK,
+        libc::O_RDWR => R_OK | W_OK,
+        _ => R_OK,
+    };
+    if flags & libc::O_TRUNC != 0 {
+        mask |= W_OK;
+    }
+    mask
+}
+
+#[cfg(test)]
+fn file_row(uid: i64, gid: i64, perms: i64) -> FileRow {
+    FileRow {
+        id: 2,
+        version: 1,
+        kind: 0,
+        name: "file".to_string(),
+        uid,
+        gid,
+        perms,
+        rdev: 0,
+        size: 0,
+        stored_size: 0,
+        allocated_size: 0,
+        sha512: "".to_string(),
+        encryption_key: "".to_string(),
+        compression: "".to_string(),
+        accessed_at: 0,
+        created_at: 0,
+        updated_at: 0,
+        changed_at: 0,
+    }
+}
+
+#[test]
+fn test_permission_classes() {
+    // Pid 0 has no /proc entry, so there are no supplementary groups
+    let user = Credentials::new(1000, 1000, 0);
+    let other = Credentials::new(1001, 1001, 0);
+    let root = Credentials::new(0, 0, 0);
+
+    let private = file_row(1000, 1000, 0o640);
+    assert!(has_access(&private, &user, R_OK | W_OK));
+    assert!(!has_access(&private, &user, X_OK));
+    assert!(!has_access(&private, &other, R_OK));
+    assert!(has_access(&private, &Credentials::new(1001, 1000, 0), R_OK));
+    assert!(has_access(&private, &root, R_OK | W_OK));
+    assert!(!has_access(&private, &root, X_OK));
+
+    // The owner class applies even if others have more permissions
+    let inverted = file_row(1000, 1000, 0o007);
+    assert!(!has_access(&inverted, &user, R_OK));
+    assert!(has_access(&inverted, &other, R_OK | W_OK | X_OK));
+
+    let sticky = FileRow { kind: FILE_KIND_DIRECTORY, ..file_row(0, 0, 0o1777) };
+    assert!(can_remove_entry(&sticky, &private, &user));
+    assert!(!can_remove_entry(&sticky, &private, &other));
+    assert!(can_remove_entry(&FileRow { kind: FILE_KIND_DIRECTORY, ..file_row(0, 0, 0o777) }, &private, &other));
+}
+
+#[test]
+fn test_ownership_rules() {
+    let file = file_row(1000, 1000, 0o6755);
+    let owner = Credentials::new(1000, 1000, 0);
+    let other = Credentials::new(1001, 1001, 0);
+    let root = Credentials::new(0, 0, 0);
+
+    // Only root can give away files, owners can keep the same uid and change to their own groups
+    assert!(!can_chown(&file, &owner, Some(1001), None));
+    assert!(can_chown(&file, &owner, Some(1000), Some(1000)));
+    assert!(!can_chown(&file, &owner, None, Some(1001)));
+    assert!(!can_chown(&file, &other, None, Some(1001)));
+    assert!(can_chown(&file, &root, Some(1001), Some(1001)));
+
+    assert_eq!(chmod_mode(&file, &owner, 0o2755), 0o2755);
+    assert_eq!(chmod_mode(&FileRow { gid: 50, ..file.clone() }, &owner, 0o2755), 0o755);
+    assert_eq!(chmod_mode(&FileRow { gid: 50, ..file.clone() }, &root, 0o2755), 0o2755);
+
+    assert_eq!(clear_privileges(0o6755), 0o755);
+    // Setgid without group execute is kept
+    assert_eq!(clear_privileges(0o6745), 0o2745);
+}
@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use anyhow::anyhow;
+use crate::AnyError;
+
+/// Header of objects stored with holes, raw contents that start with it are also stored this way to keep it unambiguous
+pub const SPARSE_MAGIC: &[u8; 16] = b"\0INNERFS-SPARSE\x01";
+
+/// Contents of a file kept as data extents, everything between them up to `size` is a hole that reads as zeros.
+/// Extents never overlap nor touch each other, writes next to an extent are merged into it.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SparseBuffer {
+    size: u64,
+    extents: BTreeMap<u64, Vec<u8>>,
+}
+
+impl SparseBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Contents as read from the object storage, either raw bytes or the sparse encoding
+    pub fn from_object(data: Vec<u8>) -> Result<Self, AnyError> {
+        if data.starts_with(SPARSE_MAGIC) {
+            return Self::decode(&data);
+        }
+
+        let mut buffer = SparseBuffer { size: data.len() as u64, extents: BTreeMap::new() };
+        if !data.is_empty() {
+            buffer.extents.insert(0, data);
+        }
+        Ok(buffer)
+    }
+
+    /// If the object uses the sparse encoding, only its first bytes are needed
+    pub fn is_encoded(data: &[u8]) -> bool {
+        data.starts_with(SPARSE_MAGIC)
+    }
+
+    /// Bytes to store in the object storage, the raw contents unless the file has holes
+    pub fn to_object(&self) -> Vec<u8> {
+        let raw = self.extents.get(&0).filter(|data| data.len() as u64 == self.size);
+
+        match raw {
+            Some(data) if !data.starts_with(SPARSE_MAGIC) => data.clone(),
+            None if self.size == 0 => vec![],
+            _ => self.encode(),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes that are not holes
+    pub fn allocated(&self) -> u64 {
+        self.extents.values().map(|data| data.len() as u64).sum()
+    }
+
+    /// SHA512 of the contents with the holes as zeros, without building the whole file in memory
+    pub fn sha512(&self) -> String {
+        let zeros = [0u8; 4096];
+        let mut hash = hmac_sha512::Hash::new();
+        let mut pos = 0;
+
+        let hash_zeros = |hash: &mut hmac_sha512::Hash, mut len: u64| {
+            while len > 0 {
+                let chunk = len.min(zeros.len() as u64);
+                hash.update(&zeros[..chunk as usize]);
+                len -= chunk;
+            }
+        };
+
+        for (&start, data) in &self.extents {
+            hash_zeros(&mut hash, start - pos);
+            hash.update(data);
+            pos = start + data.len() as u64;
+        }
+        hash_zeros(&mut hash, self.size - pos);
+
+        hex::encode(hash.finalize())
+    }
+
+    pub fn read(&self, offset: u64, buff: &mut [u8]) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+
+        let len = buff.len().min((self.size - offset) as usize);
+        let end = offset + len as u64;
+        buff[..len].fill(0);
+
+        for (start, data) in self.overlapping(offset, end) {
+            let from = start.max(offset);
+            let to = (start + data.len() as u64).min(end);
+            buff[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+
+        len
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = offset + data.len() as u64;
+
+        // Extents that overlap or touch the written range are merged with it
+        let keys = self.extents.range(..=end)
+            .rev()
+            .take_while(|(&start, extent)| start + extent.len() as u64 >= offset)
+            .map(|(&start, _)| start)
+            .collect::<Vec<_>>();
+
+        let base = keys.last().copied().unwrap_or(offset).min(offset);
+        let mut merged = match keys.last() {
+            Some(&start) if start == base => self.extents.remove(&start).unwrap(),
+            _ => vec![],
+        };
+
+        let merged_end = keys.iter()
+            .filter_map(|start| self.extents.get(start).map(|extent| start + extent.len() as u64))
+            .fold(end.max(base + merged.len() as u64), u64::max);
+        merged.resize((merged_end - base) as usize, 0);
+
+        for start in keys {
+            if let Some(extent) = self.extents.remove(&start) {
+                let pos = (start - base) as usize;
+                merged[pos..pos + extent.len()].copy_from_slice(&extent);
+            }
+        }
+
+        let pos = (offset - base) as usize;
+        merged[pos..pos + data.len()].copy_from_slice(data);
+
+        self.extents.insert(base, merged);
+        self.size = self.size.max(end);
+    }
+
+    /// Truncate or extend the file, the extended part is a hole
+    pub fn set_len(&mut self, size: u64) {
+        if size < self.size {
+            self.extents.retain(|&start, _| start < size);
+            if let Some((&start, extent)) = self.extents.iter_mut().next_back() {
+                extent.truncate((size - start) as usize);
+            }
+        }
+        self.size = size;
+    }
+
+    /// Deallocate a range, it reads as zeros afterward and the size is not changed
+    pub fn punch_hole(&mut self, offset: u64, len: u64) {
+        let end = offset.saturating_add(len).min(self.size);
+        if offset >= end {
+            return;
+        }
+
+        let keys = self.overlapping(offset, end).map(|(start, _)| start).collect::<Vec<_>>();
+
+        for start in keys {
+            let mut extent = self.extents.remove(&start).unwrap();
+            let extent_end = start + extent.len() as u64;
+
+            if extent_end > end {
+                let tail = extent.split_off((end - start) as usize);
+                self.extents.insert(end, tail);
+            }
+            if start < offset {
+                extent.truncate((offset - start) as usize);
+                self.extents.insert(start, extent);
+            }
+        }
+    }
+
+    /// First offset with data at or after `offset`, None if there is only a hole until the end of the file
+    pub fn seek_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+
+        self.overlapping(offset, self.size)
+            .next()
+            .map(|(start, _)| start.max(offset))
+    }
+
+    /// First offset of a hole at or after `offset`, the end of the file counts as a hole
+    pub fn seek_hole(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+
+        match self.overlapping(offset, offset + 1).next() {
+            Some((start, data)) => Some(start + data.len() as u64),
+            None => Some(offset),
+        }
+    }
+
+    /// Extents with data in the range [from, to)
+    fn overlapping(&self, from: u64, to: u64) -> impl Iterator<Item=(u64, &Vec<u8>)> {
+        let previous = self.extents.range(..from)
+            .next_back()
+            .filter(|(&start, data)| start + data.len() as u64 > from);
+
+        previous.into_iter()
+            .chain(self.extents.range(from..to))
+            .map(|(&start, data)| (start, data))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.extents.len() * 16 + self.allocated() as usize);
+        out.extend_from_slice(SPARSE_MAGIC);
+        out.extend_from_slice(&self.size.to_le_bytes());
+        out.extend_from_slice(&(self.extents.len() as u64).to_le_bytes());
+
+        for (&start, data) in &self.extents {
+            out.extend_from_slice(&start.to_le_bytes());
+            out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        }
+        for data in self.extents.values() {
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, AnyError> {
+        let invalid = || anyhow!("Invalid sparse object");
+        let read_u64 = |pos: usize| -> Result<u64, AnyError> {
+            let bytes = data.get(pos..pos + 8).ok_or_else(invalid)?;
+            Ok(u64::from_le_bytes(bytes.try_into()?))
+        };
+
+        let size = read_u64(SPARSE_MAGIC.len())?;
+        let count = read_u64(SPARSE_MAGIC.len() + 8)? as usize;
+        let header_len = SPARSE_MAGIC.len() + 16;
+        let mut data_pos = header_len + count.checked_mul(16).ok_or_else(invalid)?;
+
+        let mut buffer = SparseBuffer { size, extents: BTreeMap::new() };
+
+        for index in 0..count {
+            let start = read_u64(header_len + index * 16)?;
+            let len = read_u64(header_len + index * 16 + 8)? as usize;
+            let extent = data.get(data_pos..data_pos + len).ok_or_else(invalid)?;
+
+            if start + len as u64 > size {
+                return Err(invalid());
+            }
+
+            buffer.extents.insert(start, extent.to_vec());
+            data_pos += len;
+        }
+
+        Ok(buffer)
+    }
+}
+
+#[test]
+fn test_sparse_write_and_holes() {
+    let mut buffer = SparseBuffer::new();
+    buffer.write(10, b"hello");
+    buffer.write(15, b" world");
+    buffer.write(100, b"tail");
+    buffer.write(8, b"__");
+
+    assert_eq!(buffer.len(), 104);
+    assert_eq!(buffer.allocated(), 17);
+
+    let mut out = [1u8; 16];
+    assert_eq!(buffer.read(0, &mut out), 16);
+    assert_eq!(&out, b"\0\0\0\0\0\0\0\0__hello ");
+
+    assert_eq!(buffer.seek_data(0), Some(8));
+    assert_eq!(buffer.seek_hole(8), Some(21));
+    assert_eq!(buffer.seek_data(21), Some(100));
+    assert_eq!(buffer.seek_hole(100), Some(104));
+    assert_eq!(buffer.seek_data(104), None);
+
+    buffer.punch_hole(12, 4);
+    assert_eq!(buffer.allocated(), 13);
+    assert_eq!(buffer.read(10, &mut out[..8]), 8);
+    assert_eq!(&out[..8], b"he\0\0\0\0wo");
+
+    buffer.set_len(18);
+    assert_eq!(buffer.seek_data(18), None);
+    assert_eq!(buffer.allocated(), 6);
+}
+
+#[test]
+fn test_sparse_object_encoding() {
+    let mut buffer = SparseBuffer::new();
+    buffer.write(0, &[7u8; 100]);
+    buffer.write(100, &[0u8; 3 * 4096]);
+    buffer.write(3 * 4096 + 100, b"end");
+
+    // Zeros that were written are data, only the holes made by punching or extending the file are left out
+    let raw = buffer.to_object();
+    assert_eq!(raw.len(), 3 * 4096 + 103);
+    assert_eq!(SparseBuffer::from_object(raw).unwrap(), buffer);
+
+    let sha512 = buffer.sha512();
+    buffer.punch_hole(4096, 4096);
+    buffer.set_len(4 * 4096);
+    assert_eq!(buffer.allocated(), 3 * 4096 + 103 - 4096);
+    buffer.set_len(3 * 4096 + 103);
+    assert_eq!(buffer.sha512(), sha512);
+
+    let decoded = SparseBuffer::from_object(buffer.to_object()).unwrap();
+    assert_eq!(decoded, buffer);
+
+    // Contents without holes are stored raw, unless they could be confused with the sparse encoding
+    assert_eq!(SparseBuffer::from_object(b"raw".to_vec()).unwrap().to_object(), b"raw");
+    let tricky = SparseBuffer::from_object(SPARSE_MAGIC.to_vec()).unwrap_err();
+    assert!(tricky.to_string().contains("Invalid"));
+    let mut magic = SparseBuffer::new();
+    magic.write(0, SPARSE_MAGIC);
+    assert_eq!(SparseBuffer::from_object(magic.to_object()).unwrap(), magic);
+}
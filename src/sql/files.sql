@@ -3,16 +3,24 @@
 CREATE TABLE IF NOT EXISTS files (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     version INTEGER NOT NULL DEFAULT 1,
-    kind INTEGER NOT NULL, -- 0: file, 1: directory
+    kind INTEGER NOT NULL, -- 0: file, 1: directory, 2: fifo, 3: socket, 4: character device, 5: block device
     name TEXT NOT NULL,
     uid INTEGER NOT NULL,
     gid INTEGER NOT NULL,
     perms INTEGER NOT NULL,
+    rdev INTEGER NOT NULL DEFAULT 0, -- device number of character and block devices
     size INTEGER NOT NULL,
+    stored_size INTEGER NOT NULL DEFAULT 0, -- bytes used by the object in the primary storage backend
+    allocated_size INTEGER NOT NULL DEFAULT 0, -- bytes of the contents that are not holes
     sha512 TEXT NOT NULL,
     encryption_key TEXT NOT NULL,
     compression TEXT NOT NULL, -- '', 'gzip:1', 'gzip:9', etc.
-    accessed_at INTEGER NOT NULL,
+    accessed_at INTEGER NOT NULL, -- timestamps in seconds, with the nanoseconds in the *_nsec columns
+    accessed_at_nsec INTEGER NOT NULL DEFAULT 0,
     created_at INTEGER NOT NULL,
-    updated_at INTEGER NOT NULL
+    created_at_nsec INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL, -- last change of the contents (mtime)
+    updated_at_nsec INTEGER NOT NULL DEFAULT 0,
+    changed_at INTEGER NOT NULL DEFAULT 0, -- last change of the contents or the metadata (ctime)
+    changed_at_nsec INTEGER NOT NULL DEFAULT 0
 );
\ No newline at end of file
@@ -0,0 +1,5 @@
+
+-- Indexes for path resolution and queries over the metadata
+CREATE INDEX IF NOT EXISTS directory_entries_directory_file_id_name ON directory_entries (directory_file_id, name);
+CREATE INDEX IF NOT EXISTS directory_entries_entry_file_id ON directory_entries (entry_file_id);
+CREATE INDEX IF NOT EXISTS files_sha512 ON files (sha512);
@@ -0,0 +1,24 @@
+
+-- Views to query the index without rebuilding paths by hand, recreated on every start to keep them up to date
+DROP VIEW IF EXISTS file_paths;
+CREATE VIEW file_paths AS
+WITH RECURSIVE paths(id, path) AS (
+    SELECT 1, '/'
+    UNION ALL
+    SELECT e.entry_file_id, rtrim(p.path, '/') || '/' || e.name
+    FROM paths p JOIN directory_entries e ON e.directory_file_id = p.id
+    WHERE e.name <> '.' AND e.name <> '..'
+)
+SELECT f.id, p.path, f.version, f.kind, f.name, f.uid, f.gid, f.perms, f.rdev, f.size, f.stored_size, f.allocated_size, f.sha512, f.encryption_key,
+       f.compression, f.accessed_at, f.accessed_at_nsec, f.created_at, f.created_at_nsec, f.updated_at, f.updated_at_nsec,
+       f.changed_at, f.changed_at_nsec
+FROM paths p JOIN files f ON f.id = p.id;
+
+-- Contents shared by more than one regular file, and the bytes saved by storing them once
+DROP VIEW IF EXISTS duplicate_contents;
+CREATE VIEW duplicate_contents AS
+SELECT sha512, max(size) AS size, count(*) AS copies, (count(*) - 1) * max(size) AS saved_size
+FROM files
+WHERE kind = 0 AND sha512 <> ''
+GROUP BY sha512
+HAVING count(*) > 1;
@@ -1,19 +1,27 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::config::Config;
 use crate::AnyError;
-use crate::metadata_db::{DirectoryEntry, FileChangeKind, FileRow, MetadataDB, FILE_KIND_DIRECTORY, FILE_KIND_REGULAR};
+use crate::metadata_db::{unlinked_file_path, DirectoryEntry, FileChangeKind, FileRow, MetadataDB, ROOT_DIRECTORY_ID, FILE_KIND_BLOCK_DEVICE, FILE_KIND_CHAR_DEVICE, FILE_KIND_DIRECTORY, FILE_KIND_FIFO, FILE_KIND_REGULAR, FILE_KIND_SOCKET};
 use crate::storage::Storage;
 use anyhow::{anyhow, Context};
-use libc::{EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, ENOTSUP, O_RDONLY, O_WRONLY};
+use libc::{EACCES, EEXIST, EINVAL, EIO, EISDIR, ENODEV, ENOENT, ENOTDIR, ENOTEMPTY, ENOTSUP, ENXIO, EOPNOTSUPP, EPERM, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, SEEK_DATA, SEEK_HOLE, S_ISGID, S_ISUID, W_OK, X_OK};
 use crate::obj_storage::UniquenessTest;
+use crate::obj_storage::retrying_object_storage::retry_stats;
+use crate::permissions::{can_chown, can_remove_entry, clear_privileges, has_access, Credentials};
 use crate::utils::current_timestamp;
 
+/// Setting with the counters of the content cache, as JSON
+pub const CACHE_STATS_SETTING: &str = "cache_stats";
+/// Setting with the counters of the retried storage operations, as JSON
+pub const RETRY_STATS_SETTING: &str = "retry_stats";
+
+/// Filesystem operations, shared by all the threads that serve requests
 pub struct SqlFileSystem {
-    pub sql: Rc<MetadataDB>,
-    pub config: Rc<Config>,
+    pub sql: Arc<MetadataDB>,
+    pub config: Arc<Config>,
     pub storage: Box<dyn Storage>,
 }
 
@@ -24,16 +32,17 @@ pub struct SqlFileSystemError {
 }
 
 impl SqlFileSystem {
-    pub fn new(sql: Rc<MetadataDB>, config: Rc<Config>, storage: Box<dyn Storage>) -> Self {
+    pub fn new(sql: Arc<MetadataDB>, config: Arc<Config>, storage: Box<dyn Storage>) -> Self {
         Self { sql, config, storage }
     }
 
-    pub fn read_all(&mut self, id: i64) -> Result<Vec<u8>, SqlFileSystemError> {
+    pub fn read_all(&self, id: i64) -> Result<Vec<u8>, SqlFileSystemError> {
         const BLOCK_SIZE: usize = 65536; // 64kb
 
         let mut file = self.get_file_or_err(id)?;
         let full_path = self.sql.get_file_path(file.id)?;
-        let modified = self.storage.open(&mut file, &full_path, O_RDONLY as u32)?;
+        // The whole object is needed, a single request is faster than reading it by ranges
+        let modified = self.storage.open(&mut file, &full_path, false)?;
 
         if modified {
             self.sql.update_file(&file)?;
@@ -60,11 +69,7 @@ impl SqlFileSystem {
 
         let modified = self.storage.close(&mut file)?;
         if modified {
-            self.sql.update_file(&file)?;
-
-            if self.config.store_file_change_history {
-                self.sql.register_file_change(&file, FileChangeKind::UpdatedContents)?;
-            }
+            self.contents_stored(&file)?;
         } else if self.config.update_access_time {
             self.sql.file_set_access_time(file.id, current_timestamp())?;
         }
@@ -74,12 +79,12 @@ impl SqlFileSystem {
     }
 
     #[allow(dead_code)]
-    pub fn write_all(&mut self, id: i64, contents: &[u8]) -> Result<(), SqlFileSystemError> {
+    pub fn write_all(&self, id: i64, contents: &[u8]) -> Result<(), SqlFileSystemError> {
         const BLOCK_SIZE: usize = 65536; // 64kb
 
         let mut file = self.get_file_or_err(id)?;
         let full_path = self.sql.get_file_path(file.id)?;
-        let modified = self.storage.open(&mut file, &full_path, O_WRONLY as u32)?;
+        let modified = self.storage.open(&mut file, &full_path, false)?;
 
         if modified {
             self.sql.update_file(&file)?;
@@ -91,6 +96,8 @@ impl SqlFileSystem {
             self.sql.file_set_access_time(file.id, current_timestamp())?;
         }
 
+        // Writes patch the contents, the previous ones are dropped first so a shorter file leaves no tail behind
+        self.storage.truncate(&mut file, &full_path, 0)?;
         let mut offset = 0;
 
         loop {
@@ -111,11 +118,7 @@ impl SqlFileSystem {
 
         let modified = self.storage.close(&mut file)?;
         if modified {
-            self.sql.update_file(&file)?;
-
-            if self.config.store_file_change_history {
-                self.sql.register_file_change(&file, FileChangeKind::UpdatedContents)?;
-            }
+            self.contents_stored(&file)?;
         } else if self.config.update_access_time {
             self.sql.file_set_access_time(file.id, current_timestamp())?;
         }
@@ -124,18 +127,32 @@ impl SqlFileSystem {
         Ok(())
     }
 
-    pub fn move_file(&mut self, parent_id: i64, name: &str, new_parent_id: i64, new_name: &str) -> Result<(), SqlFileSystemError> {
-        self.transaction(|this| {
+    pub fn move_file(&self, parent_id: i64, name: &str, new_parent_id: i64, new_name: &str) -> Result<(), SqlFileSystemError> {
+        let old_path = format!("{}/{}", self.sql.get_file_path(parent_id)?, name);
+        let new_path = format!("{}/{}", self.sql.get_file_path(new_parent_id)?, new_name);
+        let old_entry = self.find_directory_entry_or_err(parent_id, name)?;
+        let file = self.get_file_or_err(old_entry.entry_file_id)?;
+
+        // An open file in the target location keeps its contents, its object is moved aside like when it is unlinked
+        let target = match self.sql.find_directory_entry(new_parent_id, new_name)? {
+            Some(entry) => Some((self.get_file_or_err(entry.entry_file_id)?, entry)),
+            None => None,
+        };
+        let unlinked_path = target.as_ref().map(|(target_file, _)| unlinked_file_path(target_file.id)).unwrap_or_default();
+        let mut renames = vec![];
+        if let Some((target_file, _)) = target.as_ref().filter(|(target_file, _)| self.storage.is_open(target_file.id)) {
+            renames.push((target_file, new_path.as_str(), unlinked_path.as_str()));
+        }
+        renames.push((&file, old_path.as_str(), new_path.as_str()));
+
+        self.with_renamed_objects(&renames, |this| {
             let now = current_timestamp();
-            let old_path = format!("{}/{}", this.sql.get_file_path(parent_id)?, name);
-            let new_path = format!("{}/{}", this.sql.get_file_path(new_parent_id)?, new_name);
 
             // Remove the already existing file in the target location
-            if let Some(new_entry) = this.sql.find_directory_entry(new_parent_id, new_name)? {
-                this.sql.remove_file(new_entry.entry_file_id)?;
+            if let Some((target_file, entry)) = &target {
+                this.remove_entry_row(entry, target_file.clone(), &new_path)?;
             }
 
-            let old_entry = this.find_directory_entry_or_err(parent_id, name)?;
             let mut file = this.get_file_or_err(old_entry.entry_file_id)?;
 
             // Unlink from old parent
@@ -154,10 +171,11 @@ impl SqlFileSystem {
             file.name = new_name.to_string();
             file.updated_at = current_timestamp();
             file.accessed_at = current_timestamp();
+            file.changed_at = current_timestamp();
             this.sql.update_file(&file)?;
-
-            // Move in backend storage
-            this.storage.rename(&file, &old_path, &new_path)?;
+            if new_parent_id != parent_id {
+                this.set_parent_directory(&file, new_parent_id)?;
+            }
 
             if this.config.update_access_time {
                 this.sql.file_set_access_time(file.id, now)?;
@@ -175,13 +193,54 @@ impl SqlFileSystem {
             }
 
             Ok(())
-        })?;
+        })
+    }
 
+    /// Point the '..' entry of a moved directory to its new parent
+    fn set_parent_directory(&self, file: &FileRow, parent_id: i64) -> Result<(), SqlFileSystemError> {
+        if file.kind != FILE_KIND_DIRECTORY {
+            return Ok(());
+        }
+
+        let mut entry = self.find_directory_entry_or_err(file.id, "..")?;
+        entry.entry_file_id = parent_id;
+        self.sql.update_directory_entry(&entry)?;
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn copy_file(&mut self, parent_id: i64, name: &str, new_parent_id: i64, new_name: &str) -> Result<i64, SqlFileSystemError> {
+    /// Move objects in the storage in order, when one of them fails the ones already moved are put back
+    fn rename_objects(&self, renames: &[(&FileRow, &str, &str)]) -> Result<(), AnyError> {
+        for (done, (file, from, to)) in renames.iter().enumerate() {
+            if let Err(e) = self.storage.rename(file, from, to) {
+                for (file, from, to) in renames[..done].iter().rev() {
+                    if let Err(undo) = self.storage.rename(file, to, from) {
+                        return Err(e.context(format!("Unable to move {} back to {}: {:#}", to, from, undo)));
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the objects of a rename before the transaction that changes the metadata, so the database is not locked
+    /// during the object I/O. They are put back if the transaction fails
+    fn with_renamed_objects<R>(
+        &self, renames: &[(&FileRow, &str, &str)], func: impl FnOnce(&Self) -> Result<R, SqlFileSystemError>,
+    ) -> Result<R, SqlFileSystemError> {
+        self.rename_objects(renames)?;
+
+        let res = self.transaction(func);
+        if res.is_err() {
+            let undo = renames.iter().rev().map(|&(file, from, to)| (file, to, from)).collect::<Vec<_>>();
+            if let Err(e) = self.rename_objects(&undo) {
+                return res.map_err(|err| SqlFileSystemError { code: err.code, error: err.error.context(format!("Unable to move the objects back: {:#}", e)) });
+            }
+        }
+        res
+    }
+
+    pub fn copy_file(&self, parent_id: i64, name: &str, new_parent_id: i64, new_name: &str) -> Result<i64, SqlFileSystemError> {
         if !self.is_validate_file_name(name) {
             return error(EINVAL, anyhow!("Invalid file name: {}", name));
         }
@@ -197,9 +256,15 @@ impl SqlFileSystem {
             return error(EEXIST, anyhow!("File already exists: {}", new_name));
         }
 
-        let file = self.lookup(parent_id, name)?.unwrap();
+        let Some(file) = self.lookup(parent_id, name)? else {
+            return error(ENOENT, anyhow!("File not found: {}", name));
+        };
+        if file.kind != FILE_KIND_REGULAR {
+            return error(EINVAL, anyhow!("Only regular files can be copied: {}", name));
+        }
+        self.flush(file.id)?;
 
-        self.transaction(|this| {
+        let new_id = self.transaction(|this| {
             let now = current_timestamp();
             let mut new_file = FileRow {
                 id: 0,
@@ -209,13 +274,17 @@ impl SqlFileSystem {
                 uid: file.uid,
                 gid: file.gid,
                 perms: file.perms,
+                rdev: 0,
                 size: 0,
+                stored_size: 0,
+                allocated_size: 0,
                 sha512: "".to_string(),
                 encryption_key: "".to_string(),
                 compression: "".to_string(),
                 accessed_at: if this.config.update_access_time { now } else { 0 },
                 created_at: now,
                 updated_at: now,
+                changed_at: now,
             };
 
             let new_id = this.sql.add_file(&new_file)?;
@@ -229,9 +298,10 @@ impl SqlFileSystem {
                 kind: file.kind,
             })?;
 
-            // Copy file contents
-            let contents = this.read_all(file.id)?;
-            this.write_all(new_file.id, &contents)?;
+            // The object is shared without reading it when possible
+            if this.shares_objects() {
+                this.share_contents(&file, &mut new_file)?;
+            }
 
             if this.config.update_access_time {
                 this.sql.file_set_access_time(parent_id, now)?;
@@ -244,10 +314,40 @@ impl SqlFileSystem {
             }
 
             Ok(new_id)
-        })
+        })?;
+
+        // Otherwise the contents are copied after the new file is committed, the database is not locked meanwhile
+        if !self.shares_objects() {
+            let copied = self.read_all(file.id).and_then(|contents| self.write_all(new_id, &contents));
+            if let Err(e) = copied {
+                // No copy is left with partial contents
+                let _ = self.unlink(new_parent_id, new_name);
+                return Err(e);
+            }
+        }
+        Ok(new_id)
     }
 
-    pub fn lookup(&mut self, parent: i64, name: &str) -> Result<Option<FileRow>, SqlFileSystemError> {
+    /// Point a file to the stored object of another one, only valid when `shares_objects` is true
+    fn share_contents(&self, src: &FileRow, dst: &mut FileRow) -> Result<(), SqlFileSystemError> {
+        let full_path = self.sql.get_file_path(dst.id)?;
+        self.storage.share(src, dst, &full_path)?;
+        dst.updated_at = current_timestamp();
+        dst.changed_at = dst.updated_at;
+        self.sql.update_file(dst)?;
+
+        if self.config.store_file_change_history {
+            self.sql.register_file_change(dst, FileChangeKind::UpdatedContents)?;
+        }
+        Ok(())
+    }
+
+    /// Whether files with the same contents can point to the same object in every backend
+    fn shares_objects(&self) -> bool {
+        self.config.primary.is_content_addressed() && self.config.replicas.iter().all(|replica| replica.is_content_addressed())
+    }
+
+    pub fn lookup(&self, parent: i64, name: &str) -> Result<Option<FileRow>, SqlFileSystemError> {
         let dir_file = self.get_file_or_err(parent)?;
 
         if dir_file.kind != FILE_KIND_DIRECTORY {
@@ -269,17 +369,31 @@ impl SqlFileSystem {
         Ok(Some(file))
     }
 
-    pub fn getattr(&mut self, id: i64) -> Result<FileRow, SqlFileSystemError> {
+    pub fn getattr(&self, id: i64) -> Result<FileRow, SqlFileSystemError> {
         self.get_file_or_err(id)
     }
 
     pub fn setattr(
-        &mut self, id: i64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>,
+        &self, id: i64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>,
         atime: Option<i64>, mtime: Option<i64>, crtime: Option<i64>,
     ) -> Result<FileRow, SqlFileSystemError> {
+        // Closed files are stored right away when truncated, before the transaction, which then only saves the size
+        if let Some(size) = size {
+            let mut file = self.get_file_or_err(id)?;
+            if file.kind == FILE_KIND_DIRECTORY {
+                return error(EISDIR, anyhow!("Cannot truncate a directory: {}", file.id));
+            }
+            let full_path = self.sql.get_file_path(file.id)?;
+            if self.storage.truncate(&mut file, &full_path, size)? {
+                self.contents_stored(&file)?;
+            }
+        }
+
         self.transaction(|this| {
             let mut file = this.get_file_or_err(id)?;
 
+            let owner_changed = uid.is_some_and(|uid| uid as i64 != file.uid) || gid.is_some_and(|gid| gid as i64 != file.gid);
+
             if let Some(mode) = mode {
                 file.perms = mode as i64;
             }
@@ -289,6 +403,10 @@ impl SqlFileSystem {
             if let Some(gid) = gid {
                 file.gid = gid as i64;
             }
+            // A new owner must not inherit the privileges of the previous one
+            if owner_changed && mode.is_none() && file.kind != FILE_KIND_DIRECTORY {
+                file.perms = clear_privileges(file.perms);
+            }
             if let Some(size) = size {
                 file.size = size as i64;
             }
@@ -301,6 +419,7 @@ impl SqlFileSystem {
             if let Some(crtime) = crtime {
                 file.created_at = crtime;
             }
+            file.changed_at = current_timestamp();
 
             this.sql.update_file(&file)?;
 
@@ -311,13 +430,20 @@ impl SqlFileSystem {
         })
     }
 
-    pub fn mkdir(&mut self, parent: i64, name: &str, uid: u32, gid: u32, mode: u32) -> Result<FileRow, SqlFileSystemError> {
+    pub fn mkdir(&self, parent: i64, name: &str, uid: u32, gid: u32, mode: u32) -> Result<FileRow, SqlFileSystemError> {
         if !self.is_validate_file_name(name) {
             return error(EINVAL, anyhow!("Invalid file name: {}", name));
         }
 
         let parent_directory = self.get_file_or_err(parent)?;
 
+        // Directories created inside a setgid directory take its group, and propagate the setgid bit
+        let (gid, mode) = if parent_directory.perms & S_ISGID as i64 != 0 {
+            (parent_directory.gid, mode | S_ISGID)
+        } else {
+            (gid as i64, mode)
+        };
+
         self.transaction(|this| {
             let now = current_timestamp();
             let mut file = FileRow {
@@ -326,15 +452,19 @@ impl SqlFileSystem {
                 kind: FILE_KIND_DIRECTORY,
                 name: name.to_string(),
                 uid: uid as i64,
-                gid: gid as i64,
+                gid,
                 perms: mode as i64,
+                rdev: 0,
                 size: 0,
+                stored_size: 0,
+                allocated_size: 0,
                 sha512: "".to_string(),
                 encryption_key: "".to_string(),
                 compression: "".to_string(),
                 accessed_at: if this.config.update_access_time { now } else { 0 },
                 created_at: now,
                 updated_at: now,
+                changed_at: now,
             };
 
             let id = this.sql.add_file(&file)?;
@@ -380,15 +510,25 @@ impl SqlFileSystem {
         })
     }
 
-    pub fn mknod(&mut self, parent: i64, name: &str, uid: u32, gid: u32, mode: u32) -> Result<FileRow, SqlFileSystemError> {
+    pub fn mknod(&self, parent: i64, name: &str, uid: u32, gid: u32, mode: u32, rdev: u32) -> Result<FileRow, SqlFileSystemError> {
         if !self.is_validate_file_name(name) {
             return error(EINVAL, anyhow!("Invalid file name: {}", name));
         }
 
-        let regular = mode & libc::S_IFREG != 0;
-        if !regular {
-            return error(ENOTSUP, anyhow!("Only regular files are supported in mknod: mode = {}", mode));
-        }
+        // A zero file type means a regular file
+        let kind = match mode & libc::S_IFMT {
+            0 | libc::S_IFREG => FILE_KIND_REGULAR,
+            libc::S_IFIFO => FILE_KIND_FIFO,
+            libc::S_IFSOCK => FILE_KIND_SOCKET,
+            libc::S_IFCHR => FILE_KIND_CHAR_DEVICE,
+            libc::S_IFBLK => FILE_KIND_BLOCK_DEVICE,
+            _ => {
+                return error(ENOTSUP, anyhow!("Unsupported file type in mknod: mode = {}", mode));
+            }
+        };
+
+        // Only devices have a device number
+        let rdev = if kind == FILE_KIND_CHAR_DEVICE || kind == FILE_KIND_BLOCK_DEVICE { rdev as i64 } else { 0 };
 
         let parent_directory = self.get_file_or_err(parent)?;
 
@@ -402,23 +542,30 @@ impl SqlFileSystem {
             return error(EEXIST, anyhow!("File already exists: {}", name));
         }
 
+        // Files created inside a setgid directory take its group
+        let gid = if parent_directory.perms & S_ISGID as i64 != 0 { parent_directory.gid } else { gid as i64 };
+
         let id = self.transaction(|this| {
             let now = current_timestamp();
             let mut file = FileRow {
                 id: 0,
                 version: 1,
-                kind: FILE_KIND_REGULAR,
+                kind,
                 name: name.to_string(),
                 uid: uid as i64,
-                gid: gid as i64,
+                gid,
                 perms: mode as i64,
+                rdev,
                 size: 0,
+                stored_size: 0,
+                allocated_size: 0,
                 sha512: "".to_string(),
                 encryption_key: "".to_string(),
                 compression: "".to_string(),
                 accessed_at: if this.config.update_access_time { now } else { 0 },
                 created_at: now,
                 updated_at: now,
+                changed_at: now,
             };
 
             let id = this.sql.add_file(&file)?;
@@ -447,7 +594,7 @@ impl SqlFileSystem {
         self.get_file_or_err(id)
     }
 
-    pub fn unlink(&mut self, parent: i64, name: &str) -> Result<(), SqlFileSystemError> {
+    pub fn unlink(&self, parent: i64, name: &str) -> Result<(), SqlFileSystemError> {
         if !self.is_validate_file_name(name) {
             return error(EINVAL, anyhow!("Invalid file name: {}", name));
         }
@@ -460,9 +607,7 @@ impl SqlFileSystem {
         }
 
         let parent_directory = self.get_file_or_err(parent)?;
-        let full_path = self.sql.get_file_path(file.id)?;
-        self.storage.remove(&file, &full_path)?;
-        self.sql.remove_file(dir_entry.entry_file_id)?;
+        let file = self.remove_entry(&dir_entry)?;
 
         if self.config.store_file_change_history {
             self.sql.register_file_change(&file, FileChangeKind::Deleted)?;
@@ -472,7 +617,39 @@ impl SqlFileSystem {
         Ok(())
     }
 
-    pub fn rmdir(&mut self, parent: i64, name: &str) -> Result<(), SqlFileSystemError> {
+    /// Remove the entry of a file that is not a directory. Open files keep their row and contents until the last handle
+    /// is released, so they can still be read, written and linked again
+    fn remove_entry(&self, entry: &DirectoryEntry) -> Result<FileRow, SqlFileSystemError> {
+        let file = self.get_file_or_err(entry.entry_file_id)?;
+        let full_path = self.sql.get_file_path(file.id)?;
+
+        // Objects stored by path are moved aside, another file can take the name meanwhile
+        if self.storage.is_open(file.id) {
+            self.storage.rename(&file, &full_path, &unlinked_file_path(file.id))?;
+        }
+        self.remove_entry_row(entry, file, &full_path)
+    }
+
+    /// Metadata changes of `remove_entry`, the object of an open file must have been moved aside already
+    fn remove_entry_row(&self, entry: &DirectoryEntry, mut file: FileRow, full_path: &str) -> Result<FileRow, SqlFileSystemError> {
+        if self.storage.is_open(file.id) {
+            self.sql.remove_directory_entry(entry.id)?;
+            file.changed_at = current_timestamp();
+            self.sql.update_file(&file)?;
+        } else {
+            // Only queued, the object is removed by the next cleanup
+            self.storage.remove(&file, full_path)?;
+            self.sql.remove_file(file.id)?;
+        }
+        Ok(file)
+    }
+
+    /// Whether a file has no entry in any directory, only files unlinked while open
+    pub fn is_unlinked(&self, id: i64) -> Result<bool, SqlFileSystemError> {
+        Ok(id != ROOT_DIRECTORY_ID && self.sql.find_parent_directory(id)?.is_none())
+    }
+
+    pub fn rmdir(&self, parent: i64, name: &str) -> Result<(), SqlFileSystemError> {
         if !self.is_validate_file_name(name) {
             return error(EINVAL, anyhow!("Invalid file name: {}", name));
         }
@@ -503,7 +680,7 @@ impl SqlFileSystem {
         Ok(())
     }
 
-    pub fn rename(&mut self, parent: i64, old_name: &str, new_name: &str) -> Result<(), SqlFileSystemError> {
+    pub fn rename(&self, parent: i64, old_name: &str, new_name: &str) -> Result<(), SqlFileSystemError> {
         if !self.is_validate_file_name(old_name) {
             return error(EINVAL, anyhow!("Invalid file name: {}", old_name));
         }
@@ -520,17 +697,18 @@ impl SqlFileSystem {
                 FILE_KIND_DIRECTORY => {
                     return error(EISDIR, anyhow!("Cannot overwrite directory: {} -> {}", old_name, new_name));
                 }
-                FILE_KIND_REGULAR => {
+                _ => {
                     // When moving into an existing file, unlink it first
                     self.unlink(parent, new_name)?;
                 }
-                _ => {
-                    return error(EEXIST, anyhow!("File already exists: {}", new_name));
-                }
             }
         }
 
-        self.transaction(|this| {
+        let file = self.get_file_or_err(entry.entry_file_id)?;
+        let prev_path = self.sql.get_file_path(file.id)?;
+        let new_path = format!("{}/{}", self.sql.get_file_path(parent)?, new_name);
+
+        self.with_renamed_objects(&[(&file, &prev_path, &new_path)], |this| {
             let parent_directory = this.get_file_or_err(parent)?;
             let mut entry = entry;
 
@@ -538,28 +716,92 @@ impl SqlFileSystem {
             this.sql.update_directory_entry(&entry)?;
 
             let mut file = this.get_file_or_err(entry.entry_file_id)?;
-            let prev_path = this.sql.get_file_path(file.id)?;
+            file.name = new_name.to_string();
+            file.changed_at = current_timestamp();
+            this.sql.update_file(&file)?;
+
+            if this.config.store_file_change_history {
+                this.sql.register_file_change(&file, FileChangeKind::UpdatedContents)?;
+                this.sql.register_file_change(&parent_directory, FileChangeKind::UpdatedContents)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Swap two existing entries (RENAME_EXCHANGE), each name ends up pointing to the file of the other one
+    pub fn exchange(&self, parent: i64, name: &str, new_parent: i64, new_name: &str) -> Result<(), SqlFileSystemError> {
+        if !self.is_validate_file_name(name) {
+            return error(EINVAL, anyhow!("Invalid file name: {}", name));
+        }
+
+        if !self.is_validate_file_name(new_name) {
+            return error(EINVAL, anyhow!("Invalid file name: {}", new_name));
+        }
+
+        let entry = self.find_directory_entry_or_err(parent, name)?;
+        let new_entry = self.find_directory_entry_or_err(new_parent, new_name)?;
+
+        if entry.entry_file_id == new_entry.entry_file_id {
+            return Ok(());
+        }
+
+        let file = self.get_file_or_err(entry.entry_file_id)?;
+        let new_file = self.get_file_or_err(new_entry.entry_file_id)?;
+        let path = self.sql.get_file_path(file.id)?;
+        let new_path = self.sql.get_file_path(new_file.id)?;
+
+        // When objects are stored by path, one of them is moved aside so the other is not overwritten
+        let temp_path = format!("{}.exchange-{}", path, file.id);
+        let renames = [(&file, path.as_str(), temp_path.as_str()), (&new_file, new_path.as_str(), path.as_str()), (&file, temp_path.as_str(), new_path.as_str())];
+
+        self.with_renamed_objects(&renames, |this| {
+            let (mut entry, mut new_entry) = (entry, new_entry);
+            let mut file = this.get_file_or_err(entry.entry_file_id)?;
+            let mut new_file = this.get_file_or_err(new_entry.entry_file_id)?;
+
+            std::mem::swap(&mut entry.entry_file_id, &mut new_entry.entry_file_id);
+            std::mem::swap(&mut entry.kind, &mut new_entry.kind);
+            this.sql.update_directory_entry(&entry)?;
+            this.sql.update_directory_entry(&new_entry)?;
 
             file.name = new_name.to_string();
+            new_file.name = name.to_string();
+            file.changed_at = current_timestamp();
+            new_file.changed_at = file.changed_at;
             this.sql.update_file(&file)?;
+            this.sql.update_file(&new_file)?;
+            if new_parent != parent {
+                this.set_parent_directory(&file, new_parent)?;
+                this.set_parent_directory(&new_file, parent)?;
+            }
 
-            let new_path = this.sql.get_file_path(file.id)?;
-            this.storage.rename(&file, &prev_path, &new_path)?;
+            if this.config.update_access_time {
+                let now = current_timestamp();
+                this.sql.file_set_access_time(parent, now)?;
+                this.sql.file_set_access_time(new_parent, now)?;
+            }
 
             if this.config.store_file_change_history {
                 this.sql.register_file_change(&file, FileChangeKind::UpdatedContents)?;
+                this.sql.register_file_change(&new_file, FileChangeKind::UpdatedContents)?;
+                let parent_directory = this.get_file_or_err(parent)?;
                 this.sql.register_file_change(&parent_directory, FileChangeKind::UpdatedContents)?;
+                if new_parent != parent {
+                    let new_parent_directory = this.get_file_or_err(new_parent)?;
+                    this.sql.register_file_change(&new_parent_directory, FileChangeKind::UpdatedContents)?;
+                }
             }
 
             Ok(())
         })
     }
 
-    pub fn open(&mut self, id: i64, flags: u32) -> Result<(), SqlFileSystemError> {
+    pub fn open(&self, id: i64, read_only: bool) -> Result<(), SqlFileSystemError> {
         let mut file = self.get_file_or_err(id)?;
 
         let full_path = self.sql.get_file_path(file.id)?;
-        let modified = self.storage.open(&mut file, &full_path, flags).context("Error opening file")?;
+        let modified = self.storage.open(&mut file, &full_path, read_only).context("Error opening file")?;
 
         file.accessed_at = current_timestamp();
 
@@ -576,7 +818,7 @@ impl SqlFileSystem {
         Ok(())
     }
 
-    pub fn read(&mut self, id: i64, offset: i64, size: usize) -> Result<Vec<u8>, SqlFileSystemError> {
+    pub fn read(&self, id: i64, offset: i64, size: usize) -> Result<Vec<u8>, SqlFileSystemError> {
         let file = self.get_file_or_err(id)?;
 
         let mut buff = vec![0u8; size];
@@ -585,45 +827,104 @@ impl SqlFileSystem {
         Ok(buff)
     }
 
-    pub fn write(&mut self, id: i64, offset: i64, data: &[u8]) -> Result<usize, SqlFileSystemError> {
+    pub fn write(&self, id: i64, offset: i64, data: &[u8]) -> Result<usize, SqlFileSystemError> {
         let file = self.get_file_or_err(id)?;
 
         let len = self.storage.write(&file, offset as u64, data)?;
         Ok(len)
     }
 
-    pub fn flush(&mut self, id: i64) -> Result<(), SqlFileSystemError> {
+    /// Allocate, zero or deallocate a range of an open file, holes are not stored in the objects
+    pub fn fallocate(&self, id: i64, offset: u64, length: u64, mode: i32) -> Result<(), SqlFileSystemError> {
+        let supported = FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE;
+
+        if mode & !supported != 0 {
+            return error(EOPNOTSUPP, anyhow!("Unsupported fallocate mode: {:#x}", mode));
+        }
+        // Same rules as the kernel, punching a hole never changes the size and can not be combined with zeroing
+        if mode & FALLOC_FL_PUNCH_HOLE != 0 && (mode & FALLOC_FL_KEEP_SIZE == 0 || mode & FALLOC_FL_ZERO_RANGE != 0) {
+            return error(EOPNOTSUPP, anyhow!("Invalid fallocate mode: {:#x}", mode));
+        }
+        if length == 0 || offset.checked_add(length).is_none() {
+            return error(EINVAL, anyhow!("Invalid fallocate range: {} + {}", offset, length));
+        }
+
+        let file = self.get_file_or_err(id)?;
+        if file.kind != FILE_KIND_REGULAR {
+            return error(ENODEV, anyhow!("Not a regular file: {}", id));
+        }
+
+        self.storage.fallocate(&file, offset, length, mode)?;
+        Ok(())
+    }
+
+    /// Offset of the next data (SEEK_DATA) or hole (SEEK_HOLE) of an open file
+    pub fn seek(&self, id: i64, offset: i64, whence: i32) -> Result<i64, SqlFileSystemError> {
+        if offset < 0 {
+            return error(EINVAL, anyhow!("Invalid offset: {}", offset));
+        }
+        if whence != SEEK_DATA && whence != SEEK_HOLE {
+            return error(EINVAL, anyhow!("Unsupported seek mode: {}", whence));
+        }
+
+        let file = self.get_file_or_err(id)?;
+        match self.storage.seek(&file, offset as u64, whence)? {
+            Some(offset) => Ok(offset as i64),
+            None => error(ENXIO, anyhow!("Offset {} is past the last data of file {}", offset, id)),
+        }
+    }
+
+    pub fn flush(&self, id: i64) -> Result<(), SqlFileSystemError> {
         let mut file = self.get_file_or_err(id)?;
         let modified = self.storage.flush(&mut file)?;
 
         if modified {
-            self.sql.update_file(&file)?;
-
-            if self.config.store_file_change_history {
-                self.sql.register_file_change(&file, FileChangeKind::UpdatedContents)?;
-            }
+            self.contents_stored(&file)?;
         }
 
         Ok(())
     }
 
-    pub fn release(&mut self, id: i64) -> Result<(), SqlFileSystemError> {
+    pub fn release(&self, id: i64) -> Result<(), SqlFileSystemError> {
         let mut file = self.get_file_or_err(id)?;
         let modified = self.storage.close(&mut file)?;
 
         if modified {
-            self.sql.update_file(&file)?;
+            self.contents_stored(&file)?;
+        }
 
-            if self.config.store_file_change_history {
-                self.sql.register_file_change(&file, FileChangeKind::UpdatedContents)?;
-            }
+        // The last handle of a file without links removes it
+        if !self.storage.is_open(id) && self.is_unlinked(id)? {
+            self.storage.remove(&file, &unlinked_file_path(id))?;
+            self.sql.remove_file(id)?;
         }
 
         self.cleanup()?;
         Ok(())
     }
 
-    pub fn readdir(&mut self, id: i64, offset: i64) -> Result<Vec<DirectoryEntry>, SqlFileSystemError> {
+    /// Save the new contents of a file, the row was read before storing them so only the fields of the contents are
+    /// written, other requests may have changed the rest in the meantime
+    fn contents_stored(&self, file: &FileRow) -> Result<(), SqlFileSystemError> {
+        self.transaction(|this| {
+            // The object replaced is the one of the stored row, another flush may have stored contents since
+            // this row was read
+            let stored = this.get_file_or_err(file.id)?;
+            this.sql.update_file_contents(file)?;
+
+            if stored.sha512 != file.sha512 {
+                this.storage.release_object(&stored, &this.sql.get_file_path(file.id)?);
+            }
+
+            if this.config.store_file_change_history {
+                let file = this.get_file_or_err(file.id)?;
+                this.sql.register_file_change(&file, FileChangeKind::UpdatedContents)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn readdir(&self, id: i64, offset: i64) -> Result<Vec<DirectoryEntry>, SqlFileSystemError> {
         let entries = self.sql.get_directory_entries(id, 1024, offset)?;
 
         if self.config.update_access_time {
@@ -633,9 +934,22 @@ impl SqlFileSystem {
         Ok(entries)
     }
 
-    pub fn cleanup(&mut self) -> Result<(), SqlFileSystemError> {
+    /// Counters of the content cache are kept in the database, so they can be shown by other commands
+    pub fn save_cache_stats(&self) -> Result<(), SqlFileSystemError> {
+        let stats = serde_json::to_string(&self.storage.cache_stats()).context("Unable to serialize cache stats")?;
+        self.sql.set_setting(CACHE_STATS_SETTING, &stats)?;
+        Ok(())
+    }
+
+    pub fn save_retry_stats(&self) -> Result<(), SqlFileSystemError> {
+        let stats = serde_json::to_string(&retry_stats()).context("Unable to serialize retry stats")?;
+        self.sql.set_setting(RETRY_STATS_SETTING, &stats)?;
+        Ok(())
+    }
+
+    pub fn cleanup(&self) -> Result<(), SqlFileSystemError> {
         let sql = self.sql.clone();
-        self.storage.cleanup(Rc::new(move |info, test| {
+        self.storage.cleanup(Arc::new(move |info, test| {
             let exists = match test {
                 UniquenessTest::Path => {
                     sql.get_file_by_path(&info.full_path)?.is_some()
@@ -649,7 +963,7 @@ impl SqlFileSystem {
         Ok(())
     }
 
-    pub fn get_file_or_err(&mut self, id: i64) -> Result<FileRow, SqlFileSystemError> {
+    pub fn get_file_or_err(&self, id: i64) -> Result<FileRow, SqlFileSystemError> {
         let file = self.sql.get_file(id)?;
 
         if file.is_none() {
@@ -659,7 +973,7 @@ impl SqlFileSystem {
         Ok(file.unwrap())
     }
 
-    pub fn find_directory_entry_or_err(&mut self, id: i64, name: &str) -> Result<DirectoryEntry, SqlFileSystemError> {
+    pub fn find_directory_entry_or_err(&self, id: i64, name: &str) -> Result<DirectoryEntry, SqlFileSystemError> {
         let entry = self.sql.find_directory_entry(id, name)?;
 
         if entry.is_none() {
@@ -669,19 +983,78 @@ impl SqlFileSystem {
         Ok(entry.unwrap())
     }
 
+    /// Check the permissions of a file for an access mask of R_OK, W_OK and X_OK
+    pub fn check_access(&self, id: i64, cred: &Credentials, mask: i32) -> Result<FileRow, SqlFileSystemError> {
+        let file = self.get_file_or_err(id)?;
+
+        if !has_access(&file, cred, mask) {
+            return error(EACCES, anyhow!("Permission denied: file {}, uid {}, mask {}", id, cred.uid, mask));
+        }
+
+        Ok(file)
+    }
+
+    /// Only the owner of a file (or root) can change its metadata
+    pub fn check_owner(&self, id: i64, cred: &Credentials) -> Result<FileRow, SqlFileSystemError> {
+        let file = self.get_file_or_err(id)?;
+
+        if !cred.is_owner(&file) {
+            return error(EPERM, anyhow!("Operation not permitted: file {} is not owned by uid {}", id, cred.uid));
+        }
+
+        Ok(file)
+    }
+
+    /// Check the POSIX ownership rules of chown, fails with EPERM
+    pub fn check_chown(&self, id: i64, cred: &Credentials, uid: Option<u32>, gid: Option<u32>) -> Result<FileRow, SqlFileSystemError> {
+        let file = self.get_file_or_err(id)?;
+
+        if !can_chown(&file, cred, uid, gid) {
+            return error(EPERM, anyhow!("Operation not permitted: uid {} cannot chown file {} to {:?}:{:?}", cred.uid, id, uid, gid));
+        }
+
+        Ok(file)
+    }
+
+    /// Writing to a setuid/setgid file as another user removes those bits, so the content cannot be replaced while
+    /// keeping the privileges of the owner
+    pub fn clear_privileges_on_write(&self, id: i64, cred: &Credentials) -> Result<(), SqlFileSystemError> {
+        let mut file = self.get_file_or_err(id)?;
+
+        if file.perms & (S_ISUID | S_ISGID) as i64 == 0 || cred.is_owner(&file) {
+            return Ok(());
+        }
+
+        file.perms = clear_privileges(file.perms);
+        file.changed_at = current_timestamp();
+        self.sql.update_file(&file)?;
+
+        if self.config.store_file_change_history {
+            self.sql.register_file_change(&file, FileChangeKind::UpdatedMetadata)?;
+        }
+        Ok(())
+    }
+
+    /// Removing, renaming or replacing an entry needs write and search permission on the directory,
+    /// and ownership of the entry or the directory if it has the sticky bit
+    pub fn check_remove_entry(&self, parent: i64, name: &str, cred: &Credentials) -> Result<(), SqlFileSystemError> {
+        let directory = self.check_access(parent, cred, W_OK | X_OK)?;
+        let entry = self.find_directory_entry_or_err(parent, name)?;
+        let file = self.get_file_or_err(entry.entry_file_id)?;
+
+        if !can_remove_entry(&directory, &file, cred) {
+            return error(EPERM, anyhow!("Operation not permitted: sticky directory {}, uid {}", parent, cred.uid));
+        }
+
+        Ok(())
+    }
+
     pub fn is_validate_file_name(&self, name: &str) -> bool {
         name.len() > 0 && name.len() <= 255 && !name.contains("/") && name != "." && name != ".."
     }
 
-    pub fn transaction<R>(&mut self, func: impl FnOnce(&mut Self) -> Result<R, SqlFileSystemError>) -> Result<R, SqlFileSystemError> {
-        self.sql.connection.execute("BEGIN TRANSACTION").context("Database error")?;
-        let res = func(self);
-        if res.is_ok() {
-            self.sql.connection.execute("COMMIT").context("Database error")?;
-        } else {
-            self.sql.connection.execute("ROLLBACK").context("Database error")?;
-        }
-        res
+    pub fn transaction<R>(&self, func: impl FnOnce(&Self) -> Result<R, SqlFileSystemError>) -> Result<R, SqlFileSystemError> {
+        self.sql.transaction(|| func(self))
     }
 }
 
@@ -702,3 +1075,81 @@ impl Display for SqlFileSystemError {
 }
 
 impl Error for SqlFileSystemError {}
+
+/// Filesystem in a new temporary directory, the storage settings are added to the primary section of the config
+#[cfg(test)]
+pub fn test_file_system(name: &str, storage: &str) -> SqlFileSystem {
+    use crate::obj_storage::create_object_storage;
+    use crate::storage_interface::StorageInterface;
+
+    let dir = std::env::temp_dir().join(format!("innerfs-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("config.yml");
+    std::fs::write(&config_path, format!(
+        "database_file: {0}/index.db\nmount_point: {0}/data\ncache_directory: {0}/cache\nprimary:\n  storage_backend: filesystem\n  blob_storage: {0}/blob\n{1}",
+        dir.display(), storage,
+    )).unwrap();
+
+    let config = crate::config::read_config(&config_path).unwrap();
+    let sql = Arc::new(MetadataDB::open(&config.database_file));
+    sql.run_migrations().unwrap();
+    let obj_storage = create_object_storage(config.primary.clone(), sql.clone());
+    let storage = Box::new(StorageInterface::new(obj_storage, config.cache_memory_limit, dir.join("cache/spill")));
+    SqlFileSystem::new(sql, config, storage)
+}
+
+/// Small tree to test the queries over the index: two files with the same contents in different directories, and a
+/// file whose contents were replaced by shorter ones
+#[cfg(test)]
+pub fn test_fixture_tree(fs: &SqlFileSystem) {
+    let photos = fs.mkdir(ROOT_DIRECTORY_ID, "photos", 0, 0, 0o755).unwrap();
+    let year = fs.mkdir(photos.id, "2024", 0, 0, 0o755).unwrap();
+    let docs = fs.mkdir(ROOT_DIRECTORY_ID, "docs", 0, 0, 0o755).unwrap();
+    fs.mkdir(ROOT_DIRECTORY_ID, "empty", 0, 0, 0o755).unwrap();
+
+    for (parent, name) in [(photos.id, "a.jpg"), (year.id, "b.jpg")] {
+        let file = fs.mknod(parent, name, 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_all(file.id, b"same content").unwrap();
+    }
+
+    let notes = fs.mknod(docs.id, "notes.txt", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(notes.id, b"first version of the notes").unwrap();
+    fs.write_all(notes.id, b"second").unwrap();
+}
+
+#[test]
+fn test_concurrent_flushes_release_replaced_objects() {
+    let fs = test_file_system("flushes", "  use_hash_as_filename: true\n");
+    let file = fs.mknod(ROOT_DIRECTORY_ID, "file", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(file.id, b"first").unwrap();
+
+    // Two flushes of the same file that read the row before either of them stored the contents
+    fs.open(file.id, false).unwrap();
+    let stale = fs.get_file_or_err(file.id).unwrap();
+    for contents in [b"second", b"third!"] {
+        fs.write(file.id, 0, contents).unwrap();
+        let mut file = stale.clone();
+        assert!(fs.storage.flush(&mut file).unwrap());
+        fs.contents_stored(&file).unwrap();
+    }
+    fs.release(file.id).unwrap();
+
+    // Only the object of the last contents is left
+    let file = fs.get_file_or_err(file.id).unwrap();
+    let objects = std::fs::read_dir(&fs.config.primary.blob_storage).unwrap().count();
+    assert_eq!(objects, 1);
+    assert_eq!(fs.read_all(file.id).unwrap(), b"third!");
+}
+
+#[test]
+fn test_rewrite_keeps_object_stored_by_path() {
+    let fs = test_file_system("rewrite", "");
+    let dir = fs.mkdir(ROOT_DIRECTORY_ID, "dir", 0, 0, 0o755).unwrap();
+    let file = fs.mknod(dir.id, "file", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+    fs.write_all(file.id, b"first").unwrap();
+
+    // The new contents are stored in the same path, the replaced object is still in use
+    fs.write_all(file.id, b"second").unwrap();
+    assert_eq!(fs.read_all(file.id).unwrap(), b"second");
+}
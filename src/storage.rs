@@ -1,18 +1,42 @@
-use std::rc::Rc;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
 use crate::AnyError;
 use crate::obj_storage::{ObjInfo, UniquenessTest};
 use crate::metadata_db::{FileRow};
 
-pub type ObjInUseFn = Rc<dyn Fn(&ObjInfo, UniquenessTest) -> Result<bool, AnyError>>;
+pub type ObjInUseFn = Arc<dyn Fn(&ObjInfo, UniquenessTest) -> Result<bool, AnyError> + Send + Sync>;
 
-pub trait Storage {
-    fn open(&mut self, file: &mut FileRow, full_path: &str, mode: u32) -> Result<bool, AnyError>;
-    fn read(&mut self, file: &FileRow, offset: u64, buff: &mut [u8]) -> Result<usize, AnyError>;
-    fn write(&mut self, file: &FileRow, offset: u64, buff: &[u8]) -> Result<usize, AnyError>;
-    fn close(&mut self, file: &mut FileRow) -> Result<bool, AnyError>;
-    fn flush(&mut self, file: &mut FileRow) -> Result<bool, AnyError>;
-    fn remove(&mut self, file: &FileRow, full_path: &str) -> Result<(), AnyError>;
-    fn rename(&mut self, file: &FileRow, prev_full_path: &str, new_full_path: &str) -> Result<(), AnyError>;
-    fn cleanup(&mut self, is_in_use: ObjInUseFn) -> Result<(), AnyError>;
-    fn nuke(&mut self) -> Result<(), AnyError>;
+/// Counters of the cache of open file contents
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheStats {
+    // Contents found in memory
+    pub hits: u64,
+    // Contents downloaded from the object storage
+    pub misses: u64,
+    // Dirty contents moved to disk to stay in the memory limit, and read back later
+    pub spills: u64,
+    pub spill_reads: u64,
+    // Clean contents dropped to stay in the memory limit
+    pub evictions: u64,
+}
+
+/// Contents of the files, shared by all the threads that serve filesystem requests
+pub trait Storage: Send + Sync {
+    fn open(&self, file: &mut FileRow, full_path: &str, read_only: bool) -> Result<bool, AnyError>;
+    fn read(&self, file: &FileRow, offset: u64, buff: &mut [u8]) -> Result<usize, AnyError>;
+    fn write(&self, file: &FileRow, offset: u64, buff: &[u8]) -> Result<usize, AnyError>;
+    fn close(&self, file: &mut FileRow) -> Result<bool, AnyError>;
+    fn flush(&self, file: &mut FileRow) -> Result<bool, AnyError>;
+    fn remove(&self, file: &FileRow, full_path: &str) -> Result<(), AnyError>;
+    // Object of contents that were replaced, removed on the next cleanup if no other file uses it
+    fn release_object(&self, file: &FileRow, full_path: &str);
+    fn rename(&self, file: &FileRow, prev_full_path: &str, new_full_path: &str) -> Result<(), AnyError>;
+    fn truncate(&self, file: &mut FileRow, full_path: &str, size: u64) -> Result<bool, AnyError>;
+    fn fallocate(&self, file: &FileRow, offset: u64, length: u64, mode: i32) -> Result<(), AnyError>;
+    fn seek(&self, file: &FileRow, offset: u64, whence: i32) -> Result<Option<u64>, AnyError>;
+    fn is_open(&self, id: i64) -> bool;
+    fn share(&self, src: &FileRow, dst: &mut FileRow, dst_full_path: &str) -> Result<(), AnyError>;
+    fn cleanup(&self, is_in_use: ObjInUseFn) -> Result<(), AnyError>;
+    fn nuke(&self) -> Result<(), AnyError>;
+    fn cache_stats(&self) -> CacheStats;
 }
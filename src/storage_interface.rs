@@ -1,215 +1,396 @@
-use std::cmp::min;
 use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use anyhow::anyhow;
-use libc::{O_APPEND, O_RDONLY};
+use libc::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, SEEK_DATA, SEEK_HOLE};
+use log::warn;
 use crate::AnyError;
 use crate::obj_storage::{ObjInfo, ObjectStorage};
-use crate::metadata_db::{FileRow, FILE_KIND_DIRECTORY};
-use crate::fuse_fs::OpenFlags;
-use crate::storage::{ObjInUseFn, Storage};
+use crate::metadata_db::{FileRow, FILE_KIND_REGULAR};
+use crate::sparse::{SparseBuffer, SPARSE_MAGIC};
+use crate::storage::{CacheStats, ObjInUseFn, Storage};
 use crate::utils::current_timestamp;
 
+/// Minimum bytes fetched by each range read, so sequential reads do not send a request for each block
+const RANGE_READ_SIZE: u64 = 1024 * 1024;
+
 pub struct StorageInterface {
     pub obj_storage: Box<dyn ObjectStorage>,
-    pub cache: HashMap<i64, StorageInterfaceCache>,
-    pub pending_remove: HashSet<ObjInfo>,
+    // Open files, the contents of each one have their own lock so a slow transfer only blocks the requests to that file
+    pub cache: Mutex<HashMap<i64, CacheEntry>>,
+    pub pending_remove: Mutex<HashSet<ObjInfo>>,
+    // Bytes of contents kept in memory, beyond it the least recently used are dropped or spilled to disk, 0 for no limit
+    pub memory_limit: u64,
+    pub spill_directory: PathBuf,
+    memory_used: AtomicU64,
+    clock: AtomicU64,
+    counters: CacheCounters,
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    spills: AtomicU64,
+    spill_reads: AtomicU64,
+    evictions: AtomicU64,
 }
 
+/// Open file and the number of handles to it
+pub struct CacheEntry {
+    pub count: i32,
+    pub contents: Arc<Mutex<StorageInterfaceCache>>,
+}
+
+/// Contents of an open file, shared by all its handles
 pub struct StorageInterfaceCache {
     pub full_path: String,
-    pub mode: i32,
-    pub content: Vec<u8>,
+    pub content: SparseBuffer,
     pub retrieved: bool,
     pub modified: bool,
-    pub count: i32,
+    // Unsaved contents moved out of memory, the file is already unlinked so nothing is left behind on a crash
+    pub spilled: Option<File>,
+    // While all the handles are read-only, stored objects without encoding are read by ranges instead of whole
+    pub read_only: bool,
+    pub raw: Option<bool>,
+    pub window: Option<(u64, Vec<u8>)>,
+    // Bytes counted in the memory used, and the last time the contents were used
+    memory: u64,
+    last_used: u64,
 }
 
 impl StorageInterface {
-    pub fn new(obj_storage: Box<dyn ObjectStorage>) -> Self {
+    pub fn new(obj_storage: Box<dyn ObjectStorage>, memory_limit: u64, spill_directory: PathBuf) -> Self {
         Self {
             obj_storage,
-            cache: HashMap::new(),
-            pending_remove: HashSet::new(),
+            cache: Mutex::new(HashMap::new()),
+            pending_remove: Mutex::new(HashSet::new()),
+            memory_limit,
+            spill_directory,
+            memory_used: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+            counters: CacheCounters::default(),
         }
     }
-}
 
-impl Storage for StorageInterface {
-    fn open(&mut self, file: &mut FileRow, full_path: &str, mode: u32) -> Result<bool, AnyError> {
-        if (mode as i32) & O_APPEND != 0 {
-            return Err(anyhow::anyhow!("Append mode is not supported"));
+    /// Fetch the contents the first time they are needed, or after they were moved out of memory
+    fn load(&self, row: &mut StorageInterfaceCache, file: &FileRow) -> Result<(), AnyError> {
+        if row.retrieved {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
         }
 
-        // Allow multiple read-only opens
-        {
-            let prev = self.cache.get_mut(&file.id);
-
-            if let Some(cache) = prev {
-                let prev_flags = OpenFlags::from(cache.mode);
-                let new_flags = OpenFlags::from(mode as i32);
-                // Only allowed to open in read mode if it was previously opened in read mode
-                let valid = prev_flags.read_only && new_flags.read_only && !prev_flags.exclusive && !new_flags.exclusive;
-
-                if !valid {
-                    return Err(anyhow!(
-                        "File {} is already open in write mode:\n  prev={:?},\n  new={:?}",
-                        file.id, prev_flags, new_flags)
-                    );
-                }
+        if let Some(spill) = row.spilled.as_mut() {
+            let mut data = vec![];
+            spill.seek(SeekFrom::Start(0))?;
+            spill.read_to_end(&mut data)?;
+            row.content = SparseBuffer::from_object(data)?;
+            row.spilled = None;
+            self.counters.spill_reads.fetch_add(1, Ordering::Relaxed);
+        } else if !file.sha512.is_empty() {
+            let info = ObjInfo::new(file, &row.full_path);
+            row.content = SparseBuffer::from_object(self.obj_storage.get(&info)?)?;
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        row.retrieved = true;
+        row.window = None;
+        Ok(())
+    }
 
-                cache.count += 1;
-            }
+    /// Read part of the stored object without fetching the rest, if the file allows it
+    fn read_range(&self, file: &FileRow, offset: u64, buff: &mut [u8]) -> Result<Option<usize>, AnyError> {
+        // The object has the contents as they are, unless it starts with the sparse encoding header
+        if file.sha512.is_empty() || file.allocated_size != file.size {
+            return Ok(None);
         }
 
-        self.cache.insert(file.id, StorageInterfaceCache {
-            full_path: full_path.to_string(),
-            mode: mode as i32,
-            content: vec![],
-            retrieved: false,
-            modified: false,
-            count: 1,
-        });
+        let contents = self.contents(file)?;
+        let read = {
+            let mut row = contents.lock().unwrap();
+            if !row.read_only || row.retrieved || row.modified || row.raw == Some(false) {
+                return Ok(None);
+            }
+            let info = ObjInfo::new(file, &row.full_path);
+            if !self.obj_storage.partial_reads(&info) {
+                return Ok(None);
+            }
 
-        Ok(false)
+            let size = file.size as u64;
+            let end = offset.saturating_add(buff.len() as u64).min(size);
+            if offset >= end {
+                return Ok(Some(0));
+            }
+
+            let in_window = row.window.as_ref().is_some_and(|(start, data)| *start <= offset && start + data.len() as u64 >= end);
+            if in_window {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            } else {
+                let data = self.obj_storage.get_range(&info, offset, (end - offset).max(RANGE_READ_SIZE))?;
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+
+                if row.raw.is_none() {
+                    let encoded = match offset {
+                        0 => SparseBuffer::is_encoded(&data),
+                        _ => SparseBuffer::is_encoded(&self.obj_storage.get_range(&info, 0, SPARSE_MAGIC.len() as u64)?),
+                    };
+                    row.raw = Some(!encoded);
+                    if row.raw == Some(false) {
+                        return Ok(None);
+                    }
+                }
+                row.window = Some((offset, data));
+            }
+
+            let (start, data) = row.window.as_ref().unwrap();
+            let from = (offset - start) as usize;
+            let len = ((end - offset) as usize).min(data.len() - from);
+            buff[..len].copy_from_slice(&data[from..from + len]);
+            self.touch(&mut row);
+            len
+        };
+
+        self.reclaim(file.id);
+        Ok(Some(read))
     }
 
-    fn read(&mut self, file: &FileRow, offset: u64, buff: &mut [u8]) -> Result<usize, AnyError> {
-        let row = self.cache.get_mut(&file.id).ok_or_else(||
-            anyhow!("Trying to use a file that was closed or never opened: {}", file.id)
-        )?;
+    /// Update the memory counted for the contents, after they were used
+    fn touch(&self, row: &mut StorageInterfaceCache) {
+        let memory = match &row.window {
+            _ if row.retrieved => row.content.allocated(),
+            Some((_, data)) => data.len() as u64,
+            None => 0,
+        };
+        self.memory_used.fetch_add(memory, Ordering::Relaxed);
+        self.memory_used.fetch_sub(row.memory, Ordering::Relaxed);
+        row.memory = memory;
+        row.last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Run an operation on the loaded contents of an open file
+    fn with_contents<R>(&self, file: &FileRow, func: impl FnOnce(&mut StorageInterfaceCache) -> Result<R, AnyError>) -> Result<R, AnyError> {
+        let contents = self.contents(file)?;
+        let res = {
+            let mut row = contents.lock().unwrap();
+            let res = self.load(&mut row, file).and_then(|_| func(&mut row));
+            self.touch(&mut row);
+            res
+        };
+
+        self.reclaim(file.id);
+        res
+    }
 
-        if row.mode & libc::O_WRONLY != 0 {
-            return Err(anyhow::anyhow!("File is write-only ({})", file.name));
+    /// Drop or spill the least recently used contents until the memory used is within the limit.
+    /// The contents of the file in use are kept, as well as the ones locked by other requests.
+    fn reclaim(&self, keep: i64) {
+        if self.memory_limit == 0 || self.memory_used.load(Ordering::Relaxed) <= self.memory_limit {
+            return;
         }
 
-        if !row.retrieved {
-            let content = if !file.sha512.is_empty() {
-                let info = ObjInfo::new(file, &row.full_path);
-                self.obj_storage.get(&info)?
-            } else {
-                vec![]
+        let entries: Vec<Arc<Mutex<StorageInterfaceCache>>> = self.cache.lock().unwrap()
+            .iter()
+            .filter(|(id, _)| **id != keep)
+            .map(|(_, entry)| entry.contents.clone())
+            .collect();
+
+        let mut candidates: Vec<(bool, u64, Arc<Mutex<StorageInterfaceCache>>)> = entries.into_iter()
+            .filter_map(|contents| {
+                let row = contents.try_lock().ok()?;
+                (row.memory > 0).then(|| (row.modified, row.last_used, contents.clone()))
+            })
+            .collect();
+
+        // Clean contents can be fetched again, so they go before the ones that need to be written to disk
+        candidates.sort_by_key(|(modified, last_used, _)| (*modified, *last_used));
+
+        for (_, _, contents) in candidates {
+            if self.memory_used.load(Ordering::Relaxed) <= self.memory_limit {
+                break;
+            }
+            let Ok(mut row) = contents.try_lock() else {
+                continue;
             };
-            row.content = content;
-            row.retrieved = true;
+            if let Err(e) = self.evict(&mut row) {
+                warn!("Unable to move {} out of memory: {}", row.full_path, e);
+            }
         }
+    }
 
-        if offset >= row.content.len() as u64 {
-            return Ok(0);
+    fn evict(&self, row: &mut StorageInterfaceCache) -> Result<(), AnyError> {
+        if row.modified && row.retrieved {
+            fs::create_dir_all(&self.spill_directory)?;
+            let path = self.spill_directory.join(format!("{}-{}.spill", process::id(), self.clock.fetch_add(1, Ordering::Relaxed)));
+            let mut spill = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+            fs::remove_file(&path)?;
+            spill.write_all(&row.content.to_object())?;
+            row.spilled = Some(spill);
+            self.counters.spills.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
         }
 
-        let remaining_content_slice = &row.content[offset as usize..];
-        let read_len = min(buff.len(), remaining_content_slice.len());
-        buff[..read_len].copy_from_slice(&remaining_content_slice[..read_len]);
-        Ok(read_len)
+        row.content = SparseBuffer::new();
+        row.retrieved = false;
+        row.window = None;
+        self.memory_used.fetch_sub(row.memory, Ordering::Relaxed);
+        row.memory = 0;
+        Ok(())
     }
 
-    fn write(&mut self, file: &FileRow, offset: u64, buff: &[u8]) -> Result<usize, AnyError> {
-        let row = self.cache.get_mut(&file.id).ok_or_else(||
+    fn contents(&self, file: &FileRow) -> Result<Arc<Mutex<StorageInterfaceCache>>, AnyError> {
+        let cache = self.cache.lock().unwrap();
+        let entry = cache.get(&file.id).ok_or_else(||
             anyhow!("Trying to use a file that was closed or never opened: {}", file.id)
         )?;
+        Ok(entry.contents.clone())
+    }
+}
 
-        if row.mode & O_RDONLY != 0 {
-            return Err(anyhow::anyhow!("File is read-only"));
-        }
-
-        if row.retrieved {
-            row.content.clear();
-            row.retrieved = false;
+impl Storage for StorageInterface {
+    fn open(&self, file: &mut FileRow, full_path: &str, read_only: bool) -> Result<bool, AnyError> {
+        let mut cache = self.cache.lock().unwrap();
+
+        // Every handle of a file uses the same contents, the access mode of each handle is checked by the caller
+        if let Some(entry) = cache.get_mut(&file.id) {
+            entry.count += 1;
+            entry.contents.lock().unwrap().read_only &= read_only;
+            return Ok(false);
         }
 
-        let offset = offset as usize;
+        cache.insert(file.id, CacheEntry {
+            count: 1,
+            contents: Arc::new(Mutex::new(StorageInterfaceCache {
+                full_path: full_path.to_string(),
+                content: SparseBuffer::new(),
+                retrieved: false,
+                modified: false,
+                spilled: None,
+                read_only,
+                raw: None,
+                window: None,
+                memory: 0,
+                last_used: 0,
+            })),
+        });
 
+        Ok(false)
+    }
 
-        if offset == buff.len() {
-            // Append to the end
-            row.content.extend(buff.iter());
-        } else {
-            // Overwrite
-            if offset + buff.len() > row.content.len() {
-                row.content.resize(offset + buff.len(), 0);
-            }
-            row.content[offset..offset + buff.len()].copy_from_slice(buff);
+    fn read(&self, file: &FileRow, offset: u64, buff: &mut [u8]) -> Result<usize, AnyError> {
+        if let Some(len) = self.read_range(file, offset, buff)? {
+            return Ok(len);
         }
+        self.with_contents(file, |row| Ok(row.content.read(offset, buff)))
+    }
 
-        row.modified = true;
-        Ok(buff.len())
+    fn write(&self, file: &FileRow, offset: u64, buff: &[u8]) -> Result<usize, AnyError> {
+        // Writes patch the current contents, truncation is done through setattr
+        self.with_contents(file, |row| {
+            row.content.write(offset, buff);
+            row.modified = true;
+            Ok(buff.len())
+        })
     }
 
-    fn close(&mut self, file: &mut FileRow) -> Result<bool, AnyError> {
+    fn close(&self, file: &mut FileRow) -> Result<bool, AnyError> {
         let count = {
-            let row = self.cache.get_mut(&file.id).ok_or_else(||
+            let mut cache = self.cache.lock().unwrap();
+            let entry = cache.get_mut(&file.id).ok_or_else(||
                 anyhow!("Trying to use a file that was closed or never opened: {}", file.id)
             )?;
 
-            row.count -= 1;
-            row.count
+            entry.count -= 1;
+            entry.count
         };
 
-        match self.flush(file) {
-            Ok(modified) => {
-                if count <= 0 {
-                    self.cache.remove(&file.id);
-                }
-                Ok(modified)
-            }
-            Err(e) => {
-                // Clean up file even if there was an error
-                if count <= 0 {
-                    self.cache.remove(&file.id);
+        let res = self.flush(file);
+
+        // Clean up file even if there was an error, unless it was opened again in the meantime
+        if count <= 0 {
+            let removed = {
+                let mut cache = self.cache.lock().unwrap();
+                if cache.get(&file.id).is_some_and(|entry| entry.count <= 0) {
+                    cache.remove(&file.id)
+                } else {
+                    None
                 }
-                Err(e)
+            };
+            if let Some(entry) = removed {
+                let mut row = entry.contents.lock().unwrap();
+                self.memory_used.fetch_sub(row.memory, Ordering::Relaxed);
+                row.memory = 0;
+                row.spilled = None;
             }
         }
+        res
     }
 
-    fn flush(&mut self, file: &mut FileRow) -> Result<bool, AnyError> {
+    fn flush(&self, file: &mut FileRow) -> Result<bool, AnyError> {
         let mut modified = false;
-        let row = self.cache.get_mut(&file.id).unwrap();
+        let Ok(contents) = self.contents(file) else {
+            return Ok(false);
+        };
+        let mut row = contents.lock().unwrap();
 
         if row.modified {
-            // Shas of contents as id for the object
-            let sha512 = hex::encode(hmac_sha512::Hash::hash(&row.content));
-
-            // Remove old object
-            if !file.sha512.is_empty() && file.sha512 != sha512 {
-                let info = ObjInfo::new(file, &row.full_path);
-                self.pending_remove.insert(info);
-            }
+            // Unsaved contents may have been spilled to disk
+            self.load(&mut row, file)?;
 
-            file.sha512 = sha512;
+            // Shas of contents as id for the object. The previous object is released by the caller, with the
+            // row as it is stored: another flush may have replaced it since this one was read.
+            file.sha512 = row.content.sha512();
             let mut info = ObjInfo::new(file, &row.full_path);
-            info.size = row.content.len() as u64;
+            info.size = row.content.len();
 
             // Store new object
-            self.obj_storage.put(&mut info, &row.content)?;
+            self.obj_storage.put(&mut info, &row.content.to_object())?;
 
             // Update file metadata
             file.encryption_key = info.encryption_key;
             file.compression = info.compression;
+            file.stored_size = info.stored_size as i64;
             file.size = row.content.len() as i64;
+            file.allocated_size = row.content.allocated() as i64;
             file.updated_at = current_timestamp();
+            file.changed_at = file.updated_at;
+            row.modified = false;
             modified = true;
+            self.touch(&mut row);
         }
+        drop(row);
+
+        self.reclaim(file.id);
         Ok(modified)
     }
 
-    fn remove(&mut self, file: &FileRow, full_path: &str) -> Result<(), AnyError> {
-        if self.cache.contains_key(&file.id) {
+    fn remove(&self, file: &FileRow, full_path: &str) -> Result<(), AnyError> {
+        if self.is_open(file.id) {
             return Err(anyhow!("File is open, cannot remove"));
         }
         if !file.sha512.is_empty() {
-            self.pending_remove.insert(ObjInfo::new(file, full_path));
+            self.pending_remove.lock().unwrap().insert(ObjInfo::new(file, full_path));
         }
         Ok(())
     }
 
-    fn rename(&mut self, file: &FileRow, prev_full_path: &str, new_full_path: &str) -> Result<(), AnyError> {
-        if self.cache.contains_key(&file.id) {
-            return Err(anyhow!("File is open, cannot rename"));
+    fn release_object(&self, file: &FileRow, full_path: &str) {
+        if !file.sha512.is_empty() {
+            self.pending_remove.lock().unwrap().insert(ObjInfo::new(file, full_path));
+        }
+    }
+
+    fn rename(&self, file: &FileRow, prev_full_path: &str, new_full_path: &str) -> Result<(), AnyError> {
+        // Open files keep their contents in the cache, pending changes are stored in the new path
+        if let Ok(contents) = self.contents(file) {
+            contents.lock().unwrap().full_path = new_full_path.to_string();
         }
 
-        // Directories are not stored as objects
-        if file.kind == FILE_KIND_DIRECTORY {
+        // Only regular files are stored as objects
+        if file.kind != FILE_KIND_REGULAR {
             return Ok(());
         }
 
@@ -220,18 +401,262 @@ impl Storage for StorageInterface {
         Ok(())
     }
 
-    fn cleanup(&mut self, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
-        for info in &self.pending_remove {
-            self.obj_storage.remove(info, is_in_use.clone())?;
+    fn truncate(&self, file: &mut FileRow, full_path: &str, size: u64) -> Result<bool, AnyError> {
+        // Closed files are opened just for the truncation, and stored right away
+        let opened = !self.is_open(file.id);
+        if opened {
+            self.open(file, full_path, false)?;
         }
 
-        self.pending_remove.clear();
+        {
+            let contents = self.contents(file)?;
+            let mut row = contents.lock().unwrap();
+            if size > 0 {
+                self.load(&mut row, file)?;
+            } else {
+                row.retrieved = true;
+                row.spilled = None;
+            }
+            row.content.set_len(size);
+            row.modified = true;
+            self.touch(&mut row);
+        }
+        self.reclaim(file.id);
+
+        if opened {
+            return self.close(file);
+        }
+        Ok(false)
+    }
+
+    fn fallocate(&self, file: &FileRow, offset: u64, length: u64, mode: i32) -> Result<(), AnyError> {
+        let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+        let end = offset + length;
+
+        self.with_contents(file, |row| {
+            // Objects are written whole, so there is no space to reserve, allocated ranges stay as holes until written
+            if mode & (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+                row.content.punch_hole(offset, length);
+            }
+            if !keep_size && end > row.content.len() {
+                row.content.set_len(end);
+            }
+
+            row.modified = true;
+            Ok(())
+        })
+    }
+
+    fn seek(&self, file: &FileRow, offset: u64, whence: i32) -> Result<Option<u64>, AnyError> {
+        self.with_contents(file, |row| {
+            match whence {
+                SEEK_DATA => Ok(row.content.seek_data(offset)),
+                SEEK_HOLE => Ok(row.content.seek_hole(offset)),
+                _ => Err(anyhow!("Unsupported seek mode: {}", whence)),
+            }
+        })
+    }
+
+    fn is_open(&self, id: i64) -> bool {
+        self.cache.lock().unwrap().contains_key(&id)
+    }
+
+    fn share(&self, src: &FileRow, dst: &mut FileRow, dst_full_path: &str) -> Result<(), AnyError> {
+        if self.contents(src).is_ok_and(|contents| contents.lock().unwrap().modified) {
+            return Err(anyhow!("File {} has changes that are not stored yet", src.id));
+        }
+
+        // Previous contents of the destination
+        if !dst.sha512.is_empty() && dst.sha512 != src.sha512 {
+            self.pending_remove.lock().unwrap().insert(ObjInfo::new(dst, dst_full_path));
+        }
+
+        dst.sha512 = src.sha512.clone();
+        dst.encryption_key = src.encryption_key.clone();
+        dst.compression = src.compression.clone();
+        dst.stored_size = src.stored_size;
+        dst.allocated_size = src.allocated_size;
+        dst.size = src.size;
+
+        // An open destination reads the shared object from now on
+        if let Ok(contents) = self.contents(dst) {
+            let mut row = contents.lock().unwrap();
+            row.content = SparseBuffer::new();
+            row.retrieved = false;
+            row.modified = false;
+            row.spilled = None;
+            row.raw = None;
+            row.window = None;
+            self.touch(&mut row);
+        }
         Ok(())
     }
 
-    fn nuke(&mut self) -> Result<(), AnyError> {
-        self.cache.clear();
-        self.pending_remove.clear();
+    fn cleanup(&self, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+        // Taken out of the set, so other threads can queue more objects while these are removed
+        let pending: Vec<ObjInfo> = self.pending_remove.lock().unwrap().drain().collect();
+
+        for (index, info) in pending.iter().enumerate() {
+            if let Err(e) = self.obj_storage.remove(info, is_in_use.clone()) {
+                // Retried on the next cleanup
+                self.pending_remove.lock().unwrap().extend(pending[index..].iter().cloned());
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn nuke(&self) -> Result<(), AnyError> {
+        self.cache.lock().unwrap().clear();
+        self.memory_used.store(0, Ordering::Relaxed);
+        self.pending_remove.lock().unwrap().clear();
         self.obj_storage.nuke()
     }
-}
\ No newline at end of file
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            spills: self.counters.spills.load(Ordering::Relaxed),
+            spill_reads: self.counters.spill_reads.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+#[test]
+fn test_dirty_contents_spill_and_read_back() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+    use crate::obj_storage::create_object_storage;
+
+    let fs = crate::sql_fs::test_file_system("spill", "");
+    let spill_directory = PathBuf::from(&fs.config.cache_directory).join("spill");
+    let storage = StorageInterface::new(create_object_storage(fs.config.primary.clone(), fs.sql.clone()), 1024, spill_directory);
+
+    let mut files = vec![];
+    for name in ["a", "b", "c"] {
+        let mut file = fs.mknod(ROOT_DIRECTORY_ID, name, 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+        storage.open(&mut file, &format!("/{}", name), false).unwrap();
+        storage.write(&file, 0, &[name.as_bytes()[0]; 1000]).unwrap();
+        files.push(file);
+    }
+
+    // Only one file fits in the limit, the other two are dirty and moved to disk instead of being stored
+    assert_eq!(storage.cache_stats().spills, 2);
+    assert!(storage.memory_used.load(Ordering::Relaxed) <= 1024);
+    assert_eq!(fs::read_dir(&fs.config.primary.blob_storage).map_or(0, |objects| objects.count()), 0);
+
+    for file in &files {
+        let mut buff = [0u8; 1000];
+        assert_eq!(storage.read(file, 0, &mut buff).unwrap(), 1000);
+        assert_eq!(buff, [file.name.as_bytes()[0]; 1000]);
+    }
+    assert!(storage.cache_stats().spill_reads >= 2);
+    assert!(storage.memory_used.load(Ordering::Relaxed) <= 1024);
+
+    // The spilled writes are the ones stored when the files are closed
+    for file in &mut files {
+        assert!(storage.close(file).unwrap());
+        storage.open(file, &format!("/{}", file.name), true).unwrap();
+        let mut buff = [0u8; 1000];
+        assert_eq!(storage.read(file, 0, &mut buff).unwrap(), 1000);
+        assert_eq!(buff, [file.name.as_bytes()[0]; 1000]);
+        storage.close(file).unwrap();
+    }
+}
+
+/// Backend wrapper that counts the bytes fetched, to check what the reads download
+#[cfg(test)]
+struct CountingObjectStorage {
+    proxy: Box<dyn ObjectStorage>,
+    fetched: Arc<AtomicU64>,
+}
+
+#[cfg(test)]
+impl ObjectStorage for CountingObjectStorage {
+    fn get(&self, info: &ObjInfo) -> Result<Vec<u8>, AnyError> {
+        let data = self.proxy.get(info)?;
+        self.fetched.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(data)
+    }
+
+    fn get_range(&self, info: &ObjInfo, offset: u64, len: u64) -> Result<Vec<u8>, AnyError> {
+        let data = self.proxy.get_range(info, offset, len)?;
+        self.fetched.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(data)
+    }
+
+    fn partial_reads(&self, info: &ObjInfo) -> bool {
+        self.proxy.partial_reads(info)
+    }
+
+    fn put(&self, info: &mut ObjInfo, content: &[u8]) -> Result<(), AnyError> {
+        self.proxy.put(info, content)
+    }
+
+    fn remove(&self, info: &ObjInfo, is_in_use: ObjInUseFn) -> Result<(), AnyError> {
+        self.proxy.remove(info, is_in_use)
+    }
+
+    fn rename(&self, prev_info: &ObjInfo, new_info: &ObjInfo) -> Result<(), AnyError> {
+        self.proxy.rename(prev_info, new_info)
+    }
+
+    fn nuke(&self) -> Result<(), AnyError> {
+        self.proxy.nuke()
+    }
+
+    fn list(&self) -> Result<Vec<crate::obj_storage::ObjEntry>, AnyError> {
+        self.proxy.list()
+    }
+
+    fn key(&self, info: &ObjInfo) -> Result<String, AnyError> {
+        self.proxy.key(info)
+    }
+
+    fn remove_key(&self, key: &str) -> Result<(), AnyError> {
+        self.proxy.remove_key(key)
+    }
+}
+
+#[test]
+fn test_range_reads_fetch_only_the_window() {
+    use crate::metadata_db::ROOT_DIRECTORY_ID;
+    use crate::obj_storage::{create_backend_object_storage, wrap_object_storage};
+
+    // Contents that don't compress, so the compressed blocks are as large as the plain ones
+    let mut seed = 1u32;
+    let content: Vec<u8> = (0..4 * RANGE_READ_SIZE).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }).collect();
+
+    for (name, storage_config) in [("range", ""), ("range-compressed", "  compression_level: 6\n")] {
+        let fs = crate::sql_fs::test_file_system(name, storage_config);
+        let file = fs.mknod(ROOT_DIRECTORY_ID, "video", 0, 0, libc::S_IFREG | 0o644, 0).unwrap();
+        fs.write_all(file.id, &content).unwrap();
+        let mut file = fs.get_file_or_err(file.id).unwrap();
+
+        let fetched = Arc::new(AtomicU64::new(0));
+        let backend = CountingObjectStorage {
+            proxy: create_backend_object_storage(fs.config.primary.clone(), fs.sql.clone()),
+            fetched: fetched.clone(),
+        };
+        let storage = StorageInterface::new(wrap_object_storage(fs.config.primary.clone(), Box::new(backend)), 0, PathBuf::new());
+
+        storage.open(&mut file, "/video", true).unwrap();
+        let mut buff = vec![0u8; 64 * 1024];
+        let offset = 2 * RANGE_READ_SIZE;
+        assert_eq!(storage.read(&file, offset, &mut buff).unwrap(), buff.len());
+        assert_eq!(buff, content[offset as usize..offset as usize + buff.len()]);
+
+        // The window of 1 MiB, and the first bytes to check the object is not sparse-encoded
+        let window = fetched.load(Ordering::Relaxed);
+        assert!((RANGE_READ_SIZE..RANGE_READ_SIZE + RANGE_READ_SIZE / 4).contains(&window), "{}: fetched {}", name, window);
+
+        // Reads inside the window don't fetch anything
+        assert_eq!(storage.read(&file, offset + 1000, &mut buff).unwrap(), buff.len());
+        assert_eq!(buff, content[offset as usize + 1000..offset as usize + 1000 + buff.len()]);
+        assert_eq!(fetched.load(Ordering::Relaxed), window);
+        storage.close(&mut file).unwrap();
+    }
+}
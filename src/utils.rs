@@ -43,20 +43,80 @@ pub fn humanize_bytes_binary(value: usize) -> String {
     }
 }
 
+/// Major and minor numbers of a device number, using the same encoding as the kernel and glibc
+pub fn device_numbers(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+pub const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+/// Timestamps are nanoseconds since the unix epoch
 pub fn current_timestamp() -> i64 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    timestamp_from_system_time(SystemTime::now())
 }
 
 pub fn system_time_from_timestamp(value: i64) -> SystemTime {
     if value < 0 {
-        SystemTime::UNIX_EPOCH.sub(Duration::from_secs(value.abs() as u64))
+        SystemTime::UNIX_EPOCH.sub(Duration::from_nanos(value.unsigned_abs()))
     } else {
-        SystemTime::UNIX_EPOCH.add(Duration::from_secs(value as u64))
+        SystemTime::UNIX_EPOCH.add(Duration::from_nanos(value as u64))
     }
 }
 
 pub fn timestamp_from_system_time(value: SystemTime) -> i64 {
-    value.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    match value.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+/// Seconds and nanoseconds of a timestamp, the nanoseconds are always positive like in `struct timespec`
+pub fn split_timestamp(value: i64) -> (i64, i64) {
+    (value.div_euclid(NANOS_PER_SECOND), value.rem_euclid(NANOS_PER_SECOND))
+}
+
+pub fn join_timestamp(seconds: i64, nanos: i64) -> i64 {
+    seconds * NANOS_PER_SECOND + nanos
+}
+
+/// Render rows as a plain text table with a header, columns are padded to the widest value
+pub fn format_table(headers: &[String], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (index, value) in row.iter().enumerate() {
+            widths[index] = widths[index].max(value.chars().count());
+        }
+    }
+
+    let format_row = |row: &[String]| -> String {
+        let cells: Vec<String> = row.iter().enumerate()
+            .map(|(index, value)| format!("{:width$}", value, width = widths[index]))
+            .collect();
+        cells.join(" | ").trim_end().to_string()
+    };
+
+    let mut result = String::new();
+    result.push_str(&format_row(headers));
+    result.push('\n');
+    let separator: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+    result.push_str(&separator.join("-+-"));
+    result.push('\n');
+    for row in rows {
+        result.push_str(&format_row(row));
+        result.push('\n');
+    }
+    result
+}
+
+/// Quote a CSV field if it contains separators, quotes or line breaks
+pub fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
 }
 
 pub fn ask_for_confirmation(msg: &str) -> bool {
@@ -66,4 +126,11 @@ pub fn ask_for_confirmation(msg: &str) -> bool {
     std::io::stdin().read_line(&mut input).unwrap();
     let choice = input.trim().to_ascii_lowercase();
     choice == "yes" || choice == "y"
-}
\ No newline at end of file
+}
+#[test]
+fn test_split_timestamp() {
+    assert_eq!(split_timestamp(1_700_000_000_123_456_789), (1_700_000_000, 123_456_789));
+    assert_eq!(split_timestamp(-1), (-1, 999_999_999));
+    assert_eq!(join_timestamp(-1, 999_999_999), -1);
+    assert_eq!(timestamp_from_system_time(system_time_from_timestamp(-1_500_000_000)), -1_500_000_000);
+}
@@ -0,0 +1,81 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use log::error;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fixed number of threads running jobs in the order they are queued
+pub struct WorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, size: usize) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size.max(1))
+            .map(|index| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("{}-{}", name, index))
+                    .spawn(move || Self::run(receiver))
+                    .expect("Unable to start worker thread")
+            })
+            .collect();
+
+        WorkerPool { sender: Some(sender), workers }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if let Some(sender) = &self.sender {
+            // Only fails if every worker is gone, the job is dropped along with its reply
+            let _ = sender.send(Box::new(job));
+        }
+    }
+
+    fn run(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+
+            // A failed job must not take the worker down with it
+            if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                error!("Worker job panicked");
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Finish the queued jobs before stopping
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[test]
+fn test_worker_pool_runs_all_jobs() {
+    let (sender, receiver) = channel();
+    {
+        let pool = WorkerPool::new("test", 4);
+        for index in 0..32 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(index).unwrap());
+        }
+        pool.execute(|| panic!("Failing job"));
+    }
+    drop(sender);
+
+    let mut results: Vec<i32> = receiver.iter().collect();
+    results.sort();
+    assert_eq!(results, (0..32).collect::<Vec<_>>());
+}